@@ -0,0 +1,580 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    os::unix::ffi::OsStringExt,
+    path::{Component, Path, PathBuf},
+};
+
+use eyre::{bail, eyre, Result};
+
+use crate::inflate::inflate;
+
+const BLOCK_SIZE: u64 = 512;
+
+const ZIP_LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const ZIP_CENTRAL_HEADER: &[u8] = b"PK\x01\x02";
+const ZIP_END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+/// The end of central directory record is followed by a comment of at most this size
+const ZIP_MAX_COMMENT: u64 = 0xffff;
+const ZIP_END_SIZE: u64 = 22;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+
+/// Symlinks pointing to symlinks are followed this many times, like `ELOOP`
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Calls `f` with the path and content of the regular files of a tar or zip archive
+///
+/// Members are streamed from the archive, only the ones `wanted` accepts
+/// are read into memory. Symlinks are reported under their own path with
+/// the content of their target, which takes a second pass over the archive.
+/// Both ustar and GNU long names are understood, compressed tar archives
+/// are not. Zip members can be stored or deflated, zip64 archives are not
+/// supported.
+pub(crate) fn for_each_member(
+    archive: &Path,
+    wanted: impl Fn(&Path) -> bool,
+    mut f: impl FnMut(&Path, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut symlinks = HashMap::new();
+    scan(
+        archive,
+        |name, target| match target {
+            Some(target) => {
+                symlinks.insert(name.to_path_buf(), symlink_target(name, target));
+                false
+            }
+            None => wanted(name),
+        },
+        &mut f,
+    )?;
+
+    // The targets of the wanted symlinks, with the names they are found under
+    let mut aliases: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+    for name in symlinks.keys().filter(|name| wanted(name)) {
+        let mut target = &symlinks[name];
+        for _ in 0..MAX_SYMLINK_HOPS {
+            match symlinks.get(target) {
+                Some(next) => target = next,
+                None => {
+                    aliases
+                        .entry(target.clone())
+                        .or_default()
+                        .push(name.clone());
+                    break;
+                }
+            }
+        }
+    }
+    if aliases.is_empty() {
+        return Ok(());
+    }
+    for names in aliases.values_mut() {
+        names.sort();
+    }
+
+    scan(
+        archive,
+        |name, target| target.is_none() && aliases.contains_key(name),
+        |name, content| {
+            for alias in &aliases[name] {
+                f(alias, content)?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Streams the members of a tar or zip archive
+///
+/// `want` gets the path of every member and the target of symlinks, the
+/// content of the regular files it accepts is passed to `f`.
+fn scan(
+    archive: &Path,
+    want: impl FnMut(&Path, Option<&Path>) -> bool,
+    f: impl FnMut(&Path, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut magic = Vec::new();
+    File::open(archive)?.take(4).read_to_end(&mut magic)?;
+    if magic == ZIP_LOCAL_HEADER || magic == ZIP_END_OF_CENTRAL_DIRECTORY {
+        scan_zip(archive, want, f)
+    } else {
+        scan_tar(archive, want, f)
+    }
+}
+
+fn scan_tar(
+    archive: &Path,
+    mut want: impl FnMut(&Path, Option<&Path>) -> bool,
+    mut f: impl FnMut(&Path, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(archive)?);
+    let mut header = [0; BLOCK_SIZE as usize];
+    let mut long_name = None;
+
+    while read_block(&mut reader, &mut header)? {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !has_valid_checksum(&header) {
+            bail!("{} is not an uncompressed tar archive", archive.display());
+        }
+
+        let Some(size) = parse_octal(&header[124..136]) else {
+            bail!("{}: invalid member size", archive.display());
+        };
+        let padded_size = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        let name = long_name.take().unwrap_or_else(|| header_name(&header));
+
+        match header[156] {
+            b'L' => {
+                let mut name = read_member(&mut reader, size, padded_size)?;
+                name.truncate(until_nul(&name).len());
+                long_name = Some(PathBuf::from(OsString::from_vec(name)));
+            }
+            b'0' | 0 if want(&name, None) => {
+                let content = read_member(&mut reader, size, padded_size)?;
+                f(&name, &content)?;
+            }
+            b'2' => {
+                let target =
+                    PathBuf::from(OsString::from_vec(until_nul(&header[157..257]).to_vec()));
+                want(&name, Some(&target));
+                io::copy(&mut (&mut reader).take(padded_size), &mut io::sink())?;
+            }
+            _ => {
+                io::copy(&mut (&mut reader).take(padded_size), &mut io::sink())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A file listed in the central directory of a zip archive
+struct ZipEntry {
+    name: PathBuf,
+    mode: u32,
+    method: u16,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+}
+
+/// Streams the members of a zip archive using its central directory
+///
+/// The sizes in the local headers may be missing when the archive was
+/// written to a pipe, the ones in the central directory are always set.
+fn scan_zip(
+    archive: &Path,
+    mut want: impl FnMut(&Path, Option<&Path>) -> bool,
+    mut f: impl FnMut(&Path, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let invalid = |what: &str| eyre!("{}: invalid zip archive, {what}", archive.display());
+    let mut file = File::open(archive)?;
+
+    let len = file.metadata()?.len();
+    let tail_len = len.min(ZIP_END_SIZE + ZIP_MAX_COMMENT);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = Vec::new();
+    (&mut file).take(tail_len).read_to_end(&mut tail)?;
+    let end = tail
+        .windows(ZIP_END_OF_CENTRAL_DIRECTORY.len())
+        .rposition(|window| window == ZIP_END_OF_CENTRAL_DIRECTORY)
+        .filter(|&end| tail.len() - end >= ZIP_END_SIZE as usize)
+        .ok_or_else(|| invalid("missing end of central directory"))?;
+    let end = &tail[end..];
+    let entry_count = le16(end, 10);
+    let directory_size = le32(end, 12);
+    let directory_offset = le32(end, 16);
+    if entry_count == 0xffff || directory_offset == 0xffff_ffff {
+        bail!("{}: zip64 archives are not supported", archive.display());
+    }
+
+    file.seek(SeekFrom::Start(directory_offset as u64))?;
+    let mut directory = Vec::new();
+    (&mut file)
+        .take(directory_size as u64)
+        .read_to_end(&mut directory)?;
+
+    let mut entries = Vec::new();
+    let mut rest = &directory[..];
+    for _ in 0..entry_count {
+        if rest.len() < 46 || &rest[..4] != ZIP_CENTRAL_HEADER {
+            return Err(invalid("truncated central directory"));
+        }
+        let name_len = le16(rest, 28) as usize;
+        let header_len = 46 + name_len + le16(rest, 30) as usize + le16(rest, 32) as usize;
+        if rest.len() < header_len {
+            return Err(invalid("truncated central directory"));
+        }
+        if le16(rest, 8) & 1 != 0 {
+            bail!(
+                "{}: encrypted zip members are not supported",
+                archive.display()
+            );
+        }
+        // Archives written on other systems have no unix mode
+        let mode = if rest[5] == 3 {
+            le32(rest, 38) >> 16
+        } else {
+            0
+        };
+        let name = PathBuf::from(OsString::from_vec(rest[46..46 + name_len].to_vec()));
+        entries.push(ZipEntry {
+            name,
+            mode,
+            method: le16(rest, 10),
+            compressed_size: le32(rest, 20) as u64,
+            size: le32(rest, 24) as u64,
+            offset: le32(rest, 42) as u64,
+        });
+        rest = &rest[header_len..];
+    }
+
+    for entry in entries {
+        let kind = entry.mode & S_IFMT;
+        if entry.name.as_os_str().as_encoded_bytes().ends_with(b"/") {
+            continue;
+        }
+        if kind == S_IFLNK {
+            let target = read_zip_member(&mut file, &entry, archive)?;
+            let target = PathBuf::from(OsString::from_vec(target));
+            want(&entry.name, Some(&target));
+        } else if (kind == 0 || kind == S_IFREG) && want(&entry.name, None) {
+            let content = read_zip_member(&mut file, &entry, archive)?;
+            f(&entry.name, &content)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads and decompresses the content of a zip member
+fn read_zip_member(file: &mut File, entry: &ZipEntry, archive: &Path) -> Result<Vec<u8>> {
+    let mut header = [0; 30];
+    file.seek(SeekFrom::Start(entry.offset))?;
+    file.read_exact(&mut header)?;
+    if &header[..4] != ZIP_LOCAL_HEADER {
+        bail!(
+            "{}: invalid zip archive, no local header for {}",
+            archive.display(),
+            entry.name.display()
+        );
+    }
+    let skip = le16(&header, 26) as i64 + le16(&header, 28) as i64;
+    file.seek(SeekFrom::Current(skip))?;
+
+    let mut compressed = Vec::new();
+    file.take(entry.compressed_size)
+        .read_to_end(&mut compressed)?;
+    if (compressed.len() as u64) < entry.compressed_size {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    let content = match entry.method {
+        0 => compressed,
+        8 => inflate(&compressed, entry.size as usize)
+            .map_err(|err| eyre!("{}: {}: {err}", archive.display(), entry.name.display()))?,
+        method => bail!(
+            "{}: {} uses the unsupported compression method {method}",
+            archive.display(),
+            entry.name.display()
+        ),
+    };
+    if content.len() as u64 != entry.size {
+        bail!(
+            "{}: {} is not {} bytes long",
+            archive.display(),
+            entry.name.display(),
+            entry.size
+        );
+    }
+    Ok(content)
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a whole block, returns false at the end of the input
+fn read_block(reader: &mut impl Read, block: &mut [u8]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match reader.read(&mut block[filled..])? {
+            0 if filled == 0 => return Ok(false),
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Reads a member and skips its padding
+///
+/// The size comes from the archive, so the content grows as it is read
+/// instead of being allocated upfront.
+fn read_member(reader: &mut impl Read, size: u64, padded_size: u64) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    reader.take(size).read_to_end(&mut content)?;
+    let padding = io::copy(&mut reader.take(padded_size - size), &mut io::sink())?;
+    if content.len() as u64 + padding < padded_size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(content)
+}
+
+/// Gets the archive path a symlink points to
+///
+/// Relative targets are relative to the directory of the symlink, absolute
+/// ones to the root of the archive.
+fn symlink_target(name: &Path, target: &Path) -> PathBuf {
+    let mut path = PathBuf::new();
+    let parent = name.parent().unwrap_or(Path::new(""));
+    let base = if target.has_root() {
+        Path::new("")
+    } else {
+        parent
+    };
+    for component in base.components().chain(target.components()) {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    path
+}
+
+/// Gets the member path, joining the ustar prefix and stripping a leading `./`
+fn header_name(header: &[u8]) -> PathBuf {
+    let mut name = Vec::new();
+    if &header[257..262] == b"ustar" {
+        let prefix = until_nul(&header[345..500]);
+        if !prefix.is_empty() {
+            name.extend_from_slice(prefix);
+            name.push(b'/');
+        }
+    }
+    name.extend_from_slice(until_nul(&header[..100]));
+
+    let name = PathBuf::from(OsString::from_vec(name));
+    match name.strip_prefix(".") {
+        Ok(rest) => rest.to_path_buf(),
+        Err(_) => name,
+    }
+}
+
+fn has_valid_checksum(header: &[u8]) -> bool {
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+    parse_octal(&header[148..156]) == Some(sum)
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(until_nul(field)).ok()?.trim();
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+/// Builds a tar archive of regular files in memory
+#[cfg(test)]
+pub(crate) fn tar(members: &[(&str, &[u8])]) -> Vec<u8> {
+    tar_with_symlinks(&[], members)
+}
+
+/// Builds a tar archive of symlinks, followed by regular files, in memory
+#[cfg(test)]
+pub(crate) fn tar_with_symlinks(symlinks: &[(&str, &str)], members: &[(&str, &[u8])]) -> Vec<u8> {
+    fn header(name: &str, size: usize, kind: u8, target: &str) -> [u8; BLOCK_SIZE as usize] {
+        let mut header = [0; BLOCK_SIZE as usize];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{size:011o}").as_bytes());
+        header[156] = kind;
+        header[157..157 + target.len()].copy_from_slice(target.as_bytes());
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].fill(b' ');
+        let sum: u64 = header.iter().map(|&b| b as u64).sum();
+        header[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        header
+    }
+
+    let mut archive = Vec::new();
+    for (name, target) in symlinks {
+        archive.extend_from_slice(&header(name, 0, b'2', target));
+    }
+    for (name, content) in members {
+        archive.extend_from_slice(&header(name, content.len(), b'0', ""));
+        archive.extend_from_slice(content);
+        archive.resize(
+            archive.len().div_ceil(BLOCK_SIZE as usize) * BLOCK_SIZE as usize,
+            0,
+        );
+    }
+    archive.resize(archive.len() + 2 * BLOCK_SIZE as usize, 0);
+    archive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::{asset, TempDir};
+    use std::fs;
+
+    #[test]
+    fn test_tar_members() {
+        let dir = TempDir::new("tar");
+        let archive = dir.join("libs.tar");
+        let large = vec![1; 1000];
+        fs::write(
+            &archive,
+            tar(&[
+                ("./lib/libfoo.so.1", b"foo"),
+                ("lib/README", b"skipped"),
+                ("lib/libbar.so", &large),
+            ]),
+        )
+        .unwrap();
+
+        let mut members = Vec::new();
+        for_each_member(
+            &archive,
+            |name| !name.ends_with("README"),
+            |name, content| {
+                members.push((name.to_path_buf(), content.to_vec()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            members,
+            [
+                (PathBuf::from("lib/libfoo.so.1"), b"foo".to_vec()),
+                (PathBuf::from("lib/libbar.so"), large),
+            ]
+        );
+
+        let mut gzip = b"\x1f\x8b\x08\x00".to_vec();
+        gzip.resize(1024, 0xff);
+        fs::write(&archive, gzip).unwrap();
+        let err = for_each_member(&archive, |_| true, |_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("not an uncompressed tar"));
+    }
+
+    #[test]
+    fn test_tar_symlinks() {
+        let dir = TempDir::new("tar-symlinks");
+        let archive = dir.join("libs.tar");
+        fs::write(
+            &archive,
+            tar_with_symlinks(
+                &[
+                    ("lib/libfoo.so", "libfoo.so.1"),
+                    ("lib/libfoo.so.1", "../real/libfoo.so.1.2"),
+                    ("lib64/libbar.so", "/lib/libbar.so.1"),
+                    ("lib/libdangling.so", "missing.so"),
+                    ("lib/libloop.so", "libloop.so"),
+                ],
+                &[("real/libfoo.so.1.2", b"foo"), ("lib/libbar.so.1", b"bar")],
+            ),
+        )
+        .unwrap();
+
+        let mut members = Vec::new();
+        for_each_member(
+            &archive,
+            |name| name.starts_with("lib") || name.starts_with("lib64"),
+            |name, content| {
+                members.push((name.to_path_buf(), content.to_vec()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        members.sort();
+        assert_eq!(
+            members,
+            [
+                (PathBuf::from("lib/libbar.so.1"), b"bar".to_vec()),
+                (PathBuf::from("lib/libfoo.so"), b"foo".to_vec()),
+                (PathBuf::from("lib/libfoo.so.1"), b"foo".to_vec()),
+                (PathBuf::from("lib64/libbar.so"), b"bar".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zip_members() {
+        let archive = asset("libs.zip");
+        let mut members = Vec::new();
+        for_each_member(
+            &archive,
+            |name| !name.ends_with("README"),
+            |name, content| {
+                members.push((name.to_path_buf(), content.to_vec()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        members.sort();
+
+        // lib/libnocfi.so.1 is deflated, lib/libnocfi.so a symlink to it
+        let nocfi = fs::read(asset("libnocfi.so")).unwrap();
+        assert_eq!(
+            members,
+            [
+                (PathBuf::from("lib/libnocfi.so"), nocfi.clone()),
+                (PathBuf::from("lib/libnocfi.so.1"), nocfi),
+                (
+                    PathBuf::from("lib/liborigin.so"),
+                    fs::read(asset("liborigin.so")).unwrap()
+                ),
+            ]
+        );
+
+        let dir = TempDir::new("zip-truncated");
+        let truncated = dir.join("libs.zip");
+        let content = fs::read(&archive).unwrap();
+        fs::write(&truncated, &content[..content.len() / 2]).unwrap();
+        let err = for_each_member(&truncated, |_| true, |_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("missing end of central directory"));
+    }
+
+    #[test]
+    fn test_truncated_member() {
+        let dir = TempDir::new("tar-truncated");
+        let archive = dir.join("libs.tar");
+        let mut content = tar(&[("lib/libfoo.so", b"foo")]);
+        // Claim an 8 GiB member that the archive does not have
+        content[124..135].copy_from_slice(b"77777777777");
+        content[148..156].fill(b' ');
+        let sum: u64 = content[..BLOCK_SIZE as usize]
+            .iter()
+            .map(|&b| b as u64)
+            .sum();
+        content[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        content.truncate(2 * BLOCK_SIZE as usize);
+        fs::write(&archive, content).unwrap();
+
+        let err = for_each_member(&archive, |_| true, |_, _| Ok(())).unwrap_err();
+        assert!(err.to_string().contains("unexpected end of file"), "{err}");
+    }
+}
@@ -3,34 +3,170 @@
 // SPDX-License-Identifier: EUPL-1.2
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter},
     path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
 };
 
-use eyre::Result;
+use eyre::{bail, eyre, Result};
+use glob::Pattern;
 
 use crate::{
-    elf::{osabi_are_compatible, Arch, ElfFile, OsAbi},
-    misc::{glob, read_file},
+    archive,
+    elf::{
+        compatible_archs, expand_rpath_tokens, machine_to_str, osabi_are_compatible,
+        osabi_to_string, Arch, ElfFile, OsAbi,
+    },
+    misc::{glob, path_string, read_file},
+    output::Event,
 };
 
+type SonameCache = HashMap<(String, Arch), Vec<(PathBuf, OsAbi)>>;
+
 /// Library cache to avoid duplicate scanning
 pub(crate) struct LibraryCache {
     cached_paths: HashSet<PathBuf>,
-    soname_cache: HashMap<(String, Arch), Vec<(PathBuf, OsAbi)>>,
+    soname_cache: SonameCache,
+    /// Lookups known to fail, cleared whenever the cache is populated further
+    misses: Mutex<HashSet<(String, Arch, OsAbi, bool)>>,
+    /// Directory or archive each library was indexed from
+    origins: HashMap<PathBuf, PathBuf>,
+    /// Origins of the libraries returned by lookups so far
+    hits: Mutex<HashSet<PathBuf>>,
+    /// File extensions of indexed libraries, e.g. `so` matches `libfoo.so.1`
+    extensions: Vec<String>,
+    symlink_mode: SymlinkMode,
+    /// Soname whose lookups are traced candidate by candidate
+    debug_soname: Option<String>,
+    /// How many directories deep recursive scans go
+    max_scan_depth: Option<usize>,
+    /// Build time directories with the runtime location of their libraries
+    runtime_map: Vec<(PathBuf, PathBuf)>,
+    /// Runtime location of the libraries indexed under a `runtime_map` directory
+    runtime_paths: HashMap<PathBuf, PathBuf>,
+}
+
+/// Which path a library found through a symlink is indexed under
+///
+/// This decides the directory that ends up in RPATH for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum SymlinkMode {
+    /// The real path if the symlink target has the same file name, the symlink otherwise
+    #[default]
+    Auto,
+    /// Always the symlink
+    Preserve,
+    /// Always the real path
+    Canonicalize,
+    /// Both the real path and the symlink
+    Both,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "preserve" => Ok(Self::Preserve),
+            "canonicalize" => Ok(Self::Canonicalize),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "unknown symlink mode {s:?}, expected auto, preserve, canonicalize or both"
+            )),
+        }
+    }
+}
+
+impl SymlinkMode {
+    /// Gets the paths to index a library found at `path` under
+    fn resolve(self, path: &Path) -> Vec<PathBuf> {
+        let Ok(canonical) = path.canonicalize() else {
+            return vec![path.to_path_buf()];
+        };
+        match self {
+            Self::Auto if canonical.file_name() == path.file_name() => vec![canonical],
+            Self::Auto | Self::Preserve => vec![path.to_path_buf()],
+            Self::Canonicalize => vec![canonical],
+            Self::Both if canonical != path => vec![canonical, path.to_path_buf()],
+            Self::Both => vec![canonical],
+        }
+    }
 }
 
 impl LibraryCache {
-    pub(crate) fn new() -> Self {
+    const VERSION: u32 = 1;
+
+    pub(crate) fn new(extensions: Vec<String>) -> Self {
         Self {
             cached_paths: HashSet::new(),
             soname_cache: HashMap::new(),
+            misses: Mutex::new(HashSet::new()),
+            origins: HashMap::new(),
+            hits: Mutex::new(HashSet::new()),
+            extensions,
+            symlink_mode: SymlinkMode::default(),
+            debug_soname: None,
+            max_scan_depth: None,
+            runtime_map: Vec::new(),
+            runtime_paths: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn with_symlink_mode(mut self, symlink_mode: SymlinkMode) -> Self {
+        self.symlink_mode = symlink_mode;
+        self
+    }
+
+    pub(crate) fn with_debug_soname(mut self, debug_soname: Option<String>) -> Self {
+        self.debug_soname = debug_soname;
+        self
+    }
+
+    pub(crate) fn with_max_scan_depth(mut self, max_scan_depth: Option<usize>) -> Self {
+        self.max_scan_depth = max_scan_depth;
+        self
+    }
+
+    /// Maps the libraries under a build time directory to a runtime one
+    ///
+    /// Libraries are still read from where they were found, only
+    /// `runtime_path` returns the mapped location. The first matching
+    /// directory wins.
+    pub(crate) fn with_runtime_map(mut self, runtime_map: Vec<(PathBuf, PathBuf)>) -> Self {
+        self.runtime_map = runtime_map;
+        self
+    }
+
+    /// Gets where a library returned by `find_library` is located at runtime
+    pub(crate) fn runtime_path(&self, lib: &Path) -> PathBuf {
+        self.runtime_paths
+            .get(lib)
+            .cloned()
+            .unwrap_or_else(|| lib.to_path_buf())
+    }
+
+    fn record_runtime_path(&mut self, lib: &Path) {
+        let runtime_path = self.runtime_map.iter().find_map(|(build, runtime)| {
+            lib.strip_prefix(build).ok().map(|rest| runtime.join(rest))
+        });
+        if let Some(runtime_path) = runtime_path {
+            self.runtime_paths.insert(lib.to_path_buf(), runtime_path);
         }
     }
 
     /// Populates the cache with libraries from specified paths
+    ///
+    /// Directories are searched in order. Libraries found while scanning a
+    /// single directory are ordered by their path, so that ties are broken
+    /// deterministically. Recursive searches skip the subdirectories that
+    /// were already scanned, e.g. the ones seeded from a previous report.
     pub(crate) fn populate_cache(&mut self, initial: &[PathBuf], recursive: bool) -> Result<()> {
         let mut lib_dirs = initial.to_vec();
+        self.misses.get_mut().unwrap().clear();
 
         while !lib_dirs.is_empty() {
             let lib_dir = lib_dirs.remove(0);
@@ -41,23 +177,49 @@ impl LibraryCache {
 
             self.cached_paths.insert(lib_dir.clone());
 
-            for path in glob(&lib_dir, "*.so*", recursive)?.flatten() {
+            let mut candidates = BTreeSet::new();
+            for ext in &self.extensions {
+                candidates.extend(
+                    glob(
+                        &lib_dir,
+                        &format!("*.{ext}*"),
+                        recursive,
+                        self.max_scan_depth,
+                    )?
+                    .flatten(),
+                );
+            }
+
+            let mut found: HashMap<(String, Arch), Vec<(PathBuf, OsAbi)>> = HashMap::new();
+            for path in candidates {
+                let parent = path.parent().unwrap_or(&lib_dir);
+                if parent != lib_dir && self.cached_paths.contains(parent) {
+                    continue;
+                }
+                // Left behind by partially copied symlink farms, indexing them
+                // would end up in RPATHs pointing to missing libraries
+                if path.is_symlink() && !path.exists() {
+                    Event::info(
+                        "dangling",
+                        format!("skipping dangling symlink {}", path.display()),
+                    )
+                    .field("library", path.display())
+                    .emit();
+                    continue;
+                }
                 if !path.is_file() {
                     continue;
                 }
 
-                // Resolve symlinks optimally
-                let resolved = match path.canonicalize() {
-                    Ok(p) if p.file_name() == path.file_name() => p,
-                    _ => path.clone(),
-                };
+                let resolved = self.symlink_mode.resolve(&path);
                 let content = read_file(&path)?;
                 if let Ok(elf) = ElfFile::new(&content) {
                     // Add RPATH directories to search list
                     let rpath: Vec<PathBuf> = elf
                         .get_rpath()
                         .iter()
-                        .filter(|p| !p.is_empty() && !p.contains("$ORIGIN"))
+                        .filter(|p| !p.is_empty())
+                        .filter_map(|p| expand_rpath_tokens(p, elf.is_64(), elf.get_arch()))
                         .map(PathBuf::from)
                         .collect();
 
@@ -66,24 +228,338 @@ impl LibraryCache {
                     // Cache this library
                     if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
                         let key = (name.to_string(), elf.get_arch());
-                        self.soname_cache.entry(key).or_default().push((
-                            resolved.parent().unwrap_or(Path::new("")).to_path_buf(),
-                            elf.get_osabi(),
-                        ));
+                        found
+                            .entry(key)
+                            .or_default()
+                            .extend(resolved.into_iter().map(|p| (p, elf.get_osabi())));
                     }
                 }
             }
+
+            self.insert_found(&lib_dir, found);
         }
         Ok(())
     }
 
-    /// Finds a dependency in the cache
-    pub(crate) fn find_dependency(
+    /// Populates the cache with libraries from a tar or zip archive without extracting it
+    ///
+    /// Members are indexed as if the archive was a directory, e.g.
+    /// `lib/libfoo.so` of `libs.tar` is found as `libs.tar/lib/libfoo.so`.
+    /// Use `--staging` and `--prefix` to map this location to the real one.
+    /// The RPATHs of the members are not followed.
+    pub(crate) fn populate_from_archive(&mut self, archive: &Path) -> Result<()> {
+        self.misses.get_mut().unwrap().clear();
+
+        let patterns = self
+            .extensions
+            .iter()
+            .map(|ext| Pattern::new(&format!("*.{ext}*")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let is_library = |member: &Path| {
+            member
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|name| patterns.iter().any(|pattern| pattern.matches(name)))
+        };
+
+        let mut found: HashMap<(String, Arch), Vec<(PathBuf, OsAbi)>> = HashMap::new();
+        archive::for_each_member(archive, is_library, |member, content| {
+            if let (Ok(elf), Some(name)) = (
+                ElfFile::new(content),
+                member.file_name().and_then(|n| n.to_str()),
+            ) {
+                found
+                    .entry((name.to_string(), elf.get_arch()))
+                    .or_default()
+                    .push((archive.join(member), elf.get_osabi()));
+            }
+            Ok(())
+        })?;
+
+        self.insert_found(archive, found);
+        Ok(())
+    }
+
+    fn insert_found(
+        &mut self,
+        origin: &Path,
+        found: HashMap<(String, Arch), Vec<(PathBuf, OsAbi)>>,
+    ) {
+        for (key, mut libs) in found {
+            libs.sort_by(|(a, _), (b, _)| a.cmp(b));
+            for (lib, _) in &libs {
+                self.origins
+                    .entry(lib.clone())
+                    .or_insert_with(|| origin.to_path_buf());
+                self.record_runtime_path(lib);
+            }
+            self.soname_cache.entry(key).or_default().extend(libs);
+        }
+    }
+
+    /// Writes the indexed libraries and scanned directories for `--load-cache`
+    ///
+    /// Failed and successful lookups are not saved, they depend on the files
+    /// being patched.
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        bincode::serialize_into(&mut file, &Self::VERSION)?;
+        bincode::serialize_into(
+            &mut file,
+            &(&self.cached_paths, &self.soname_cache, &self.origins),
+        )?;
+        Ok(())
+    }
+
+    /// Merges the libraries of caches written by `save` into this one
+    ///
+    /// The directories scanned for a cache are not scanned again. If more
+    /// than one cache provides a soname with different libraries, the ones of
+    /// the cache loaded first take precedence.
+    pub(crate) fn load(&mut self, caches: &[PathBuf]) -> Result<()> {
+        self.misses.get_mut().unwrap().clear();
+
+        let mut loaded_from: HashMap<(String, Arch), &Path> = HashMap::new();
+        for cache in caches {
+            let (cached_paths, soname_cache, origins): (HashSet<PathBuf>, SonameCache, _) =
+                Self::read(cache).map_err(|err| eyre!("{}: {err}", cache.display()))?;
+            self.cached_paths.extend(cached_paths);
+            for (lib, origin) in origins {
+                self.record_runtime_path(&lib);
+                self.origins.entry(lib).or_insert(origin);
+            }
+
+            for (key, libs) in soname_cache {
+                let known = self.soname_cache.entry(key.clone()).or_default();
+                let new: Vec<_> = libs
+                    .into_iter()
+                    .filter(|lib| !known.contains(lib))
+                    .collect();
+                if new.is_empty() {
+                    continue;
+                }
+                if let Some(first) = loaded_from.get(&key) {
+                    Event::warning(
+                        "cache-conflict",
+                        format!(
+                            "{} ({}) is provided by both {} and {}, preferring the libraries of {}",
+                            key.0,
+                            machine_to_str(key.1),
+                            first.display(),
+                            cache.display(),
+                            first.display()
+                        ),
+                    )
+                    .field("soname", &key.0)
+                    .field("cache", cache.display())
+                    .emit();
+                }
+                known.extend(new);
+                loaded_from.entry(key).or_insert(cache);
+            }
+        }
+        Ok(())
+    }
+
+    fn read(cache: &Path) -> Result<(HashSet<PathBuf>, SonameCache, HashMap<PathBuf, PathBuf>)> {
+        let mut file = BufReader::new(File::open(cache)?);
+        let version: u32 = bincode::deserialize_from(&mut file)?;
+        if version != Self::VERSION {
+            bail!("Invalid version {}", version)
+        }
+        bincode::deserialize_from(file).map_err(|err| eyre!("cache is corrupt: {err}"))
+    }
+
+    /// Gets the directories among `dirs` that no lookup found a library in so far
+    ///
+    /// A directory counts as used if a library indexed while scanning it,
+    /// recursively or not, was returned.
+    pub(crate) fn unused_dirs<'a>(&self, dirs: &'a [PathBuf]) -> Vec<&'a Path> {
+        let hits = self.hits.lock().unwrap();
+        dirs.iter()
+            .filter(|dir| !hits.contains(dir.as_path()))
+            .map(PathBuf::as_path)
+            .collect()
+    }
+
+    /// Finds the library file of a dependency in the cache
+    ///
+    /// When `allow_compat_arch` is set and there is no exact architecture
+    /// match, libraries of compatible architectures are considered too.
+    ///
+    /// Failed lookups are remembered, so repeated misses of the same
+    /// dependency are answered without searching again.
+    pub(crate) fn find_library(
         &self,
         soname: &str,
         soarch: Arch,
         soabi: OsAbi,
+        allow_compat_arch: bool,
     ) -> Option<PathBuf> {
+        if self.debug_soname.as_deref() == Some(soname) {
+            self.trace_lookup(soname, soarch, soabi, allow_compat_arch);
+        }
+
+        let key = (soname.to_string(), soarch, soabi, allow_compat_arch);
+        if self.misses.lock().unwrap().contains(&key) {
+            return None;
+        }
+
+        let found = self.find_uncached_library(soname, soarch, soabi, allow_compat_arch);
+        match &found {
+            Some(lib) => {
+                if let Some(origin) = self.origins.get(lib) {
+                    self.hits.lock().unwrap().insert(origin.clone());
+                }
+            }
+            None => {
+                self.misses.lock().unwrap().insert(key);
+            }
+        }
+        found
+    }
+
+    fn find_uncached_library(
+        &self,
+        soname: &str,
+        soarch: Arch,
+        soabi: OsAbi,
+        allow_compat_arch: bool,
+    ) -> Option<PathBuf> {
+        if let Some(found) = self.find_exact_library(soname, soarch, soabi) {
+            return Some(found);
+        }
+
+        if !allow_compat_arch {
+            return None;
+        }
+
+        compatible_archs(soarch).iter().find_map(|&libarch| {
+            let found = self.find_exact_library(soname, libarch, soabi)?;
+            Event::warning(
+                "compat-arch",
+                format!(
+                    "using {} library {} for a {} binary",
+                    machine_to_str(libarch),
+                    found.display(),
+                    machine_to_str(soarch)
+                ),
+            )
+            .field("library", found.display())
+            .emit();
+            Some(found)
+        })
+    }
+
+    /// Explains which candidate a lookup selects and why the others are not
+    ///
+    /// Candidates are listed in the order they are considered, followed by
+    /// the ones of other architectures.
+    fn trace_lookup(&self, soname: &str, soarch: Arch, soabi: OsAbi, allow_compat_arch: bool) {
+        Event::info(
+            "debug-soname",
+            format!(
+                "resolving {soname} for a {} {} binary",
+                machine_to_str(soarch),
+                osabi_to_string(soabi)
+            ),
+        )
+        .emit();
+
+        let mut archs = vec![soarch];
+        if allow_compat_arch {
+            archs.extend(compatible_archs(soarch));
+        }
+        let mut other_archs: Vec<Arch> = self
+            .soname_cache
+            .keys()
+            .filter(|(name, arch)| name == soname && !archs.contains(arch))
+            .map(|&(_, arch)| arch)
+            .collect();
+        other_archs.sort();
+
+        let mut selected = None;
+        for arch in archs.iter().chain(&other_archs) {
+            let Some(libs) = self.soname_cache.get(&(soname.to_string(), *arch)) else {
+                continue;
+            };
+            for (lib, libabi) in libs {
+                let (outcome, reason) = if !archs.contains(arch) {
+                    ("rejected", "architecture differs".to_string())
+                } else if !osabi_are_compatible(soabi, *libabi) {
+                    ("rejected", "OS ABI is not compatible".to_string())
+                } else if let Some(selected) = &selected {
+                    ("shadowed", format!("{} comes first", path_string(selected)))
+                } else {
+                    selected = Some(lib.clone());
+                    ("selected", "first compatible candidate".to_string())
+                };
+                Event::info(
+                    "debug-soname",
+                    format!(
+                        " {} ({}, {}): {outcome}, {reason}",
+                        lib.display(),
+                        machine_to_str(*arch),
+                        osabi_to_string(*libabi)
+                    ),
+                )
+                .field("library", lib.display())
+                .field("arch", machine_to_str(*arch))
+                .field("osabi", osabi_to_string(*libabi))
+                .field("outcome", outcome)
+                .emit();
+            }
+        }
+
+        if selected.is_none() {
+            Event::info(
+                "debug-soname",
+                format!(" no candidate for {soname} is usable"),
+            )
+            .emit();
+        }
+    }
+
+    /// Finds the DT_NEEDED entries of indexed libraries that cannot be satisfied
+    ///
+    /// Dependencies are considered satisfied if they are absolute paths that
+    /// exist, are provided by libc or can be found in the cache itself.
+    pub(crate) fn validate(&self, libc_libs: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let libraries: BTreeSet<(&PathBuf, Arch, OsAbi)> = self
+            .soname_cache
+            .iter()
+            .flat_map(|((_, arch), libs)| libs.iter().map(|(lib, abi)| (lib, *arch, *abi)))
+            .collect();
+
+        let mut unresolved = Vec::new();
+        for (lib, arch, abi) in libraries {
+            // Members of --libs-archive are not on the disk
+            if !lib.is_file() {
+                continue;
+            }
+            let content = read_file(lib)?;
+            let Ok(elf) = ElfFile::new(&content) else {
+                continue;
+            };
+
+            for needed in elf.get_needed() {
+                let found = (needed.is_absolute() && needed.is_file())
+                    || libc_libs.iter().any(|dir| dir.join(needed).is_file())
+                    || needed
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .and_then(|name| self.find_library(name, arch, abi, false))
+                        .is_some();
+                if !found {
+                    unresolved.push((lib.clone(), needed.to_path_buf()));
+                }
+            }
+        }
+
+        Ok(unresolved)
+    }
+
+    fn find_exact_library(&self, soname: &str, soarch: Arch, soabi: OsAbi) -> Option<PathBuf> {
         self.soname_cache
             .get(&(soname.to_string(), soarch))
             .and_then(|libs| {
@@ -93,3 +569,469 @@ impl LibraryCache {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        misc::{asset, TempDir},
+        output,
+    };
+    use goblin::elf::header;
+    use std::{fs, slice};
+
+    #[test]
+    fn test_nested_libs_need_recursion() {
+        let dir = TempDir::new("nested");
+        let nested = dir.join("pkg/lib");
+        fs::create_dir_all(&nested).unwrap();
+        fs::copy(
+            asset("pam_systemd_home.so"),
+            nested.join("pam_systemd_home.so"),
+        )
+        .unwrap();
+        let find = |cache: &LibraryCache| {
+            cache.find_library(
+                "pam_systemd_home.so",
+                header::EM_X86_64,
+                header::ELFOSABI_NONE,
+                false,
+            )
+        };
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], false).unwrap();
+        assert_eq!(find(&cache), None);
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], true).unwrap();
+        assert_eq!(
+            find(&cache),
+            Some(nested.join("pam_systemd_home.so").canonicalize().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_scanned_dirs_are_skipped() {
+        let dir = TempDir::new("rescan");
+        dir.copy_asset("pam_systemd_home.so", "a/lib/libseeded.so");
+        dir.copy_asset("pam_systemd_home.so", "b/lib/libuncovered.so");
+        let find = |cache: &LibraryCache, soname| {
+            cache.find_library(soname, header::EM_X86_64, header::ELFOSABI_NONE, false)
+        };
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.join("a/lib")], false).unwrap();
+        // Added after the directory was scanned, so it is not found
+        dir.copy_asset("pam_systemd_home.so", "a/lib/libadded.so");
+        cache.populate_cache(&[dir.to_path_buf()], true).unwrap();
+
+        assert_eq!(
+            find(&cache, "libseeded.so"),
+            Some(dir.join("a/lib/libseeded.so"))
+        );
+        assert_eq!(
+            find(&cache, "libuncovered.so"),
+            Some(dir.join("b/lib/libuncovered.so"))
+        );
+        assert_eq!(find(&cache, "libadded.so"), None);
+    }
+
+    #[test]
+    fn test_load_caches() {
+        let dir = TempDir::new("caches");
+        let asset = &asset("pam_systemd_home.so");
+        let save = |name: &str, libs: &[&str]| {
+            let lib_dir = dir.join(name);
+            fs::create_dir_all(&lib_dir).unwrap();
+            for lib in libs {
+                fs::copy(asset, lib_dir.join(lib)).unwrap();
+            }
+            let mut cache = LibraryCache::new(vec!["so".into()]);
+            cache
+                .populate_cache(slice::from_ref(&lib_dir), false)
+                .unwrap();
+            let file = dir.join(format!("{name}.cache"));
+            cache.save(&file).unwrap();
+            fs::remove_dir_all(&lib_dir).unwrap();
+            (lib_dir, file)
+        };
+        let (first_dir, first) = save("first", &["libfoo.so.1"]);
+        let (second_dir, second) = save("second", &["libfoo.so.1", "libbar.so.1"]);
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        let output = output::capture(|| cache.load(&[first, second]).unwrap());
+        assert!(output.starts_with("warn: libfoo.so.1 (X86_64) is provided by both"));
+        let find =
+            |name: &str| cache.find_library(name, header::EM_X86_64, header::ELFOSABI_NONE, false);
+        assert_eq!(find("libbar.so.1"), Some(second_dir.join("libbar.so.1")));
+        assert_eq!(find("libfoo.so.1"), Some(first_dir.join("libfoo.so.1")));
+
+        // The directories of loaded caches are not scanned again
+        assert!(cache.cached_paths.contains(&first_dir));
+        assert!(cache.cached_paths.contains(&second_dir));
+
+        assert!(cache.load(&[dir.join("missing.cache")]).is_err());
+    }
+
+    #[test]
+    fn test_ties_are_broken_by_path() {
+        let dir = TempDir::new("ties");
+        for sub in ["b/lib", "a/lib"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+            fs::copy(
+                asset("pam_systemd_home.so"),
+                dir.join(sub).join("pam_systemd_home.so"),
+            )
+            .unwrap();
+        }
+
+        for _ in 0..3 {
+            let mut cache = LibraryCache::new(vec!["so".into()]);
+            cache.populate_cache(&[dir.to_path_buf()], true).unwrap();
+            assert_eq!(
+                cache.find_library(
+                    "pam_systemd_home.so",
+                    header::EM_X86_64,
+                    header::ELFOSABI_NONE,
+                    false,
+                ),
+                Some(
+                    dir.join("a/lib/pam_systemd_home.so")
+                        .canonicalize()
+                        .unwrap()
+                )
+            );
+        }
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache
+            .populate_cache(&[dir.join("b/lib"), dir.join("a/lib")], false)
+            .unwrap();
+        assert_eq!(
+            cache.find_library(
+                "pam_systemd_home.so",
+                header::EM_X86_64,
+                header::ELFOSABI_NONE,
+                false,
+            ),
+            Some(
+                dir.join("b/lib/pam_systemd_home.so")
+                    .canonicalize()
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn test_validate_incomplete_library_set() {
+        let dir = TempDir::new("validate");
+        dir.copy_asset("pam_systemd_home.so", "pam_systemd_home.so");
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], false).unwrap();
+        let unresolved = cache.validate(&[dir.join("no-libc")]).unwrap();
+
+        let lib = dir.join("pam_systemd_home.so").canonicalize().unwrap();
+        assert!(unresolved.contains(&(lib.clone(), PathBuf::from("libpam.so.0"))));
+        assert!(unresolved.contains(&(lib, PathBuf::from("libc.so.6"))));
+    }
+
+    #[test]
+    fn test_hybrid_executable_is_indexed() {
+        let dir = TempDir::new("hybrid");
+        let hybrid = dir.join("libhybrid.so.1");
+        fs::copy(asset("hybrid"), &hybrid).unwrap();
+
+        let content = read_file(&hybrid).unwrap();
+        let elf = ElfFile::new(&content).unwrap();
+        assert!(elf.is_dynamic_executable());
+        assert_eq!(elf.get_soname(), Some("libhybrid.so.1"));
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], true).unwrap();
+        assert_eq!(
+            cache.find_library(
+                "libhybrid.so.1",
+                header::EM_X86_64,
+                header::ELFOSABI_NONE,
+                false,
+            ),
+            Some(hybrid.canonicalize().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_repeated_miss_hits_negative_cache() {
+        let dir = TempDir::new("misses");
+        let find = |cache: &LibraryCache| {
+            cache.find_library(
+                "pam_systemd_home.so",
+                header::EM_X86_64,
+                header::ELFOSABI_NONE,
+                false,
+            )
+        };
+        let key = (
+            "pam_systemd_home.so".to_string(),
+            header::EM_X86_64,
+            header::ELFOSABI_NONE,
+            false,
+        );
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], false).unwrap();
+        assert_eq!(find(&cache), None);
+        assert!(cache.misses.lock().unwrap().contains(&key));
+
+        // A known miss is answered even if the index would now have a match
+        cache.soname_cache.insert(
+            ("pam_systemd_home.so".to_string(), header::EM_X86_64),
+            vec![(dir.join("pam_systemd_home.so"), header::ELFOSABI_NONE)],
+        );
+        assert_eq!(find(&cache), None);
+
+        // Populating the cache further forgets the misses
+        cache.populate_cache(&[dir.join("other")], false).unwrap();
+        assert!(cache.misses.lock().unwrap().is_empty());
+        assert_eq!(find(&cache), Some(dir.join("pam_systemd_home.so")));
+    }
+
+    #[test]
+    fn test_library_extensions() {
+        let dir = TempDir::new("exts");
+        let addon = dir.join("addon.node");
+        fs::copy(asset("pam_systemd_home.so"), &addon).unwrap();
+        let find = |cache: &LibraryCache| {
+            cache.find_library(
+                "addon.node",
+                header::EM_X86_64,
+                header::ELFOSABI_NONE,
+                false,
+            )
+        };
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], false).unwrap();
+        assert_eq!(find(&cache), None);
+
+        let mut cache = LibraryCache::new(vec!["so".into(), "node".into()]);
+        cache.populate_cache(&[dir.to_path_buf()], false).unwrap();
+        assert_eq!(find(&cache), Some(addon.canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn test_dangling_symlink_is_not_indexed() {
+        let dir = TempDir::new("dangling");
+        let lib = dir.join("lib");
+        let elsewhere = dir.join("elsewhere");
+        fs::create_dir_all(&lib).unwrap();
+        fs::create_dir_all(&elsewhere).unwrap();
+        let target = elsewhere.join("libpam.so.0");
+        fs::copy(asset("pam_systemd_home.so"), &target).unwrap();
+        std::os::unix::fs::symlink(&target, lib.join("libpam.so.0")).unwrap();
+        std::os::unix::fs::symlink(elsewhere.join("missing.so"), lib.join("libmissing.so.1"))
+            .unwrap();
+        let find = |cache: &LibraryCache, name| {
+            cache.find_library(name, header::EM_X86_64, header::ELFOSABI_NONE, false)
+        };
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(slice::from_ref(&lib), false).unwrap();
+        assert_eq!(
+            find(&cache, "libpam.so.0"),
+            Some(target.canonicalize().unwrap())
+        );
+        assert_eq!(find(&cache, "libmissing.so.1"), None);
+        assert!(cache
+            .soname_cache
+            .keys()
+            .all(|(name, _)| name != "libmissing.so.1"));
+    }
+
+    #[test]
+    fn test_symlink_modes() {
+        let dir = TempDir::new("symlinks");
+        let real = dir.join("real");
+        let farm = dir.join("farm");
+        fs::create_dir_all(&real).unwrap();
+        fs::create_dir_all(&farm).unwrap();
+        for name in ["libfoo.so.1.2.3", "libbar.so.1"] {
+            fs::copy(asset("pam_systemd_home.so"), real.join(name)).unwrap();
+        }
+        let real = real.canonicalize().unwrap();
+        std::os::unix::fs::symlink(real.join("libfoo.so.1.2.3"), farm.join("libfoo.so.1")).unwrap();
+        std::os::unix::fs::symlink(real.join("libbar.so.1"), farm.join("libbar.so.1")).unwrap();
+
+        let index = |mode: &str| {
+            let mut cache =
+                LibraryCache::new(vec!["so".into()]).with_symlink_mode(mode.parse().unwrap());
+            cache.populate_cache(slice::from_ref(&farm), false).unwrap();
+            let paths = |name: &str| -> Vec<PathBuf> {
+                cache.soname_cache[&(name.to_string(), header::EM_X86_64)]
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect()
+            };
+            (paths("libfoo.so.1"), paths("libbar.so.1"))
+        };
+
+        assert_eq!(
+            index("auto"),
+            (
+                vec![farm.join("libfoo.so.1")],
+                vec![real.join("libbar.so.1")]
+            )
+        );
+        assert_eq!(
+            index("preserve"),
+            (
+                vec![farm.join("libfoo.so.1")],
+                vec![farm.join("libbar.so.1")]
+            )
+        );
+        assert_eq!(
+            index("canonicalize"),
+            (
+                vec![real.join("libfoo.so.1.2.3")],
+                vec![real.join("libbar.so.1")]
+            )
+        );
+        let (foo, bar) = index("both");
+        assert!(foo.contains(&farm.join("libfoo.so.1")));
+        assert!(foo.contains(&real.join("libfoo.so.1.2.3")));
+        assert!(bar.contains(&farm.join("libbar.so.1")));
+        assert!(bar.contains(&real.join("libbar.so.1")));
+        assert!("resolve".parse::<SymlinkMode>().is_err());
+    }
+
+    #[test]
+    fn test_unused_dirs() {
+        let dir = TempDir::new("unused");
+        let used = dir.join("used");
+        let nested = dir.join("nested");
+        let unused = dir.join("unused");
+        for (lib_dir, name) in [
+            (&used, "libpam.so.0"),
+            (&nested.join("lib"), "libcap.so.2"),
+            (&unused, "libacl.so.1"),
+        ] {
+            fs::create_dir_all(lib_dir).unwrap();
+            fs::copy(asset("pam_systemd_home.so"), lib_dir.join(name)).unwrap();
+        }
+        let dirs = [used.clone(), nested.clone(), unused.clone()];
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_cache(&dirs, true).unwrap();
+        assert_eq!(cache.unused_dirs(&dirs).len(), 3);
+
+        for name in ["libpam.so.0", "libcap.so.2", "libmissing.so.1"] {
+            cache.find_library(name, header::EM_X86_64, header::ELFOSABI_NONE, false);
+        }
+        assert_eq!(cache.unused_dirs(&dirs), [unused.as_path()]);
+    }
+
+    #[test]
+    fn test_debug_soname() {
+        let dir = TempDir::new("debug");
+        let lib = fs::read(asset("pam_systemd_home.so")).unwrap();
+        let variant = |name: &str, osabi: OsAbi, arch: Arch| {
+            let mut lib = lib.clone();
+            lib[header::EI_OSABI] = osabi;
+            lib[18..20].copy_from_slice(&arch.to_le_bytes());
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(dir.join(name).join("libx.so.1"), lib).unwrap();
+            dir.join(name)
+        };
+        let dirs = [
+            variant("sysv", header::ELFOSABI_NONE, header::EM_X86_64),
+            variant("linux", header::ELFOSABI_LINUX, header::EM_X86_64),
+            variant("later", header::ELFOSABI_NONE, header::EM_X86_64),
+            variant("arm", header::ELFOSABI_NONE, header::EM_AARCH64),
+        ];
+
+        let mut cache =
+            LibraryCache::new(vec!["so".into()]).with_debug_soname(Some("libx.so.1".into()));
+        cache.populate_cache(&dirs, false).unwrap();
+        let mut found = None;
+        let output = crate::output::capture(|| {
+            found = cache.find_library(
+                "libx.so.1",
+                header::EM_X86_64,
+                header::ELFOSABI_FREEBSD,
+                false,
+            );
+            cache.find_library(
+                "libpam.so.0",
+                header::EM_X86_64,
+                header::ELFOSABI_FREEBSD,
+                false,
+            );
+        });
+
+        assert_eq!(
+            found,
+            Some(dirs[0].canonicalize().unwrap().join("libx.so.1"))
+        );
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 5, "{output}");
+        assert!(lines[0].starts_with("resolving libx.so.1 for a X86_64"));
+        assert!(lines[1].ends_with("(X86_64, ELFOSABI_SYSV): selected, first compatible candidate"));
+        assert!(lines[2].ends_with("(X86_64, ELFOSABI_LINUX): rejected, OS ABI is not compatible"));
+        assert!(lines[3].contains("(X86_64, ELFOSABI_SYSV): shadowed"));
+        assert!(lines[4].ends_with("(AARCH64, ELFOSABI_SYSV): rejected, architecture differs"));
+    }
+
+    #[test]
+    fn test_libs_archive() {
+        let dir = TempDir::new("archive");
+        let archive = dir.join("libs.tar");
+        let lib = fs::read(asset("pam_systemd_home.so")).unwrap();
+        fs::write(
+            &archive,
+            archive::tar(&[("lib/libpam.so.0", &lib), ("lib/libnotelf.so", b"text")]),
+        )
+        .unwrap();
+        let find = |cache: &LibraryCache, name| {
+            cache.find_library(name, header::EM_X86_64, header::ELFOSABI_NONE, false)
+        };
+
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        assert_eq!(find(&cache, "libpam.so.0"), None);
+        cache.populate_from_archive(&archive).unwrap();
+        assert_eq!(
+            find(&cache, "libpam.so.0"),
+            Some(archive.join("lib/libpam.so.0"))
+        );
+        assert_eq!(find(&cache, "libnotelf.so"), None);
+
+        // Symlinked members are found under their own name
+        let archive = dir.join("symlinks.tar");
+        fs::write(
+            &archive,
+            archive::tar_with_symlinks(
+                &[("lib/libpam.so.0", "libpam.so.0.1")],
+                &[("lib/libpam.so.0.1", &lib)],
+            ),
+        )
+        .unwrap();
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_from_archive(&archive).unwrap();
+        assert_eq!(
+            find(&cache, "libpam.so.0"),
+            Some(archive.join("lib/libpam.so.0"))
+        );
+
+        let archive = asset("libs.zip");
+        let mut cache = LibraryCache::new(vec!["so".into()]);
+        cache.populate_from_archive(&archive).unwrap();
+        assert_eq!(
+            find(&cache, "libnocfi.so"),
+            Some(archive.join("lib/libnocfi.so"))
+        );
+        assert_eq!(
+            find(&cache, "liborigin.so"),
+            Some(archive.join("lib/liborigin.so"))
+        );
+    }
+}
@@ -2,27 +2,269 @@
 //
 // SPDX-License-Identifier: EUPL-1.2
 
-use std::path::PathBuf;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::SymlinkMode,
+    elf::{machine_to_str, Arch, DlopenPriority, KernelVersion, DLOPEN_NOTE},
+    ld_so_conf,
+    manifest::PathManifest,
+    output::Format,
+    per_file_args::PerFileArgs,
+    state::DirState,
+};
 
 pub(crate) struct Cli {
+    pub(crate) command: Subcommand,
+
     pub(crate) patch: PatchConfig,
 
     pub(crate) libraries: LibrariesConfig,
+
+    pub(crate) report: ReportConfig,
+}
+
+/// What auto-patchelf is asked to do
+///
+/// Selected by a leading `patch`, `check` or `inspect` argument. Without one,
+/// `--validate-libs` and `--explain` pick the command like they always did,
+/// and the rest is an implicit `patch`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Subcommand {
+    /// Patch the files under `--paths`
+    Patch,
+    /// Check that the library set is self-contained, see `--validate-libs`
+    Check,
+    /// Trace the dependency resolution of `PatchConfig::explain`
+    Inspect,
+}
+
+impl Subcommand {
+    fn from_name(name: &OsStr) -> Option<Self> {
+        match name.to_str()? {
+            "patch" => Some(Self::Patch),
+            "check" => Some(Self::Check),
+            "inspect" => Some(Self::Inspect),
+            _ => None,
+        }
+    }
 }
 
 pub(crate) struct PatchConfig {
     pub(crate) ignore_missing: Vec<String>,
+    /// Dependencies provided at runtime by other means, e.g. LD_PRELOAD
+    pub(crate) skip_sonames: Vec<String>,
     pub(crate) recurse: bool,
+    /// How many directories deep the recursive searches go
+    pub(crate) max_scan_depth: Option<usize>,
     pub(crate) paths: Vec<PathBuf>,
+    /// Only files modified at or after this time, in seconds since the epoch, are patched
+    pub(crate) since: Option<i64>,
     pub(crate) runtime_dependencies: Vec<PathBuf>,
     pub(crate) append_rpaths: Vec<PathBuf>,
+    /// Append `append_rpaths` verbatim after deduplicating the resolved entries
+    pub(crate) no_dedup_append: bool,
+    /// Collapse RPATH entries that are the same directory on disk
+    pub(crate) dedup_by_inode: bool,
     pub(crate) keep_libc: bool,
+    /// Set DF_1_NODEFLIB and add the libc directory to RPATH instead of leaving libc to the dynamic linker
+    pub(crate) no_default_lib: bool,
+    pub(crate) no_libc_skip: bool,
+    pub(crate) allow_compat_arch: bool,
     pub(crate) extra_args: Vec<String>,
+    /// patchelf executable to run
+    pub(crate) patchelf: PathBuf,
+    pub(crate) state_size_limit: u64,
+    /// How many times to run the patching process, a hidden benchmarking aid
+    pub(crate) repeat: usize,
+    /// How many of the `--paths` to patch at the same time
+    pub(crate) jobs: usize,
+    pub(crate) explain: Option<PathBuf>,
+    /// Where to write the hash of the patch plan, `-` for stdout
+    pub(crate) plan_hash: Option<PathBuf>,
+    pub(crate) clear_symbol_versions: Vec<String>,
+    pub(crate) warn_rpath_length: usize,
+    pub(crate) max_errors: Option<usize>,
+    /// Files outside of this size window in bytes are skipped
+    pub(crate) min_size: Option<u64>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) touch_state: bool,
+    /// State files to compare instead of patching, as `(old, new)`
+    pub(crate) diff_state: Option<(PathBuf, PathBuf)>,
+    pub(crate) watch: bool,
+    pub(crate) per_file_args: PerFileArgs,
+    pub(crate) fail_on_warning: bool,
+    pub(crate) path_manifest: Option<PathManifest>,
+    /// Maps the build time staging prefix to the runtime prefix
+    pub(crate) staging_prefix: Option<(PathBuf, PathBuf)>,
+    /// Library directories of the host, searched after --libs with `--host-libs`
+    pub(crate) host_lib_dirs: Vec<PathBuf>,
+    /// Add the host directories dependencies are found in to RPATH
+    pub(crate) rpath_host: bool,
+    pub(crate) deep_resolve: bool,
+    pub(crate) reindex_after_patch: bool,
+    /// Record the RPATH of patched files in an extended attribute
+    pub(crate) xattr: bool,
+    /// Trust the configured dynamic linker without verifying it
+    pub(crate) no_interpreter_check: bool,
+    /// Leave the interpreter of files alone if it exists with the right architecture
+    pub(crate) keep_interpreter_if_valid: bool,
+    /// Only set the interpreter of launcher stubs, without resolving their dependencies
+    pub(crate) fast_stubs: bool,
+    /// Keep the existing RPATH entries of files and resolve dependencies through them
+    pub(crate) preserve_rpath: bool,
+    /// Warn about executables whose interpreter is in the store, so they cannot be relocated
+    pub(crate) interpreter_relative_check: bool,
+    pub(crate) check_interpreter_deps: bool,
+    /// Warn about dependencies lacking the CFI features of the files using them
+    pub(crate) check_cfi: bool,
+    pub(crate) allow_empty: bool,
+    /// Sonames looked up under a different name, as `(old, new)` pairs
+    pub(crate) soname_aliases: Vec<(String, String)>,
+    pub(crate) rewrite_needed: bool,
+    /// Note sections to read dlopen metadata from
+    pub(crate) dlopen_notes: Vec<String>,
+    pub(crate) validate: bool,
+    pub(crate) fail_invalid: bool,
+    /// Only files importing any of these symbols are patched
+    pub(crate) require_symbols: Vec<String>,
+    /// Files of other architectures are reported instead of silently skipped
+    pub(crate) expect_arch: Option<Arch>,
+    pub(crate) warn_unexpected_arch: bool,
+    /// Warn about files requiring a newer kernel than this
+    pub(crate) min_kernel: Option<KernelVersion>,
+    /// Resolve dlopen dependencies without adding their directories to RPATH
+    pub(crate) no_rpath_for_dlopen: bool,
+    /// What to do about missing dlopen dependencies of each priority
+    pub(crate) dlopen_actions: DlopenActions,
+    pub(crate) detect_prior_patching: bool,
+    pub(crate) skip_prepatched: bool,
+    /// Location of the Nix store, taken from `NIX_STORE`
+    pub(crate) store_dir: PathBuf,
 }
 
 pub(crate) struct LibrariesConfig {
     pub(crate) libraries: Vec<PathBuf>,
     pub(crate) add_existing: bool,
+    pub(crate) seed_from_report: Option<PathBuf>,
+    /// Directories of pkg-config files whose `libdir` is searched before --libs
+    pub(crate) pc_dirs: Vec<PathBuf>,
+    /// Other outputs of the derivation, whose `lib` directory is searched before --libs
+    pub(crate) sibling_outputs: Vec<PathBuf>,
+    pub(crate) libs_recursive: bool,
+    /// File extensions of shared objects to index, without the leading dot
+    pub(crate) library_extensions: Vec<String>,
+    /// Tar archives of libraries, indexed without extracting them
+    pub(crate) libs_archives: Vec<PathBuf>,
+    /// Caches written by `--save-cache`, merged before anything is scanned
+    pub(crate) load_caches: Vec<PathBuf>,
+    pub(crate) save_cache: Option<PathBuf>,
+    pub(crate) lib_symlink_mode: SymlinkMode,
+    /// Build time library directories with their runtime location
+    pub(crate) lib_runtime_map: Vec<(PathBuf, PathBuf)>,
+    pub(crate) debug_soname: Option<String>,
+}
+
+pub(crate) struct ReportConfig {
+    pub(crate) print0_patched: Option<PathBuf>,
+    pub(crate) print0_missing: Option<PathBuf>,
+    pub(crate) classify: bool,
+    pub(crate) resolved_dirs: Option<PathBuf>,
+    pub(crate) format: Format,
+    pub(crate) summary_only: bool,
+    pub(crate) log_file: Option<PathBuf>,
+    pub(crate) used_libs: Option<PathBuf>,
+    pub(crate) closure_report: Option<PathBuf>,
+    pub(crate) print_needed: Option<PathBuf>,
+    /// List the least reliably resolved dependencies first in the reports
+    pub(crate) sort_by_confidence: bool,
+    /// Where to write the patchelf invocations as JSON
+    pub(crate) commands_log: Option<PathBuf>,
+    pub(crate) group_missing: bool,
+    pub(crate) nix_report: Option<PathBuf>,
+    pub(crate) rpath_histogram: Option<PathBuf>,
+    pub(crate) report_unused_libs: bool,
+    pub(crate) normalize_output_paths: bool,
+    pub(crate) concurrency_metrics: bool,
+    /// Tell for every file why the state file let it be skipped or not
+    pub(crate) explain_cache: bool,
+    /// Unix socket of a supervising process to stream the events to
+    pub(crate) report_socket: Option<PathBuf>,
+}
+
+/// What to do about a dependency that cannot be found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MissingAction {
+    /// Fail the run, unless the dependency is in `--ignore-missing`
+    Fail,
+    Warn,
+    Ignore,
+}
+
+impl FromStr for MissingAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "warn" => Ok(Self::Warn),
+            "ignore" => Ok(Self::Ignore),
+            _ => Err(format!(
+                "unknown action {s:?}, expected fail, warn or ignore"
+            )),
+        }
+    }
+}
+
+/// Actions for missing dlopen dependencies by priority, set by `--dlopen-priority`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DlopenActions([MissingAction; 3]);
+
+impl Default for DlopenActions {
+    fn default() -> Self {
+        Self([
+            MissingAction::Fail,
+            MissingAction::Warn,
+            MissingAction::Warn,
+        ])
+    }
+}
+
+impl DlopenActions {
+    pub(crate) fn get(&self, priority: DlopenPriority) -> MissingAction {
+        self.0[priority as usize]
+    }
+
+    /// Overrides the actions given like `required=fail,suggested=ignore`
+    fn parse_into(&mut self, value: &str) -> Result<(), String> {
+        for mapping in value.split(',') {
+            let invalid = || {
+                format!("invalid --dlopen-priority {mapping:?}, expected a mapping like suggested=ignore")
+            };
+            let (priority, action) = mapping.split_once('=').ok_or_else(invalid)?;
+            let priority = DlopenPriority::ALL
+                .into_iter()
+                .find(|known| known.as_str() == priority.trim())
+                .ok_or_else(invalid)?;
+            self.0[priority as usize] = action.trim().parse()?;
+        }
+        Ok(())
+    }
+
+    /// Only warns instead of failing for dependencies of any priority
+    fn make_optional(&mut self) {
+        for action in &mut self.0 {
+            if *action == MissingAction::Fail {
+                *action = MissingAction::Warn;
+            }
+        }
+    }
 }
 
 /// Parse zero or more arguments
@@ -35,32 +277,257 @@ macro_rules! many0 {
     }};
 }
 
+/// Parses an architecture name as printed by `machine_to_str`, ignoring case
+fn parse_arch(value: &str) -> Result<Arch, String> {
+    (0..=Arch::MAX)
+        .find(|&arch| machine_to_str(arch).eq_ignore_ascii_case(value))
+        .filter(|&arch| machine_to_str(arch) != "UNKNOWN_MACHINE")
+        .ok_or_else(|| format!("unknown architecture {value:?}, expected a name like x86_64"))
+}
+
+/// Parses a kernel version like `4.19` or `5.10.0`, missing parts are zero
+fn parse_kernel_version(value: &str) -> Result<KernelVersion, String> {
+    let invalid = || format!("invalid --min-kernel {value:?}, expected a version like 5.10");
+    let mut version = [0; 3];
+    let parts: Vec<&str> = value.split('.').collect();
+    if parts.len() > version.len() {
+        return Err(invalid());
+    }
+    for (part, number) in parts.iter().zip(&mut version) {
+        *number = part.parse().map_err(|_| invalid())?;
+    }
+    Ok(version)
+}
+
+/// Gets the directories of the outputs of the derivation being built
+///
+/// In a Nix build, `outputs` lists the output names, like `out lib dev`, and
+/// every output has an environment variable with its path.
+fn outputs_from_env(outputs: Option<&str>, var: impl Fn(&str) -> Option<OsString>) -> Vec<PathBuf> {
+    outputs
+        .unwrap_or("out")
+        .split_whitespace()
+        .filter_map(|name| var(name).map(PathBuf::from))
+        .collect()
+}
+
+/// Parses a `--since` time into seconds since the epoch
+///
+/// Relative times are subtracted from `now`.
+fn parse_since(value: &str, now: i64) -> Result<i64, String> {
+    let invalid = || format!("invalid --since {value:?}, expected seconds or a time like -5min");
+
+    let Some(relative) = value.strip_prefix('-') else {
+        return value.parse().map_err(|_| invalid());
+    };
+
+    let split = relative
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(relative.len());
+    let (amount, unit) = relative.split_at(split);
+    let amount: i64 = amount.parse().map_err(|_| invalid())?;
+    let unit = match unit {
+        "" | "s" => 1,
+        "min" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(now - amount * unit)
+}
+
+impl PatchConfig {
+    /// Rewrites a resolved directory under the staging prefix to its runtime location
+    pub(crate) fn runtime_path(&self, dir: &Path) -> PathBuf {
+        match &self.staging_prefix {
+            Some((staging, runtime)) => match dir.strip_prefix(staging) {
+                Ok(rest) => runtime.join(rest),
+                Err(_) => dir.to_path_buf(),
+            },
+            None => dir.to_path_buf(),
+        }
+    }
+
+    /// Gets the name a dependency is looked up by in the library cache
+    pub(crate) fn soname_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        self.soname_aliases
+            .iter()
+            .find(|(old, _)| old == name)
+            .map_or(name, |(_, new)| new)
+    }
+
+    /// Arguments appended to every patchelf invocation on `path`
+    pub(crate) fn patchelf_args(&self, path: &Path) -> Vec<String> {
+        let mut args = Vec::new();
+        for symbol in &self.clear_symbol_versions {
+            args.push("--clear-symbol-version".to_string());
+            args.push(symbol.clone());
+        }
+        args.extend(self.per_file_args.args_for(path).iter().cloned());
+        args.extend(self.extra_args.iter().cloned());
+        args
+    }
+}
+
 impl Cli {
     pub(crate) fn parse() -> Result<Self, lexopt::Error> {
+        Self::parse_from(std::env::args_os().skip(1))
+    }
+
+    pub(crate) fn parse_from(
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+    ) -> Result<Self, lexopt::Error> {
         use lexopt::prelude::*;
 
         let mut ignore_missing = Vec::new();
+        let mut skip_sonames = Vec::new();
         let mut recurse = true;
+        let mut max_scan_depth = None;
         let mut paths = Vec::new();
+        let mut since = None;
         let mut libraries = Vec::new();
         let mut runtime_dependencies = Vec::new();
         let mut append_rpaths = Vec::new();
+        let mut no_dedup_append = false;
+        let mut dedup_by_inode = false;
         let mut keep_libc = false;
+        let mut no_default_lib = false;
+        let mut no_libc_skip = false;
+        let mut allow_compat_arch = false;
         let mut add_existing = true;
         let mut extra_args = Vec::new();
+        let mut patchelf = PathBuf::from("patchelf");
+        let mut state_size_limit = DirState::DEFAULT_SIZE_LIMIT;
+        let mut repeat = 1;
+        let mut jobs = 1;
+        let mut host_libs = false;
+        let mut rpath_host = false;
+        let mut explain = None;
+        let mut plan_hash = None;
+        let mut clear_symbol_versions = Vec::new();
+        let mut warn_rpath_length = 4096;
+        let mut max_errors = None;
+        let mut min_size = None;
+        let mut max_size = None;
+        let mut touch_state = false;
+        let mut diff_state = None;
+        let mut watch = false;
+        let mut per_file_args = PerFileArgs::default();
+        let mut fail_on_warning = false;
+        let mut blob_dir: Option<PathBuf> = None;
+        let mut manifest_path: Option<PathBuf> = None;
+        let mut prefix: Option<PathBuf> = None;
+        let mut staging: Option<PathBuf> = None;
+        let mut deep_resolve = false;
+        let mut reindex_after_patch = false;
+        let mut xattr = false;
+        let mut no_interpreter_check = false;
+        let mut keep_interpreter_if_valid = false;
+        let mut fast_stubs = false;
+        let mut preserve_rpath = false;
+        let mut interpreter_relative_check = false;
+        let mut check_interpreter_deps = false;
+        let mut check_cfi = false;
+        let mut allow_empty = false;
+        let mut soname_aliases = Vec::new();
+        let mut rewrite_needed = false;
+        let mut dlopen_notes = vec![DLOPEN_NOTE.to_string()];
+        let mut validate = false;
+        let mut fail_invalid = false;
+        let mut detect_prior_patching = false;
+        let mut skip_prepatched = false;
+        let mut require_symbols = Vec::new();
+        let mut expect_arch = None;
+        let mut warn_unexpected_arch = false;
+        let mut min_kernel = None;
+        let mut no_rpath_for_dlopen = false;
+        let mut dlopen_actions = DlopenActions::default();
+        let mut dlopen_optional = false;
+        let mut print0_patched = None;
+        let mut print0_missing = None;
+        let mut classify = false;
+        let mut resolved_dirs = None;
+        let mut format = Format::detect();
+        let mut summary_only = false;
+        let mut log_file = None;
+        let mut used_libs = None;
+        let mut closure_report = None;
+        let mut print_needed = None;
+        let mut sort_by_confidence = false;
+        let mut commands_log = None;
+        let mut group_missing = false;
+        let mut nix_report = None;
+        let mut rpath_histogram = None;
+        let mut report_unused_libs = false;
+        let mut normalize_output_paths = false;
+        let mut concurrency_metrics = false;
+        let mut explain_cache = false;
+        let mut report_socket = None;
+        let mut seed_from_report = None;
+        let mut pc_dirs = Vec::new();
+        let mut sibling_outputs = None;
+        let mut libs_recursive = false;
+        let mut library_extensions = vec!["so".to_string()];
+        let mut validate_libs = false;
+        let mut libs_archives = Vec::new();
+        let mut load_caches = Vec::new();
+        let mut save_cache = None;
+        let mut lib_symlink_mode = SymlinkMode::default();
+        let mut lib_runtime_map = Vec::new();
+        let mut debug_soname = None;
 
-        let mut parser = lexopt::Parser::from_env();
+        let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+        let subcommand = args.first().and_then(|arg| Subcommand::from_name(arg));
+        if subcommand.is_some() {
+            args.remove(0);
+        }
+
+        let mut parser = lexopt::Parser::from_args(args);
         while let Some(arg) = parser.next()? {
             match arg {
+                Value(file) if subcommand == Some(Subcommand::Inspect) && explain.is_none() => {
+                    explain = Some(file.into());
+                }
                 Long("ignore-missing") => {
                     ignore_missing = many0!(parser);
                 }
+                Long("skip-soname") => {
+                    skip_sonames = many0!(parser);
+                }
+                Long("fail-on-warning") => {
+                    fail_on_warning = true;
+                }
                 Long("no-recurse") => {
                     recurse = false;
                 }
+                Long("max-scan-depth") => {
+                    max_scan_depth = Some(parser.value()?.parse()?);
+                }
                 Long("paths") => {
                     paths = many0!(parser);
                 }
+                Long("since") => {
+                    let value = parser.value()?.string()?;
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |now| now.as_secs() as i64);
+                    since = Some(parse_since(&value, now)?);
+                }
+                Long("allow-empty") => {
+                    allow_empty = true;
+                }
+                Long("blob-dir") => {
+                    blob_dir = Some(parser.value()?.into());
+                }
+                Long("path-manifest") => {
+                    manifest_path = Some(parser.value()?.into());
+                }
+                Long("prefix") => {
+                    prefix = Some(parser.value()?.into());
+                }
+                Long("staging") => {
+                    staging = Some(parser.value()?.into());
+                }
                 Long("libs") => {
                     libraries = many0!(parser);
                 }
@@ -70,42 +537,487 @@ impl Cli {
                 Long("append-rpaths") => {
                     append_rpaths = many0!(parser);
                 }
+                Long("no-dedup-append") => {
+                    no_dedup_append = true;
+                }
+                Long("dedup-by-inode") => {
+                    dedup_by_inode = true;
+                }
+                Long("deep-resolve") => {
+                    deep_resolve = true;
+                }
+                Long("reindex-after-patch") => {
+                    reindex_after_patch = true;
+                }
+                Long("xattr") => {
+                    xattr = true;
+                }
+                Long("keep-interpreter-if-valid") => {
+                    keep_interpreter_if_valid = true;
+                }
+                Long("fast-stubs") => {
+                    fast_stubs = true;
+                }
+                Long("preserve-rpath") => {
+                    preserve_rpath = true;
+                }
+                Long("interpreter-relative-check") => {
+                    interpreter_relative_check = true;
+                }
+                Long("no-interpreter-check") => {
+                    no_interpreter_check = true;
+                }
+                Long("check-interpreter-deps") => {
+                    check_interpreter_deps = true;
+                }
+                Long("check-cfi") => {
+                    check_cfi = true;
+                }
                 Long("keep-libc") => {
                     keep_libc = true;
                 }
+                Long("no-default-lib") => {
+                    no_default_lib = true;
+                }
+                Long("no-libc-skip") => {
+                    no_libc_skip = true;
+                }
+                Long("allow-compat-arch") => {
+                    allow_compat_arch = true;
+                }
+                Long("libs-recursive") => {
+                    libs_recursive = true;
+                }
+                Long("library-extensions") => {
+                    let extensions: Vec<String> = many0!(parser);
+                    library_extensions = extensions
+                        .into_iter()
+                        .map(|ext| ext.trim_start_matches('.').to_string())
+                        .collect();
+                }
+                Long("validate-libs") => {
+                    validate_libs = true;
+                }
+                Long("libs-archive") => {
+                    libs_archives.push(parser.value()?.into());
+                }
+                Long("load-cache") => {
+                    load_caches = many0!(parser);
+                }
+                Long("save-cache") => {
+                    save_cache = Some(parser.value()?.into());
+                }
+                Long("lib-symlink-mode") => {
+                    lib_symlink_mode = parser.value()?.parse()?;
+                }
+                Long("lib-runtime-map") => {
+                    let mapping = parser.value()?.string()?;
+                    let Some((build, runtime)) = mapping.split_once('=') else {
+                        return Err(format!(
+                            "invalid --lib-runtime-map {mapping:?}, expected BUILD=RUNTIME"
+                        )
+                        .into());
+                    };
+                    lib_runtime_map.push((PathBuf::from(build), PathBuf::from(runtime)));
+                }
+                Long("debug-soname") => {
+                    debug_soname = Some(parser.value()?.string()?);
+                }
                 Long("ignore-existing") => {
                     add_existing = false;
                 }
                 Long("extra-args") => {
                     extra_args = many0!(parser);
                 }
+                Long("patchelf") => {
+                    patchelf = parser.value()?.into();
+                }
+                Long("state-size-limit") => {
+                    state_size_limit = parser.value()?.parse()?;
+                }
+                Long("jobs") => {
+                    jobs = parser.value()?.parse()?;
+                    if jobs == 0 {
+                        return Err("--jobs must be at least 1".into());
+                    }
+                }
+                Long("host-libs") => {
+                    host_libs = true;
+                }
+                Long("rpath-host") => {
+                    rpath_host = true;
+                }
+                // Experimental and left out of --help, only meant for benchmarking
+                Long("repeat") => {
+                    repeat = parser.value()?.parse()?;
+                    if repeat == 0 {
+                        return Err("--repeat must be at least 1".into());
+                    }
+                }
+                Long("clear-symbol-version") => {
+                    clear_symbol_versions = many0!(parser);
+                }
+                Long("soname-alias") => {
+                    let alias = parser.value()?.string()?;
+                    let Some((old, new)) = alias.split_once('=') else {
+                        return Err(
+                            format!("invalid --soname-alias {alias:?}, expected OLD=NEW").into(),
+                        );
+                    };
+                    soname_aliases.push((old.to_string(), new.to_string()));
+                }
+                Long("rewrite-needed") => {
+                    rewrite_needed = true;
+                }
+                Long("validate") => {
+                    validate = true;
+                }
+                Long("fail-invalid") => {
+                    validate = true;
+                    fail_invalid = true;
+                }
+                Long("detect-prior-patching") => {
+                    detect_prior_patching = true;
+                }
+                Long("skip-prepatched") => {
+                    detect_prior_patching = true;
+                    skip_prepatched = true;
+                }
+                Long("expect-arch") => {
+                    expect_arch = Some(parse_arch(&parser.value()?.string()?)?);
+                }
+                Long("min-kernel") => {
+                    min_kernel = Some(parse_kernel_version(&parser.value()?.string()?)?);
+                }
+                Long("warn-unexpected-arch") => {
+                    warn_unexpected_arch = true;
+                }
+                Long("require-symbol") => {
+                    require_symbols = many0!(parser);
+                }
+                Long("dlopen-priority") => {
+                    dlopen_actions.parse_into(&parser.value()?.string()?)?;
+                }
+                Long("dlopen-optional") => {
+                    dlopen_optional = true;
+                }
+                Long("no-rpath-for-dlopen") => {
+                    no_rpath_for_dlopen = true;
+                }
+                Long("dlopen-note-name") => {
+                    dlopen_notes.push(parser.value()?.string()?);
+                }
+                Long("warn-rpath-length") => {
+                    warn_rpath_length = parser.value()?.parse()?;
+                }
+                Long("max-errors") => {
+                    max_errors = Some(parser.value()?.parse()?);
+                }
+                Long("min-size") => {
+                    min_size = Some(parser.value()?.parse()?);
+                }
+                Long("max-size") => {
+                    max_size = Some(parser.value()?.parse()?);
+                }
+                Long("touch-state") => {
+                    touch_state = true;
+                }
+                Long("diff-state") => {
+                    let old = parser.value()?.into();
+                    diff_state = Some((old, parser.value()?.into()));
+                }
+                Long("watch") => {
+                    watch = true;
+                }
+                Long("per-file-args") => {
+                    let path = PathBuf::from(parser.value()?);
+                    per_file_args = PerFileArgs::load(&path)
+                        .map_err(|err| format!("failed to load {}: {err}", path.display()))?;
+                }
+                Long("explain") => {
+                    explain = Some(parser.value()?.into());
+                }
+                Long("plan-hash") => {
+                    plan_hash = Some(parser.value()?.into());
+                }
+                Long("print0-patched") => {
+                    print0_patched = Some(parser.value()?.into());
+                }
+                Long("print0-missing") => {
+                    print0_missing = Some(parser.value()?.into());
+                }
+                Long("classify") => {
+                    classify = true;
+                }
+                Long("format") => {
+                    format = parser.value()?.parse()?;
+                }
+                Long("summary-only") => {
+                    summary_only = true;
+                }
+                Long("log-file") => {
+                    log_file = Some(parser.value()?.into());
+                }
+                Long("concurrency-metrics") => {
+                    concurrency_metrics = true;
+                }
+                Long("explain-cache") => {
+                    explain_cache = true;
+                }
+                Long("report-socket") => {
+                    report_socket = Some(parser.value()?.into());
+                }
+                Long("used-libs") => {
+                    used_libs = Some(parser.value()?.into());
+                }
+                Long("closure-report") => {
+                    closure_report = Some(parser.value()?.into());
+                }
+                Long("nix-report") => {
+                    nix_report = Some(parser.value()?.into());
+                }
+                Long("group-missing") => {
+                    group_missing = true;
+                }
+                Long("print-needed") => {
+                    print_needed = Some(parser.value()?.into());
+                }
+                Long("sort-by-confidence") => {
+                    sort_by_confidence = true;
+                }
+                Long("commands-log") => {
+                    commands_log = Some(parser.value()?.into());
+                }
+                Long("resolved-dirs") => {
+                    resolved_dirs = Some(parser.value()?.into());
+                }
+                Long("rpath-histogram") => {
+                    rpath_histogram = Some(parser.value()?.into());
+                }
+                Long("report-unused-libs") => {
+                    report_unused_libs = true;
+                }
+                Long("normalize-output-paths") => {
+                    normalize_output_paths = true;
+                }
+                Long("seed-from-report") => {
+                    seed_from_report = Some(parser.value()?.into());
+                }
+                Long("pc-dir") => {
+                    pc_dirs.push(parser.value()?.into());
+                }
+                Long("sibling-outputs") => {
+                    sibling_outputs = Some(match parser.values() {
+                        Ok(values) => values.map(PathBuf::from).collect(),
+                        Err(_) => Vec::new(),
+                    });
+                }
                 Short('h') | Long("help") => {
                     println!(
                         r#"automatically fixing dependencies for ELF files
 
 auto-patchelf tries as hard as possible to patch the provided binary files by looking for compatible libraries in the provided paths.
 
-Usage: auto-patchelf [OPTIONS] --paths [<PATHS>...]
+Usage: auto-patchelf [patch] [OPTIONS] --paths [<PATHS>...]
+       auto-patchelf check [OPTIONS] --libs [<LIBRARIES>...]
+       auto-patchelf inspect [OPTIONS] <FILE>
+
+Commands:
+  patch    Patch the ELF files under --paths. This is the default when no command is given
+  check    Check that every library in the library set finds its own dependencies, same as --validate-libs
+  inspect  Trace the dependency resolution of a single file without patching it, same as --explain
 
 Options:
       --ignore-missing [<IGNORE_MISSING>...]
           Do not fail when some dependencies are not found
+      --skip-soname [<SONAMES>...]
+          Treat these dependencies as satisfied without looking for them or adding anything to RPATH, e.g. when they are provided by LD_PRELOAD
+      --fail-on-warning
+          Fail if any warning was emitted, including ignored missing dependencies
       --no-recurse
           Disable the recursive traversal of paths to patch
+      --max-scan-depth <N>
+          Do not search more than N directories deep in the paths to patch and in the directories searched for libraries recursively. Entries of the directories themselves are at depth 0
       --paths [<PATHS>...]
           Paths whose content needs to be patched. Single files and directories are accepted. Directories are traversed recursively by default
+      --since <TIME>
+          Only patch files modified at or after TIME, given in seconds since the epoch or relative to now like `-5min`. Units are s, min, h and d
+      --allow-empty
+          Exit successfully instead of failing when there are no paths to patch
+      --blob-dir <DIR>
+          Directory of content-addressed ELF blobs to patch in place. Requires --path-manifest
+      --path-manifest <FILE>
+          Mapping of logical paths to blob hashes in --blob-dir, one `<path>\t<hash>` entry per line
+      --prefix <RUNTIME>
+          Runtime location of the --staging directory. Resolved directories under STAGING are rewritten to be under RUNTIME
+      --staging <BUILD>
+          Build time location of the --prefix directory
       --runtime-dependencies [<RUNTIME_DEPENDENCIES>...]
           Paths to prepend to the runtime path of executable binaries. Subject to deduplication, which may imply some reordering
       --append-rpaths [<APPEND_RPATHS>...]
           Paths to append to all runtime paths unconditionally
+      --no-dedup-append
+          Keep the paths of --append-rpaths verbatim at the end of RPATH, even if a resolved entry is the same
+      --dedup-by-inode
+          Also deduplicate RPATH entries that are different paths of the same directory, like directory symlinks. The first spelling is kept
+      --deep-resolve
+          Retry missing dependencies after also searching the directories and RPATHs of the resolved libraries
+      --reindex-after-patch
+          Index the libraries in the new RPATH of each patched file, so that files patched later can resolve dependencies from them
+      --xattr
+          Record the RPATH of each patched file in its user.auto-patchelf.rpath extended attribute. Files whose attribute matches their RPATH are not patched again, even if they were moved away from their directory's state file
+      --jobs <N>
+          Patch up to N of the --paths at the same time, files in the same directory one after the other. Cannot be used with --reindex-after-patch [default: 1]
+      --keep-interpreter-if-valid
+          Do not set the interpreter of executables whose current interpreter exists and has the right architecture
+      --fast-stubs
+          Only set the interpreter of small launcher stubs needing nothing but libc, skipping their dependency resolution
+      --preserve-rpath
+          Keep the existing RPATH entries of files in front of the resolved ones. Entries relative to $ORIGIN are kept as-is and searched relative to the location of the file in the output
+      --interpreter-relative-check
+          Warn about executables that cannot be relocated because their interpreter is an absolute path into the Nix store. ELF cannot express a relative interpreter, so relocatable bundles have to start these through a wrapper invoking the dynamic linker
+      --no-interpreter-check
+          Do not verify that the dynamic linker and libc exist. The interpreter path is embedded as-is and architecture checks are skipped if it cannot be read
+      --check-interpreter-deps
+          Warn if the dependencies of the dynamic linker itself cannot be satisfied
+      --check-cfi
+          Warn if a file uses control-flow integrity features (Intel CET, ARM BTI/PAC) that the libraries it is resolved against do not support
       --keep-libc
           Attempt to search for and relink libc dependencies
+      --no-libc-skip
+          Resolve libc dependencies through the library set like any other, failing if they are not found there. Makes --keep-libc redundant
+      --no-default-lib
+          Forbid the dynamic linker to search its default library paths (DF_1_NODEFLIB). Libc dependencies are added to RPATH from the library set or from libc itself
+      --allow-compat-arch
+          Fall back to libraries of a compatible architecture when no exact match is found
       --extra-args [<EXTRA_ARGS>...]
           Extra arguments to pass to patchelf. This argument should always come last
+      --patchelf <PATH>
+          patchelf executable to use [default: patchelf]
       --libs [<LIBRARIES>...]
           Paths where libraries are searched for. Single files and directories are accepted. Directories are not searched recursively
+      --libs-recursive
+          Search the directories given in --libs recursively
+      --libs-archive <ARCHIVE>
+          Search for libraries in an uncompressed tar or a zip archive without extracting it. Members are found under ARCHIVE as if it was a directory, map it to the runtime location with --staging and --prefix. Can be repeated
+      --load-cache [<FILES>...]
+          Load library caches written by --save-cache before searching anything else. Their directories are not searched again. If several caches provide a soname, the first one wins
+      --save-cache <FILE>
+          Write the library cache to FILE once it is complete, to be reused with --load-cache
+      --lib-symlink-mode <MODE>
+          Which directory a library found through a symlink ends up in RPATH with: `preserve` for the symlink, `canonicalize` for its target, `both` to index it under both, `auto` for the target only if it has the same file name [default: auto]
+      --lib-runtime-map <BUILD=RUNTIME>
+          Libraries found under the directory BUILD are read from there, but end up in RPATH under RUNTIME, where they are installed later. Can be repeated, the first matching BUILD wins
+      --debug-soname <NAME>
+          Explain every lookup of the soname NAME: each candidate library with its architecture and OS ABI, and why it was selected or not
+      --library-extensions [<EXTENSIONS>...]
+          File extensions of shared objects to index, optionally followed by a version suffix [default: so]
+      --validate-libs
+          Check that the dependencies of all libraries can be satisfied within the library set, then exit
       --ignore-existing
           Do not add the existing rpaths of the patched files to the list of directories to search for dependencies
+      --state-size-limit <BYTES>
+          Maximum size of the incremental state file to load [default: 33554432]
+      --clear-symbol-version [<SYMBOLS>...]
+          Clear the symbol version of SYMBOLS in patched files. Use with care, as it can mask real ABI incompatibilities
+      --soname-alias <OLD=NEW>
+          Satisfy dependencies on OLD with a library named NEW. Can be given multiple times
+      --rewrite-needed
+          Also replace aliased DT_NEEDED entries with their --soname-alias target
+      --validate
+          Warn about malformed ELF files, like dynamic entries pointing outside the string table
+      --fail-invalid
+          Fail to patch malformed ELF files. Implies --validate
+      --require-symbol [<SYMBOLS>...]
+          Only patch files that import at least one of SYMBOLS
+      --expect-arch <ARCH>
+          Fail if any file is not of architecture ARCH, e.g. x86_64 or aarch64, instead of skipping it
+      --warn-unexpected-arch
+          Only warn about the files reported by --expect-arch
+      --min-kernel <VERSION>
+          Warn if a file requires a newer Linux kernel than VERSION according to its `.note.ABI-tag`
+      --detect-prior-patching
+          Report files whose RPATH already points entirely into existing Nix store paths
+      --skip-prepatched
+          Do not patch the files reported by --detect-prior-patching. Implies --detect-prior-patching
+      --dlopen-note-name <SECTION>
+          Also read dlopen metadata from note SECTION, in addition to .note.dlopen. Can be given multiple times
+      --dlopen-priority <PRIORITY=ACTION,...>
+          What to do if a dlopen dependency of a priority is missing, like `suggested=ignore`. Priorities are required, recommended and suggested, actions fail, warn and ignore [default: required=fail,recommended=warn,suggested=warn]
+      --dlopen-optional
+          Only warn about missing dlopen dependencies, whatever their priority. Missing DT_NEEDED entries still fail
+      --no-rpath-for-dlopen
+          Resolve and report dlopen dependencies, but do not add their directories to RPATH
+      --warn-rpath-length <LENGTH>
+          Warn when the RPATH of a patched file is longer than LENGTH characters [default: 4096]
+      --max-errors <N>
+          Abort as soon as N dependencies could not be satisfied [default: unlimited]
+      --min-size <BYTES>
+          Skip files smaller than BYTES
+      --max-size <BYTES>
+          Skip files larger than BYTES with a warning, e.g. huge bundled blobs that are expensive to patch [default: unlimited]
+      --touch-state
+          Record the current state of all ELF files as up to date without patching them
+      --diff-state <OLD> <NEW>
+          Print the entries added, removed or modified between two .auto-patchelf.state files, then exit
+      --watch
+          Keep running after patching and re-patch ELF files whenever they change. The paths and libraries are polled every 500ms
+      --per-file-args <FILE>
+          Extra patchelf arguments for files matching a glob, one `<glob>\t<args>` mapping per line. The first match wins
+      --explain <FILE>
+          Trace the dependency resolution of a single file without patching it
+      --plan-hash <PATH>
+          Write a hash of the interpreter and RPATH every file would get to PATH, or stdout if PATH is `-`, without patching
+      --print0-patched <PATH>
+          Write the NUL-separated list of successfully patched files to PATH
+      --print0-missing <PATH>
+          Write the NUL-separated list of files with unsatisfied dependencies to PATH
+      --classify
+          Report the likely source language (Rust, C++, Go) of each patched file
+      --format <FORMAT>
+          Output format on stdout, either `human`, `jsonl` for one JSON object per event or `github` for GitHub Actions workflow commands [default: github if GITHUB_ACTIONS is true, human otherwise]
+      --summary-only
+          Only print the final summary and errors, suppressing all per-file output
+      --log-file <PATH>
+          Also write all output with timestamps to PATH, regardless of --summary-only. An existing log is kept as PATH.1
+      --report-socket <PATH>
+          Also stream all events as JSON lines to the Unix socket at PATH, e.g. to collect the results of many runs in a supervising process
+      --concurrency-metrics
+          Report time spent waiting for the library cache and running patchelf at the end
+      --explain-cache
+          Tell for every file whether it is skipped because its modification time matches the state file, or why it is processed
+      --used-libs <PATH>
+          Write the library files dependencies were resolved to, one per line, to PATH
+      --closure-report <PATH>
+          Write the transitive library closure of each patched file and its size on disk as JSON to PATH, with the direct dependencies and their confidence
+      --nix-report <PATH>
+          Write the interpreter, RPATH and resolved dependencies of each patched file and the missing dependencies as a Nix attribute set to PATH
+      --group-missing
+          Report each missing dependency once, listing all files that need it
+      --print-needed <PATH>
+          Write the dependencies resolved from the library set and the missing ones in the format of ldd to PATH. The confidence of each resolution follows in brackets
+      --sort-by-confidence
+          List the dependencies of each file in --print-needed, --nix-report and --closure-report from the least to the most reliably resolved, missing ones first
+      --commands-log <PATH>
+          Write the patchelf invocations to PATH as a JSON list in the style of compile_commands.json. With --explain, the invocations that would run are written
+      --resolved-dirs <PATH>
+          Write the directories dependencies were resolved to, one per line, to PATH
+      --rpath-histogram <PATH>
+          Write how many patched files have each directory in their RPATH to PATH, most frequent first
+      --report-unused-libs
+          List the directories and archives given in --libs and --libs-archive that provided no dependency
+      --normalize-output-paths
+          Replace the hashes of Nix store paths in the output and the log file with a fixed string, so that logs of different builds can be diffed
+      --seed-from-report <PATH>
+          Search the directories listed in a previous --resolved-dirs report before --libs. The parts of --libs trees they cover are not scanned again
+      --sibling-outputs [<DIRS>...]
+          Search the `lib` directories of the other outputs of a multi-output derivation before --libs. Without DIRS, the outputs are taken from the `outputs` environment variable of the Nix build
+      --pc-dir <DIR>
+          Search the `libdir` directories of the pkg-config files in DIR before --libs. Can be repeated
+      --host-libs
+          Search the library directories of the host from /etc/ld.so.conf and the default ones after --libs. Dependencies found there are left to the dynamic linker of the host
+      --rpath-host
+          Add the host directories dependencies are found in to RPATH instead. Requires --host-libs
   -h, --help
           Print help
 "#
@@ -116,20 +1028,359 @@ Options:
             }
         }
 
+        let command = match subcommand {
+            Some(Subcommand::Patch) if validate_libs || explain.is_some() => {
+                return Err("--validate-libs and --explain cannot be used with patch".into())
+            }
+            Some(Subcommand::Check) if explain.is_some() => {
+                return Err("--explain cannot be used with check".into())
+            }
+            Some(Subcommand::Inspect) if explain.is_none() => {
+                return Err("inspect requires a FILE to trace".into())
+            }
+            Some(command) => command,
+            None if validate_libs => Subcommand::Check,
+            None if explain.is_some() => Subcommand::Inspect,
+            None => Subcommand::Patch,
+        };
+
+        let path_manifest = match (manifest_path, blob_dir) {
+            (Some(manifest), Some(blob_dir)) => Some(
+                PathManifest::load(&manifest, blob_dir)
+                    .map_err(|err| format!("failed to load {}: {err}", manifest.display()))?,
+            ),
+            (None, None) => None,
+            _ => return Err("--blob-dir and --path-manifest must be used together".into()),
+        };
+
+        if jobs > 1 && reindex_after_patch {
+            return Err("--reindex-after-patch cannot be used with --jobs".into());
+        }
+
+        let host_lib_dirs = match (host_libs, rpath_host) {
+            (true, _) => ld_so_conf::host_lib_dirs(Path::new(ld_so_conf::LD_SO_CONF))
+                .map_err(|err| format!("failed to read {}: {err}", ld_so_conf::LD_SO_CONF))?,
+            (false, false) => Vec::new(),
+            (false, true) => return Err("--rpath-host requires --host-libs".into()),
+        };
+
+        if dlopen_optional {
+            dlopen_actions.make_optional();
+        }
+
+        let staging_prefix = match (staging, prefix) {
+            (Some(staging), Some(prefix)) => Some((staging, prefix)),
+            (None, None) => None,
+            _ => return Err("--prefix and --staging must be used together".into()),
+        };
+
         Ok(Self {
+            command,
             patch: PatchConfig {
                 ignore_missing,
+                skip_sonames,
                 recurse,
+                max_scan_depth,
                 paths,
+                since,
                 runtime_dependencies,
                 append_rpaths,
+                no_dedup_append,
+                dedup_by_inode,
                 keep_libc,
+                no_default_lib,
+                no_libc_skip,
+                allow_compat_arch,
                 extra_args,
+                patchelf,
+                state_size_limit,
+                repeat,
+                jobs,
+                explain,
+                plan_hash,
+                clear_symbol_versions,
+                warn_rpath_length,
+                max_errors,
+                min_size,
+                max_size,
+                touch_state,
+                diff_state,
+                watch,
+                per_file_args,
+                fail_on_warning,
+                path_manifest,
+                staging_prefix,
+                host_lib_dirs,
+                rpath_host,
+                deep_resolve,
+                reindex_after_patch,
+                xattr,
+                no_interpreter_check,
+                keep_interpreter_if_valid,
+                fast_stubs,
+                preserve_rpath,
+                interpreter_relative_check,
+                check_interpreter_deps,
+                check_cfi,
+                allow_empty,
+                soname_aliases,
+                rewrite_needed,
+                dlopen_notes,
+                validate,
+                fail_invalid,
+                require_symbols,
+                expect_arch,
+                warn_unexpected_arch,
+                min_kernel,
+                no_rpath_for_dlopen,
+                dlopen_actions,
+                detect_prior_patching,
+                skip_prepatched,
+                store_dir: env::var_os("NIX_STORE")
+                    .map_or_else(|| "/nix/store".into(), PathBuf::from),
             },
             libraries: LibrariesConfig {
                 libraries,
                 add_existing,
+                seed_from_report,
+                pc_dirs,
+                sibling_outputs: match sibling_outputs {
+                    Some(outputs) if outputs.is_empty() => {
+                        outputs_from_env(env::var("outputs").ok().as_deref(), |name| {
+                            env::var_os(name)
+                        })
+                    }
+                    outputs => outputs.unwrap_or_default(),
+                },
+                libs_recursive,
+                library_extensions,
+                libs_archives,
+                load_caches,
+                save_cache,
+                lib_symlink_mode,
+                lib_runtime_map,
+                debug_soname,
+            },
+            report: ReportConfig {
+                print0_patched,
+                print0_missing,
+                classify,
+                resolved_dirs,
+                format,
+                summary_only,
+                log_file,
+                used_libs,
+                closure_report,
+                print_needed,
+                sort_by_confidence,
+                commands_log,
+                group_missing,
+                nix_report,
+                rpath_histogram,
+                report_unused_libs,
+                normalize_output_paths,
+                concurrency_metrics,
+                explain_cache,
+                report_socket,
             },
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staging_prefix() {
+        let cli =
+            Cli::parse_from(["--prefix", "/opt/app", "--staging", "/staging/opt/app"]).unwrap();
+
+        assert_eq!(
+            cli.patch
+                .runtime_path(Path::new("/staging/opt/app/lib/nested")),
+            PathBuf::from("/opt/app/lib/nested")
+        );
+        assert_eq!(
+            cli.patch.runtime_path(Path::new("/staging/opt/app")),
+            PathBuf::from("/opt/app")
+        );
+        assert_eq!(
+            cli.patch
+                .runtime_path(Path::new("/staging/opt/application/lib")),
+            PathBuf::from("/staging/opt/application/lib")
+        );
+        assert_eq!(
+            cli.patch.runtime_path(Path::new("/nix/store/foo/lib")),
+            PathBuf::from("/nix/store/foo/lib")
+        );
+        assert!(Cli::parse_from(["--prefix", "/opt/app"]).is_err());
+    }
+
+    #[test]
+    fn test_clear_symbol_version_is_forwarded() {
+        let cli = Cli::parse_from([
+            "--clear-symbol-version",
+            "foo",
+            "bar",
+            "--extra-args=--debug",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            cli.patch.patchelf_args(Path::new("/bin/foo")),
+            vec![
+                "--clear-symbol-version",
+                "foo",
+                "--clear-symbol-version",
+                "bar",
+                "--debug"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let cli = Cli::parse_from(["patch", "--paths", "/out"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Patch);
+        assert_eq!(cli.patch.paths, vec![PathBuf::from("/out")]);
+
+        let cli = Cli::parse_from(["check", "--libs", "/libs"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Check);
+        assert_eq!(cli.libraries.libraries, vec![PathBuf::from("/libs")]);
+
+        let cli = Cli::parse_from(["inspect", "--libs", "/libs", "--", "/out/bin/foo"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Inspect);
+        assert_eq!(cli.patch.explain, Some(PathBuf::from("/out/bin/foo")));
+
+        assert!(Cli::parse_from(["inspect"]).is_err());
+        assert!(Cli::parse_from(["inspect", "/a", "/b"]).is_err());
+        assert!(Cli::parse_from(["patch", "--validate-libs"]).is_err());
+        assert!(Cli::parse_from(["check", "--explain", "/out/bin/foo"]).is_err());
+        assert!(Cli::parse_from(["--fail-on-warning", "patch"]).is_err());
+    }
+
+    #[test]
+    fn test_legacy_commands() {
+        let cli = Cli::parse_from(["--paths", "/out"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Patch);
+        assert_eq!(cli.patch.paths, vec![PathBuf::from("/out")]);
+
+        let cli = Cli::parse_from(["--validate-libs", "--libs", "/libs"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Check);
+
+        let cli = Cli::parse_from(["--explain", "/out/bin/foo"]).unwrap();
+        assert_eq!(cli.command, Subcommand::Inspect);
+        assert_eq!(cli.patch.explain, Some(PathBuf::from("/out/bin/foo")));
+
+        assert_eq!(
+            Cli::parse_from(Vec::<OsString>::new()).unwrap().command,
+            Subcommand::Patch
+        );
+    }
+
+    #[test]
+    fn test_parse_arch() {
+        assert_eq!(parse_arch("x86_64"), Ok(goblin::elf::header::EM_X86_64));
+        assert_eq!(parse_arch("AARCH64"), Ok(goblin::elf::header::EM_AARCH64));
+        assert!(parse_arch("UNKNOWN_MACHINE").is_err());
+        assert!(parse_arch("bogus").is_err());
+    }
+
+    #[test]
+    fn test_parse_kernel_version() {
+        assert_eq!(parse_kernel_version("5.10.12"), Ok([5, 10, 12]));
+        assert_eq!(parse_kernel_version("4.19"), Ok([4, 19, 0]));
+        assert_eq!(parse_kernel_version("6"), Ok([6, 0, 0]));
+        assert!(parse_kernel_version("5.10.0.1").is_err());
+        assert!(parse_kernel_version("5.x").is_err());
+        assert!(parse_kernel_version("").is_err());
+    }
+
+    #[test]
+    fn test_dlopen_priority() {
+        let actions = |args: &[&str]| Cli::parse_from(args).map(|cli| cli.patch.dlopen_actions);
+        let [required, recommended, suggested] = DlopenPriority::ALL;
+
+        let defaults = actions(&[]).unwrap();
+        assert_eq!(defaults.get(required), MissingAction::Fail);
+        assert_eq!(defaults.get(recommended), MissingAction::Warn);
+        assert_eq!(defaults.get(suggested), MissingAction::Warn);
+
+        let custom = actions(&[
+            "--dlopen-priority",
+            "suggested=ignore, required=warn",
+            "--dlopen-priority",
+            "recommended=fail",
+        ])
+        .unwrap();
+        assert_eq!(custom.get(required), MissingAction::Warn);
+        assert_eq!(custom.get(recommended), MissingAction::Fail);
+        assert_eq!(custom.get(suggested), MissingAction::Ignore);
+
+        let optional =
+            actions(&["--dlopen-optional", "--dlopen-priority", "suggested=ignore"]).unwrap();
+        assert_eq!(optional.get(required), MissingAction::Warn);
+        assert_eq!(optional.get(recommended), MissingAction::Warn);
+        assert_eq!(optional.get(suggested), MissingAction::Ignore);
+
+        assert!(actions(&["--dlopen-priority", "optional=ignore"]).is_err());
+        assert!(actions(&["--dlopen-priority", "suggested=skip"]).is_err());
+        assert!(actions(&["--dlopen-priority", "suggested"]).is_err());
+    }
+
+    #[test]
+    fn test_outputs_from_env() {
+        let var = |name: &str| match name {
+            "out" => Some("/nix/store/abc-foo".into()),
+            "lib" => Some("/nix/store/def-foo-lib".into()),
+            _ => None,
+        };
+        assert_eq!(
+            outputs_from_env(Some("out lib dev"), var),
+            ["/nix/store/abc-foo", "/nix/store/def-foo-lib"].map(PathBuf::from)
+        );
+        assert_eq!(
+            outputs_from_env(None, var),
+            [PathBuf::from("/nix/store/abc-foo")]
+        );
+
+        let cli =
+            Cli::parse_from(["--sibling-outputs", "/out", "/lib", "--paths", "/out"]).unwrap();
+        assert_eq!(
+            cli.libraries.sibling_outputs,
+            ["/out", "/lib"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("1700000000", 0), Ok(1_700_000_000));
+        assert_eq!(parse_since("-5min", 1_000), Ok(700));
+        assert_eq!(parse_since("-30", 1_000), Ok(970));
+        assert_eq!(parse_since("-2h", 10_000), Ok(2_800));
+        assert!(parse_since("-5weeks", 1_000).is_err());
+        assert!(parse_since("-min", 1_000).is_err());
+        assert!(parse_since("yesterday", 1_000).is_err());
+    }
+
+    #[test]
+    fn test_soname_alias() {
+        let cli = Cli::parse_from([
+            "--soname-alias",
+            "libncurses.so.5=libncursesw.so.6",
+            "--soname-alias",
+            "libtinfo.so.5=libtinfo.so.6",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            cli.patch.soname_alias("libncurses.so.5"),
+            "libncursesw.so.6"
+        );
+        assert_eq!(cli.patch.soname_alias("libtinfo.so.5"), "libtinfo.so.6");
+        assert_eq!(cli.patch.soname_alias("libfoo.so"), "libfoo.so");
+        assert!(!cli.patch.rewrite_needed);
+        assert!(Cli::parse_from(["--soname-alias", "libncurses.so.5"]).is_err());
+    }
+}
@@ -3,29 +3,154 @@
 // SPDX-License-Identifier: EUPL-1.2
 
 use std::{
-    cell::{OnceCell, RefCell},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
 use eyre::{eyre, Result};
 
+use crate::output::Event;
+
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+/// Result of a background computation, waited for on first use
+///
+/// The handle can be shared between threads, the first one to use it waits
+/// for the computation.
 pub(crate) struct SharedHandle<T> {
-    handle: RefCell<Option<thread::JoinHandle<Result<T>>>>,
-    result: OnceCell<Result<T>>,
+    handle: Mutex<Option<thread::JoinHandle<Result<T>>>>,
+    result: OnceLock<Result<T>>,
 }
 
 impl<T> SharedHandle<T> {
     pub(crate) fn new(handle: thread::JoinHandle<Result<T>>) -> Self {
         Self {
-            handle: RefCell::new(Some(handle)),
-            result: OnceCell::new(),
+            handle: Mutex::new(Some(handle)),
+            result: OnceLock::new(),
+        }
+    }
+
+    /// Creates a handle for an already computed value
+    pub(crate) fn ready(value: T) -> Self {
+        Self {
+            handle: Mutex::new(None),
+            result: OnceLock::from(Ok(value)),
+        }
+    }
+
+    /// Waits for the computation and takes ownership of its result
+    pub(crate) fn into_result(self) -> Result<T> {
+        match self.result.into_inner() {
+            Some(result) => result,
+            None => self
+                .handle
+                .into_inner()
+                .unwrap()
+                .unwrap()
+                .join()
+                .expect("Thread panicked"),
         }
     }
 
     pub(crate) fn get_result(&self) -> Result<&T> {
         self.result
-            .get_or_init(|| self.handle.take().unwrap().join().expect("Thread panicked"))
+            .get_or_init(|| {
+                let start = METRICS.start();
+                let handle = self.handle.lock().unwrap().take().unwrap();
+                let result = handle.join().expect("Thread panicked");
+                METRICS.record_blocked(start);
+                result
+            })
             .as_ref()
             .map_err(|err| eyre!(err.to_string()))
     }
+
+    /// Waits for the computation and gives mutable access to its result
+    pub(crate) fn get_mut(&mut self) -> Result<&mut T> {
+        self.get_result()?;
+        self.result
+            .get_mut()
+            .unwrap()
+            .as_mut()
+            .map_err(|err| eyre!(err.to_string()))
+    }
+}
+
+/// Runtime metrics for tuning, only collected when enabled
+pub(crate) struct Metrics {
+    enabled: AtomicBool,
+    blocked_ns: AtomicU64,
+    patchelf_runs: AtomicU64,
+    patchelf_total_ns: AtomicU64,
+    patchelf_max_ns: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            enabled: AtomicBool::new(false),
+            blocked_ns: AtomicU64::new(0),
+            patchelf_runs: AtomicU64::new(0),
+            patchelf_total_ns: AtomicU64::new(0),
+            patchelf_max_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Starts a measurement, returns `None` when metrics are disabled
+    pub(crate) fn start(&self) -> Option<Instant> {
+        self.enabled.load(Ordering::Relaxed).then(Instant::now)
+    }
+
+    /// Records time spent waiting for the library cache
+    pub(crate) fn record_blocked(&self, start: Option<Instant>) {
+        if let Some(start) = start {
+            self.blocked_ns
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Records the wall time of a patchelf process
+    pub(crate) fn record_patchelf(&self, start: Option<Instant>) {
+        if let Some(start) = start {
+            let elapsed = start.elapsed().as_nanos() as u64;
+            self.patchelf_runs.fetch_add(1, Ordering::Relaxed);
+            self.patchelf_total_ns.fetch_add(elapsed, Ordering::Relaxed);
+            self.patchelf_max_ns.fetch_max(elapsed, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn report(&self) -> Event {
+        let ms = |ns: &AtomicU64| Duration::from_nanos(ns.load(Ordering::Relaxed)).as_millis();
+        let runs = self.patchelf_runs.load(Ordering::Relaxed);
+        let mean = self
+            .patchelf_total_ns
+            .load(Ordering::Relaxed)
+            .checked_div(runs)
+            .map(|ns| Duration::from_nanos(ns).as_millis())
+            .unwrap_or_default();
+
+        Event::info(
+            "metrics",
+            format!(
+                "concurrency metrics: blocked on library cache for {}ms, {} patchelf runs taking {}ms in total (mean {}ms, max {}ms)",
+                ms(&self.blocked_ns),
+                runs,
+                ms(&self.patchelf_total_ns),
+                mean,
+                ms(&self.patchelf_max_ns),
+            ),
+        )
+        .number("blocked_ms", ms(&self.blocked_ns) as usize)
+        .number("patchelf_runs", runs as usize)
+        .number("patchelf_total_ms", ms(&self.patchelf_total_ns) as usize)
+        .number("patchelf_max_ms", ms(&self.patchelf_max_ns) as usize)
+    }
 }
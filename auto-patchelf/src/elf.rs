@@ -2,14 +2,21 @@
 //
 // SPDX-License-Identifier: EUPL-1.2
 
-use std::path::PathBuf;
+use std::{
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use eyre::Result;
-use goblin::elf::{dynamic, header, program_header, Elf};
+use goblin::elf::{dynamic, header, note, program_header, Elf};
 use miniserde::{json, Deserialize};
 
 pub(crate) use goblin::elf::header::machine_to_str;
 
+/// Address of the packed relative relocations, not defined by goblin yet
+const DT_RELR: u64 = 36;
+
 pub(crate) struct ElfFile<'a> {
     content: &'a [u8],
     elf: Elf<'a>,
@@ -17,6 +24,8 @@ pub(crate) struct ElfFile<'a> {
 
 pub(crate) type Arch = u16;
 pub(crate) type OsAbi = u8;
+/// A Linux kernel version as `[major, minor, patch]`
+pub(crate) type KernelVersion = [u32; 3];
 
 impl<'a> ElfFile<'a> {
     pub(crate) fn new(content: &'a [u8]) -> Result<Self> {
@@ -31,13 +40,32 @@ impl<'a> ElfFile<'a> {
         self.elf.header.e_ident[header::EI_OSABI]
     }
 
+    pub(crate) fn is_64(&self) -> bool {
+        self.elf.is_64
+    }
+
     pub(crate) fn has_program_headers(&self) -> bool {
         !self.elf.program_headers.is_empty()
     }
 
+    /// Gets the entry point address from the ELF header
+    pub(crate) fn entry(&self) -> u64 {
+        self.elf.header.e_entry
+    }
+
+    /// Checks if an ELF file is a position dependent executable, i.e. `ET_EXEC`
+    pub(crate) fn is_executable(&self) -> bool {
+        self.elf.header.e_type == header::ET_EXEC
+    }
+
+    /// Checks if an ELF file is a relocatable object, like `.o` or `.dwo` files
+    pub(crate) fn is_relocatable(&self) -> bool {
+        self.elf.header.e_type == header::ET_REL
+    }
+
     /// Checks if an ELF file is a statically linked executable
     pub(crate) fn is_static_executable(&self) -> bool {
-        self.elf.header.e_type == header::ET_EXEC
+        self.is_executable()
             && !self
                 .elf
                 .program_headers
@@ -45,6 +73,45 @@ impl<'a> ElfFile<'a> {
                 .any(|ph| ph.p_type == program_header::PT_INTERP)
     }
 
+    /// Checks if the file uses compact DT_RELR relative relocations
+    pub(crate) fn has_relr(&self) -> bool {
+        self.elf
+            .dynamic
+            .as_ref()
+            .is_some_and(|dynamics| dynamics.dyns.iter().any(|dynamic| dynamic.d_tag == DT_RELR))
+    }
+
+    /// Checks if an ELF file is a static PIE, which relocates itself at startup
+    ///
+    /// These are `ET_DYN` files without PT_INTERP, like shared libraries, but
+    /// they have no DT_NEEDED or DT_SONAME. They are recognized by DF_1_PIE, or
+    /// by DT_RELR relocations with an entry point for linkers that do not set
+    /// that flag: glibc only loads shared libraries with DT_RELR if they depend
+    /// on its `GLIBC_ABI_DT_RELR` version, which requires a DT_NEEDED entry.
+    pub(crate) fn is_static_pie(&self) -> bool {
+        let Some(dynamics) = &self.elf.dynamic else {
+            return false;
+        };
+        self.elf.header.e_type == header::ET_DYN
+            && !self.is_dynamic_executable()
+            && dynamics.info.needed_count == 0
+            && self.get_soname().is_none()
+            && (dynamics.info.flags_1 & dynamic::DF_1_PIE != 0
+                || self.has_relr() && self.entry() != 0)
+    }
+
+    /// Checks if the file has a PT_DYNAMIC segment without any entries
+    ///
+    /// This happens for corrupt or truncated files, or unusual linker output.
+    pub(crate) fn has_empty_dynamic(&self) -> bool {
+        self.elf.dynamic.as_ref().is_some_and(|dynamics| {
+            dynamics
+                .dyns
+                .iter()
+                .all(|dynamic| dynamic.d_tag == dynamic::DT_NULL)
+        })
+    }
+
     /// Checks if an ELF file is a dynamically linked executable
     pub(crate) fn is_dynamic_executable(&self) -> bool {
         self.elf
@@ -53,14 +120,60 @@ impl<'a> ElfFile<'a> {
             .any(|ph| ph.p_type == program_header::PT_INTERP)
     }
 
+    /// Gets the DT_SONAME of the file, if any
+    pub(crate) fn get_soname(&self) -> Option<&str> {
+        self.elf.soname
+    }
+
+    /// Gets the dynamic linker requested by PT_INTERP
+    ///
+    /// The segment may be longer than the path, the padding is not part of it.
+    /// Empty or corrupt segments have no interpreter, see `check_interpreter`.
+    pub(crate) fn get_interpreter(&self) -> Option<&'a Path> {
+        let segment = self.interpreter_segment()?.ok()?;
+        match segment.iter().position(|&b| b == 0) {
+            Some(0) | None => None,
+            Some(end) => Some(Path::new(OsStr::from_bytes(&segment[..end]))),
+        }
+    }
+
+    /// Describes what is wrong with the PT_INTERP segment, if anything
+    pub(crate) fn check_interpreter(&self) -> Option<&'static str> {
+        let segment = match self.interpreter_segment()? {
+            Ok(segment) => segment,
+            Err(problem) => return Some(problem),
+        };
+        match segment.iter().position(|&b| b == 0) {
+            Some(0) => Some("is empty"),
+            None if segment.is_empty() => Some("is empty"),
+            None => Some("is not NUL-terminated"),
+            Some(_) => None,
+        }
+    }
+
+    fn interpreter_segment(&self) -> Option<Result<&'a [u8], &'static str>> {
+        let ph = self
+            .elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == program_header::PT_INTERP)?;
+        let segment = usize::try_from(ph.p_offset)
+            .ok()
+            .zip(usize::try_from(ph.p_filesz).ok())
+            .and_then(|(offset, size)| self.content.get(offset..offset.checked_add(size)?));
+        Some(segment.ok_or("is outside the file"))
+    }
+
     /// Gets the RPATH from the dynamic section
-    pub(crate) fn get_rpath(&self) -> Vec<String> {
+    ///
+    /// Entries borrow from the file content to avoid allocating per entry.
+    pub(crate) fn get_rpath(&self) -> Vec<&'a str> {
         if let Some(dynamics) = &self.elf.dynamic {
             // First try RUNPATH
             for dynamic in &dynamics.dyns {
                 if dynamic.d_tag == dynamic::DT_RUNPATH {
                     if let Some(runpath) = self.elf.dynstrtab.get_at(dynamic.d_val as usize) {
-                        return runpath.split(':').map(String::from).collect();
+                        return runpath.split(':').collect();
                     }
                 }
             }
@@ -69,7 +182,7 @@ impl<'a> ElfFile<'a> {
             for dynamic in &dynamics.dyns {
                 if dynamic.d_tag == dynamic::DT_RPATH {
                     if let Some(rpath) = self.elf.dynstrtab.get_at(dynamic.d_val as usize) {
-                        return rpath.split(':').map(String::from).collect();
+                        return rpath.split(':').collect();
                     }
                 }
             }
@@ -78,51 +191,412 @@ impl<'a> ElfFile<'a> {
         Vec::with_capacity(0)
     }
 
-    /// Gets the dynamic dependencies of an ELF file
-    pub(crate) fn get_dependencies(&self) -> Vec<Vec<PathBuf>> {
-        let mut dependencies = Vec::new();
+    /// Gets the DT_NEEDED entries of an ELF file
+    pub(crate) fn get_needed(&self) -> Vec<&'a Path> {
+        let mut needed = Vec::new();
 
         if let Some(dynamics) = &self.elf.dynamic {
             for dynamic in &dynamics.dyns {
                 if dynamic.d_tag == dynamic::DT_NEEDED {
                     if let Some(name) = self.elf.dynstrtab.get_at(dynamic.d_val as usize) {
-                        dependencies.push(vec![PathBuf::from(name)]);
+                        needed.push(Path::new(name));
                     }
                 }
             }
         }
 
+        needed
+    }
+
+    /// Finds structural problems that parsing alone tolerates
+    ///
+    /// goblin silently drops dynamic entries pointing outside the string
+    /// table, which would make dependencies disappear from resolution.
+    pub(crate) fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let header = &self.elf.header;
+
+        if usize::from(header.e_phnum) != self.elf.program_headers.len() {
+            problems.push(format!(
+                "header declares {} program headers, but {} were found",
+                header.e_phnum,
+                self.elf.program_headers.len()
+            ));
+        }
+
+        for (i, ph) in self.elf.program_headers.iter().enumerate() {
+            if ph.p_offset.saturating_add(ph.p_filesz) > self.content.len() as u64 {
+                problems.push(format!(
+                    "segment {i} ({}) extends past the end of the file",
+                    program_header::pt_to_str(ph.p_type)
+                ));
+            }
+        }
+
+        if let Some(dynamics) = &self.elf.dynamic {
+            for dynamic in &dynamics.dyns {
+                let is_string = matches!(
+                    dynamic.d_tag,
+                    dynamic::DT_NEEDED
+                        | dynamic::DT_SONAME
+                        | dynamic::DT_RPATH
+                        | dynamic::DT_RUNPATH
+                );
+                if is_string && self.elf.dynstrtab.get_at(dynamic.d_val as usize).is_none() {
+                    problems.push(format!(
+                        "{} offset {:#x} is outside the dynamic string table",
+                        dynamic::tag_to_str(dynamic.d_tag),
+                        dynamic.d_val
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Gets the dynamic dependencies of an ELF file
+    ///
+    /// dlopen metadata is read from the note sections named in `dlopen_notes`.
+    pub(crate) fn get_dependencies(&self, dlopen_notes: &[String]) -> Vec<Vec<PathBuf>> {
+        let mut dependencies: Vec<Vec<PathBuf>> = self
+            .get_needed()
+            .into_iter()
+            .map(|name| vec![name.to_path_buf()])
+            .collect();
+
+        for text in self.dlopen_note_texts(dlopen_notes) {
+            dependencies.extend(parse_dlopen_note(text));
+        }
+
+        dependencies
+    }
+
+    /// Gets the priorities of the dlopen dependencies
+    ///
+    /// They are in the same order as the dlopen dependencies returned by
+    /// `get_dependencies`, which come after the DT_NEEDED ones.
+    pub(crate) fn get_dlopen_priorities(&self, dlopen_notes: &[String]) -> Vec<DlopenPriority> {
+        self.dlopen_note_texts(dlopen_notes)
+            .into_iter()
+            .flat_map(parse_dlopen_entries)
+            .map(|dlopen| DlopenPriority::from_note(dlopen.priority.as_deref()))
+            .collect()
+    }
+
+    /// Gets the text of the dlopen notes in the sections named `dlopen_notes`
+    fn dlopen_note_texts(&self, dlopen_notes: &[String]) -> Vec<&'a str> {
         // Find .note.dlopen section
         // See https://systemd.io/ELF_DLOPEN_METADATA/
-        for note in self
-            .elf
-            .iter_note_sections(&self.content, Some(".note.dlopen"))
-            .into_iter()
+        dlopen_notes
+            .iter()
+            .filter_map(|name| self.elf.iter_note_sections(self.content, Some(name)))
             .flatten()
-        {
-            let note = note.unwrap();
-
-            let Ok(text) = std::str::from_utf8(note.desc) else {
-                continue;
-            };
-            let text = text.trim_end_matches('\0');
-            let Ok(dlopens) = json::from_str::<Vec<DlOpen>>(&text) else {
-                continue;
-            };
-            for dlopen in dlopens {
-                if !dlopen.soname.is_empty() {
-                    dependencies.push(dlopen.soname.into_iter().map(PathBuf::from).collect());
+            .filter_map(|note| std::str::from_utf8(note.unwrap().desc).ok())
+            .collect()
+    }
+
+    /// Gets the control-flow integrity features declared in `.note.gnu.property`
+    ///
+    /// The linker only declares a feature if all objects of the file support
+    /// it, so a missing note means none of them are enabled.
+    pub(crate) fn get_cfi_features(&self) -> Vec<&'static str> {
+        let (feature_type, known): (u32, &[(u32, &str)]) = match self.get_arch() {
+            header::EM_X86_64 | header::EM_386 => {
+                (GNU_PROPERTY_X86_FEATURE_1_AND, &[(1, "IBT"), (2, "SHSTK")])
+            }
+            header::EM_AARCH64 => (
+                GNU_PROPERTY_AARCH64_FEATURE_1_AND,
+                &[(1, "BTI"), (2, "PAC")],
+            ),
+            _ => return Vec::new(),
+        };
+        let align = if self.is_64() { 8 } else { 4 };
+
+        let mut features = Vec::new();
+        for desc in self.gnu_notes(GNU_PROPERTY_NOTE, note::NT_GNU_PROPERTY_TYPE_0) {
+            // An array of (type, size, data) properties, each padded to the word size
+            let mut properties = desc;
+            while properties.len() >= 8 {
+                let pr_type = self.read_u32(&properties[..4]);
+                let size = self.read_u32(&properties[4..8]) as usize;
+                let Some(data) = properties.get(8..8 + size) else {
+                    break;
+                };
+                if pr_type == feature_type && size >= 4 {
+                    let bits = self.read_u32(&data[..4]);
+                    features.extend(
+                        known
+                            .iter()
+                            .filter(|(bit, _)| bits & bit != 0)
+                            .map(|(_, name)| *name),
+                    );
                 }
+                properties = properties
+                    .get((8 + size).next_multiple_of(align)..)
+                    .unwrap_or_default();
             }
         }
+        features
+    }
 
-        dependencies
+    /// Gets the minimum Linux kernel version required, from `.note.ABI-tag`
+    pub(crate) fn get_min_kernel(&self) -> Option<KernelVersion> {
+        let desc = self
+            .gnu_notes(ABI_TAG_NOTE, note::NT_GNU_ABI_TAG)
+            .find(|desc| desc.len() >= 16)?;
+        let word = |i: usize| self.read_u32(&desc[i * 4..(i + 1) * 4]);
+        (word(0) == ELF_NOTE_OS_LINUX).then(|| [word(1), word(2), word(3)])
+    }
+
+    /// Iterates over the payloads of the GNU notes of a type in a note section
+    fn gnu_notes<'b>(
+        &'b self,
+        section: &'b str,
+        n_type: u32,
+    ) -> impl Iterator<Item = &'a [u8]> + 'b {
+        self.elf
+            .iter_note_sections(self.content, Some(section))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(move |note| note.name == "GNU" && note.n_type == n_type)
+            .map(|note| note.desc)
+    }
+
+    /// Reads a word of note payloads in the byte order of the file
+    fn read_u32(&self, bytes: &[u8]) -> u32 {
+        let bytes = bytes.try_into().unwrap();
+        if self.elf.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        }
+    }
+
+    fn has_section(&self, name: &str) -> bool {
+        self.elf
+            .section_headers
+            .iter()
+            .any(|sh| self.elf.shdr_strtab.get_at(sh.sh_name) == Some(name))
+    }
+
+    /// Checks if the file imports `name` from its dependencies
+    pub(crate) fn references_symbol(&self, name: &str) -> bool {
+        self.elf
+            .dynsyms
+            .iter()
+            .any(|sym| sym.is_import() && self.elf.dynstrtab.get_at(sym.st_name) == Some(name))
+    }
+
+    fn symbol_names(&self) -> impl Iterator<Item = &str> {
+        let syms = self
+            .elf
+            .syms
+            .iter()
+            .filter_map(|sym| self.elf.strtab.get_at(sym.st_name));
+        let dynsyms = self
+            .elf
+            .dynsyms
+            .iter()
+            .filter_map(|sym| self.elf.dynstrtab.get_at(sym.st_name));
+        syms.chain(dynsyms)
+    }
+
+    /// Guesses the source language of the binary on a best-effort basis
+    pub(crate) fn detect_language(&self) -> Option<&'static str> {
+        if self.has_section(".note.go.buildid") || self.has_section(".go.buildinfo") {
+            return Some("go");
+        }
+
+        if self.has_section(".rustc")
+            || self
+                .symbol_names()
+                .any(|name| is_rust_symbol(name) || name.contains("rust_begin_unwind"))
+        {
+            return Some("rust");
+        }
+
+        if self.symbol_names().any(|name| name.starts_with("_Z")) {
+            return Some("c++");
+        }
+
+        None
+    }
+}
+
+/// Checks whether a symbol is mangled by rustc
+///
+/// The v0 scheme starts with `_R` and an uppercase tag or the version
+/// digits. The legacy scheme looks like C++ but ends with a `17h` hash of
+/// 16 hex digits.
+fn is_rust_symbol(name: &str) -> bool {
+    if let Some(rest) = name.strip_prefix("_R") {
+        return rest.starts_with(|c: char| c.is_ascii_uppercase() || c.is_ascii_digit());
     }
+    let Some(path) = name
+        .strip_prefix("_ZN")
+        .and_then(|name| name.strip_suffix('E'))
+    else {
+        return false;
+    };
+    let path = path.as_bytes();
+    path.len() >= 19
+        && path[..path.len() - 16].ends_with(b"17h")
+        && path[path.len() - 16..].iter().all(u8::is_ascii_hexdigit)
 }
 
 #[derive(Deserialize)]
 struct DlOpen {
     soname: Vec<String>,
+    priority: Option<String>,
+}
+
+/// How much a file wants a dlopen dependency
+///
+/// See https://systemd.io/ELF_DLOPEN_METADATA/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DlopenPriority {
+    Required,
+    Recommended,
+    Suggested,
+}
+
+impl DlopenPriority {
+    pub(crate) const ALL: [Self; 3] = [Self::Required, Self::Recommended, Self::Suggested];
+
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Required => "required",
+            Self::Recommended => "recommended",
+            Self::Suggested => "suggested",
+        }
+    }
+
+    /// Gets the priority named in a note, which is `recommended` if missing or unknown
+    fn from_note(priority: Option<&str>) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|known| Some(known.as_str()) == priority)
+            .unwrap_or(Self::Recommended)
+    }
+}
+
+/// Name of the note section declaring properties like CFI features
+const GNU_PROPERTY_NOTE: &str = ".note.gnu.property";
+const GNU_PROPERTY_X86_FEATURE_1_AND: u32 = 0xc000_0002;
+const GNU_PROPERTY_AARCH64_FEATURE_1_AND: u32 = 0xc000_0000;
+
+/// Name of the note section declaring the minimum required kernel version
+const ABI_TAG_NOTE: &str = ".note.ABI-tag";
+const ELF_NOTE_OS_LINUX: u32 = 0;
+
+/// Name of the note section carrying dlopen metadata
+pub(crate) const DLOPEN_NOTE: &str = ".note.dlopen";
+
+/// Parses the payload of a `.note.dlopen` note into dependency groups
+///
+/// The payload is trimmed of NUL padding, whitespace and a leading UTF-8 BOM.
+/// A single payload may contain several concatenated JSON documents, each of
+/// which is parsed independently so that a malformed one does not drop the
+/// others.
+fn parse_dlopen_note(text: &str) -> Vec<Vec<PathBuf>> {
+    parse_dlopen_entries(text)
+        .into_iter()
+        .map(|dlopen| dlopen.soname.into_iter().map(PathBuf::from).collect())
+        .collect()
+}
+
+/// Parses the entries of a dlopen note that name at least one soname
+fn parse_dlopen_entries(text: &str) -> Vec<DlOpen> {
+    let text = text
+        .trim_start_matches('\u{feff}')
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace());
+
+    split_json_documents(text)
+        .into_iter()
+        .filter_map(|doc| json::from_str::<Vec<DlOpen>>(doc).ok())
+        .flatten()
+        .filter(|dlopen| !dlopen.soname.is_empty())
+        .collect()
+}
+
+/// Splits concatenated top-level JSON arrays or objects
+///
+/// Anything outside of a balanced top-level value (e.g. trailing garbage) is
+/// discarded.
+fn split_json_documents(text: &str) -> Vec<&str> {
+    let mut documents = Vec::new();
+    let mut depth = 0usize;
+    let mut start = None;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '[' | '{' => {
+                if depth == 0 {
+                    start = Some(i);
+                }
+                depth += 1;
+            }
+            ']' | '}' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    if let Some(start) = start.take() {
+                        documents.push(&text[start..=i]);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    documents
+}
+
+/// Expands the dynamic string tokens of an RPATH entry
+///
+/// `$LIB` and `$PLATFORM` (and their braced forms) are expanded the way the
+/// dynamic linker does. Returns `None` for entries that cannot be expanded
+/// confidently, e.g. ones relative to `$ORIGIN` or an unknown platform.
+pub(crate) fn expand_rpath_tokens(entry: &str, is_64: bool, arch: Arch) -> Option<String> {
+    if entry.contains("$ORIGIN") || entry.contains("${ORIGIN}") {
+        return None;
+    }
+
+    let lib = if is_64 { "lib64" } else { "lib" };
+    let mut expanded = entry.replace("${LIB}", lib).replace("$LIB", lib);
+
+    if expanded.contains("$PLATFORM") || expanded.contains("${PLATFORM}") {
+        let platform = match arch {
+            header::EM_X86_64 => "x86_64",
+            header::EM_386 => "i686",
+            header::EM_AARCH64 => "aarch64",
+            header::EM_PPC64 => "ppc64",
+            _ => return None,
+        };
+        expanded = expanded
+            .replace("${PLATFORM}", platform)
+            .replace("$PLATFORM", platform);
+    }
+
+    if expanded.contains('$') {
+        return None;
+    }
+
+    Some(expanded)
 }
 
 /// Gets OS ABI information from the ELF header
@@ -143,6 +617,18 @@ pub(crate) fn osabi_to_string(abi: OsAbi) -> String {
     }
 }
 
+/// Gets the architectures whose libraries can be loaded by binaries of `arch`
+///
+/// Only lists conservative, well-known cases; exact matches are always
+/// preferred by the callers.
+pub(crate) fn compatible_archs(arch: Arch) -> &'static [Arch] {
+    match arch {
+        header::EM_SPARC32PLUS => &[header::EM_SPARC],
+        header::EM_IAMCU => &[header::EM_386],
+        _ => &[],
+    }
+}
+
 /// Checks if two OS ABIs are compatible
 pub(crate) fn osabi_are_compatible(wanted: OsAbi, got: OsAbi) -> bool {
     if wanted == header::ELFOSABI_SYSV || got == header::ELFOSABI_SYSV {
@@ -161,6 +647,33 @@ mod tests {
             concat!(env!("CARGO_MANIFEST_DIR"), "/tests/assets/", $fname)
         };
     }
+
+    #[test]
+    fn test_rust_symbols() {
+        // v0 mangling
+        assert!(is_rust_symbol("_RNvCs1234_7mycrate3foo"));
+        assert!(is_rust_symbol("_R0NvC7mycrate3foo"));
+        assert!(!is_rust_symbol("_Rlowercase"));
+        assert!(!is_rust_symbol("_R"));
+
+        // Legacy mangling
+        assert!(is_rust_symbol(
+            "_ZN4core9panicking5panic17h0123456789abcdefE"
+        ));
+        assert!(!is_rust_symbol(
+            "_ZN4core9panicking5panic17h0123456789abcdeE"
+        ));
+        assert!(!is_rust_symbol(
+            "_ZN4core9panicking5panic17h0123456789abcdegE"
+        ));
+        assert!(!is_rust_symbol("_ZN3foo3barEv"));
+        assert!(!is_rust_symbol("_ZNK3foo3barE"));
+
+        // Exported legacy symbols look like C++ otherwise
+        let content = include_bytes!(asset!("librustlegacy.so"));
+        let elf = ElfFile::new(content).unwrap();
+        assert_eq!(elf.detect_language(), Some("rust"));
+    }
     #[test]
     fn test_parsing() {
         let content = include_bytes!(asset!("pam_systemd_home.so"));
@@ -170,8 +683,11 @@ mod tests {
         assert_eq!(elf.get_arch(), header::EM_X86_64);
         assert_eq!(elf.get_osabi(), header::ELFOSABI_NONE);
         assert!(elf.has_program_headers());
+        assert!(!elf.is_relocatable());
         assert!(!elf.is_static_executable());
         assert!(!elf.is_dynamic_executable());
+        assert_eq!(elf.detect_language(), None);
+        assert_eq!(elf.get_soname(), Some("pam_systemd_home.so"));
 
         assert_eq!(
             elf.get_rpath(),
@@ -252,7 +768,7 @@ mod tests {
         // ]
 
         assert_eq!(
-            elf.get_dependencies(),
+            elf.get_dependencies(&[DLOPEN_NOTE.to_string()]),
             vec![
                 vec![PathBuf::from("libcrypt.so.2")],
                 vec![PathBuf::from("libpam.so.0")],
@@ -272,4 +788,258 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_expand_rpath_tokens() {
+        assert_eq!(
+            expand_rpath_tokens("/opt/$LIB/foo", true, header::EM_X86_64).as_deref(),
+            Some("/opt/lib64/foo")
+        );
+        assert_eq!(
+            expand_rpath_tokens("/opt/${LIB}/foo", false, header::EM_386).as_deref(),
+            Some("/opt/lib/foo")
+        );
+        assert_eq!(
+            expand_rpath_tokens("/opt/$PLATFORM/lib", true, header::EM_X86_64).as_deref(),
+            Some("/opt/x86_64/lib")
+        );
+        assert_eq!(
+            expand_rpath_tokens("/opt/$PLATFORM/lib", false, header::EM_MIPS),
+            None
+        );
+        assert_eq!(
+            expand_rpath_tokens("$ORIGIN/../lib", true, header::EM_X86_64),
+            None
+        );
+        assert_eq!(
+            expand_rpath_tokens("/nix/store/foo/lib", true, header::EM_X86_64).as_deref(),
+            Some("/nix/store/foo/lib")
+        );
+    }
+
+    #[test]
+    fn test_borrowed_entries_match_owned_results() {
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        let elf = ElfFile::new(content).unwrap();
+
+        let needed = elf.get_needed();
+        let dependencies = elf.get_dependencies(&[]);
+        assert_eq!(needed.len(), dependencies.len());
+        for (needed, dependency) in needed.iter().zip(&dependencies) {
+            assert_eq!(dependency, &[needed.to_path_buf()]);
+        }
+
+        let raw_rpath = elf
+            .elf
+            .dynamic
+            .iter()
+            .flat_map(|dynamics| &dynamics.dyns)
+            .find(|d| d.d_tag == dynamic::DT_RUNPATH || d.d_tag == dynamic::DT_RPATH)
+            .and_then(|d| elf.elf.dynstrtab.get_at(d.d_val as usize))
+            .unwrap();
+        assert_eq!(elf.get_rpath().join(":"), raw_rpath);
+    }
+
+    #[test]
+    fn test_references_symbol() {
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.references_symbol("readlinkat"));
+        assert!(!elf.references_symbol("pam_sm_authenticate"));
+
+        let content = include_bytes!(asset!("hybrid"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(!elf.references_symbol("readlinkat"));
+    }
+
+    #[test]
+    fn test_validate() {
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        assert!(ElfFile::new(content).unwrap().validate().is_empty());
+
+        let content = include_bytes!(asset!("libcorrupt.so"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.get_needed().is_empty());
+        assert_eq!(
+            elf.validate(),
+            ["DT_NEEDED offset 0x7fff0000 is outside the dynamic string table"]
+        );
+    }
+
+    #[test]
+    fn test_cfi_features() {
+        let content = include_bytes!(asset!("libcfi.so"));
+        assert_eq!(
+            ElfFile::new(content).unwrap().get_cfi_features(),
+            ["IBT", "SHSTK"]
+        );
+
+        let content = include_bytes!(asset!("libnocfi.so"));
+        assert!(ElfFile::new(content).unwrap().get_cfi_features().is_empty());
+
+        // Has a property note, but with other properties only
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        assert!(ElfFile::new(content).unwrap().get_cfi_features().is_empty());
+    }
+
+    #[test]
+    fn test_min_kernel() {
+        let content = include_bytes!(asset!("stub"));
+        assert_eq!(
+            ElfFile::new(content).unwrap().get_min_kernel(),
+            Some([3, 2, 0])
+        );
+
+        let content = include_bytes!(asset!("stub-linux6"));
+        assert_eq!(
+            ElfFile::new(content).unwrap().get_min_kernel(),
+            Some([6, 1, 0])
+        );
+
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        assert_eq!(ElfFile::new(content).unwrap().get_min_kernel(), None);
+    }
+
+    #[test]
+    fn test_interpreter() {
+        let content = include_bytes!(asset!("stub"));
+        let elf = ElfFile::new(content).unwrap();
+        assert_eq!(
+            elf.get_interpreter(),
+            Some(Path::new("/lib64/ld-linux-x86-64.so.2"))
+        );
+        assert_eq!(elf.check_interpreter(), None);
+
+        let content = include_bytes!(asset!("stub-empty-interp"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.is_dynamic_executable());
+        assert_eq!(elf.get_interpreter(), None);
+        assert_eq!(elf.check_interpreter(), Some("is empty"));
+
+        // Shared libraries have no PT_INTERP, which is fine
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        assert_eq!(ElfFile::new(content).unwrap().check_interpreter(), None);
+    }
+
+    #[test]
+    fn test_entry() {
+        let content = include_bytes!(asset!("stub"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(!elf.is_executable());
+        assert_eq!(elf.entry(), 0x610);
+
+        let content = include_bytes!(asset!("stub-zero-entry"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.is_executable());
+        assert_eq!(elf.entry(), 0);
+    }
+
+    #[test]
+    fn test_empty_dynamic() {
+        let content = include_bytes!(asset!("stub"));
+        assert!(!ElfFile::new(content).unwrap().has_empty_dynamic());
+
+        let content = include_bytes!(asset!("stub-empty-dynamic"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.is_dynamic_executable());
+        assert!(elf.has_empty_dynamic());
+        assert!(elf.get_dependencies(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_static_pie() {
+        let content = include_bytes!(asset!("static-pie-relr"));
+        let elf = ElfFile::new(content).unwrap();
+        assert!(elf.has_relr());
+        assert!(elf.is_static_pie());
+        assert!(!elf.is_static_executable());
+        assert!(!elf.is_dynamic_executable());
+
+        for asset in [
+            &include_bytes!(asset!("stub"))[..],
+            include_bytes!(asset!("pam_systemd_home.so")),
+        ] {
+            let elf = ElfFile::new(asset).unwrap();
+            assert!(!elf.has_relr());
+            assert!(!elf.is_static_pie());
+        }
+    }
+
+    #[test]
+    fn test_custom_dlopen_note_name() {
+        let content = include_bytes!(asset!("libvendornote.so"));
+        let elf = ElfFile::new(content).unwrap();
+
+        assert!(elf.get_dependencies(&[DLOPEN_NOTE.to_string()]).is_empty());
+        assert_eq!(
+            elf.get_dependencies(&[DLOPEN_NOTE.to_string(), ".note.vendor.dlopen".to_string()]),
+            vec![vec![PathBuf::from("libvendor.so.1")]]
+        );
+    }
+
+    #[test]
+    fn test_dlopen_note_with_bom_and_whitespace() {
+        let text = "\u{feff}  [{\"soname\": [\"libfoo.so.1\", \"libfoo.so\"]}]\n\0\0";
+
+        assert_eq!(
+            parse_dlopen_note(text),
+            vec![vec![
+                PathBuf::from("libfoo.so.1"),
+                PathBuf::from("libfoo.so")
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_dlopen_priorities() {
+        let text = concat!(
+            r#"[{"soname": ["libfoo.so.1"], "priority": "required"},"#,
+            r#" {"soname": ["libbar.so.1"], "priority": "suggested"},"#,
+            r#" {"soname": [], "priority": "required"},"#,
+            r#" {"soname": ["libbaz.so.1"]},"#,
+            r#" {"soname": ["libqux.so.1"], "priority": "optional"}]"#,
+        );
+
+        let priorities: Vec<DlopenPriority> = parse_dlopen_entries(text)
+            .iter()
+            .map(|dlopen| DlopenPriority::from_note(dlopen.priority.as_deref()))
+            .collect();
+        assert_eq!(
+            priorities,
+            [
+                DlopenPriority::Required,
+                DlopenPriority::Suggested,
+                DlopenPriority::Recommended,
+                DlopenPriority::Recommended
+            ]
+        );
+        assert_eq!(parse_dlopen_note(text).len(), priorities.len());
+
+        let content = include_bytes!(asset!("pam_systemd_home.so"));
+        let elf = ElfFile::new(content).unwrap();
+        let dlopen_notes = [DLOPEN_NOTE.to_string()];
+        assert_eq!(
+            elf.get_dlopen_priorities(&dlopen_notes).len(),
+            elf.get_dependencies(&dlopen_notes).len() - elf.get_needed().len()
+        );
+    }
+
+    #[test]
+    fn test_dlopen_note_with_concatenated_documents() {
+        let text = concat!(
+            r#"[{"soname": ["libfoo.so.1"]}]"#,
+            "\n",
+            r#"[{"soname": ["lib]bar.so.2"], "description": "a \"quoted\" [name"}]"#,
+            r#"[{"soname": "#,
+            "\0garbage"
+        );
+
+        assert_eq!(
+            parse_dlopen_note(text),
+            vec![
+                vec![PathBuf::from("libfoo.so.1")],
+                vec![PathBuf::from("lib]bar.so.2")]
+            ]
+        );
+    }
 }
@@ -0,0 +1,294 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use eyre::{bail, Result};
+
+const MAX_BITS: usize = 15;
+
+/// Base lengths and extra bits of the length codes 257..285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and extra bits of the distance codes 0..29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Order in which the code lengths of the code length alphabet are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a raw deflate stream (RFC 1951), as found in zip archives
+///
+/// Decoding stops with an error once the output would exceed `limit`, the
+/// expected size comes from the archive and is not trusted otherwise.
+pub(crate) fn inflate(input: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut bits = Bits::new(input);
+    let mut output = Vec::new();
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let len = bits.take(16)?;
+                let nlen = bits.take(16)?;
+                if len != !nlen & 0xffff {
+                    bail!("stored block length does not match its complement");
+                }
+                let block = bits.bytes(len as usize)?;
+                if output.len() + block.len() > limit {
+                    bail!("deflate stream is larger than {limit} bytes");
+                }
+                output.extend_from_slice(block);
+            }
+            1 => {
+                let mut lengths = [8; 288];
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                codes(&mut bits, &mut output, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_tables(&mut bits)?;
+                codes(&mut bits, &mut output, &literals, &distances, limit)?;
+            }
+            _ => bail!("invalid deflate block type"),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+/// Reads the Huffman tables at the start of a dynamic block
+fn dynamic_tables(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let literal_count = bits.take(5)? as usize + 257;
+    let distance_count = bits.take(5)? as usize + 1;
+    let code_length_count = bits.take(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        bail!("too many codes in a dynamic deflate block");
+    }
+
+    let mut code_lengths = [0; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = bits.take(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths)?;
+
+    let mut lengths = vec![0; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_lengths.decode(bits)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let Some(&previous) = index.checked_sub(1).map(|i| &lengths[i]) else {
+                    bail!("repeated code length without a previous one");
+                };
+                (previous, 3 + bits.take(2)? as usize)
+            }
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if index + repeat > lengths.len() {
+            bail!("code lengths overflow the dynamic deflate block");
+        }
+        lengths[index..index + repeat].fill(value);
+        index += repeat;
+    }
+    if lengths[256] == 0 {
+        bail!("dynamic deflate block has no end of block code");
+    }
+
+    Ok((
+        Huffman::new(&lengths[..literal_count])?,
+        Huffman::new(&lengths[literal_count..])?,
+    ))
+}
+
+/// Decodes the literals and back references of a compressed block
+fn codes(
+    bits: &mut Bits,
+    output: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(bits)?;
+        let length = match symbol {
+            0..=255 => {
+                if output.len() >= limit {
+                    bail!("deflate stream is larger than {limit} bytes");
+                }
+                output.push(symbol as u8);
+                continue;
+            }
+            256 => return Ok(()),
+            257..=285 => {
+                let code = symbol - 257;
+                LENGTH_BASE[code] as usize + bits.take(LENGTH_EXTRA[code])? as usize
+            }
+            _ => bail!("invalid deflate length code"),
+        };
+
+        let code = distances.decode(bits)?;
+        if code >= DIST_BASE.len() {
+            bail!("invalid deflate distance code");
+        }
+        let distance = DIST_BASE[code] as usize + bits.take(DIST_EXTRA[code])? as usize;
+        if distance > output.len() {
+            bail!("deflate distance points before the start of the output");
+        }
+        if output.len() + length > limit {
+            bail!("deflate stream is larger than {limit} bytes");
+        }
+        // The copied bytes may overlap with the ones being written
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+/// Reads the input least significant bit first
+struct Bits<'a> {
+    input: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u8,
+}
+
+impl<'a> Bits<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            position: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn take(&mut self, count: u8) -> Result<u32> {
+        while self.count < count {
+            let Some(&byte) = self.input.get(self.position) else {
+                bail!("deflate stream ends unexpectedly");
+            };
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << count) - 1);
+        self.buffer >>= count;
+        self.count -= count;
+        Ok(value)
+    }
+
+    /// Drops the bits left of the current byte
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let Some(bytes) = self.input.get(self.position..self.position + len) else {
+            bail!("deflate stream ends unexpectedly");
+        };
+        self.position += len;
+        Ok(bytes)
+    }
+}
+
+/// Canonical Huffman code, decoded one bit at a time
+struct Huffman {
+    /// Number of codes of each length
+    counts: [u16; MAX_BITS + 1],
+    /// Symbols ordered by their code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+
+        // Each length can have at most twice as many codes as the previous
+        let mut left: i32 = 1;
+        for &count in &counts[1..] {
+            left = left * 2 - count as i32;
+            if left < 0 {
+                bail!("over-subscribed deflate Huffman code");
+            }
+        }
+
+        let mut offsets = [0; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<usize> {
+        let (mut code, mut first, mut index) = (0, 0, 0);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        bail!("invalid deflate Huffman code")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored() {
+        let mut input = vec![0b001, 5, 0, !5, !0];
+        input.extend_from_slice(b"hello");
+        assert_eq!(inflate(&input, 5).unwrap(), b"hello");
+        assert!(inflate(&input, 4).is_err());
+
+        input[3] = 0;
+        assert!(inflate(&input, 5).is_err());
+    }
+
+    #[test]
+    fn test_fixed() {
+        // zlib.compress(b"hello hello hello hello", wbits=-15)
+        let input = [0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01];
+        assert_eq!(inflate(&input, 100).unwrap(), b"hello hello hello hello");
+        assert!(inflate(&input, 10).is_err());
+        assert!(inflate(&input[..5], 100).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    collections::HashSet,
+    env::consts::ARCH,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+
+/// Configuration of the host's dynamic linker
+pub(crate) const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// Gets the existing library directories the host's dynamic linker searches
+///
+/// These are the directories of `ld_so_conf` followed by the default ones,
+/// including the Debian style multiarch directories. The canonical spelling
+/// of a directory is listed too if it is different, e.g. `/usr/lib` for
+/// `/lib` on merged-usr systems.
+pub(crate) fn host_lib_dirs(ld_so_conf: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if ld_so_conf.is_file() {
+        parse(ld_so_conf, &mut dirs, &mut HashSet::new())?;
+    }
+    let multiarch = format!("{ARCH}-linux-gnu");
+    for prefix in ["/lib", "/usr/lib"] {
+        dirs.push(Path::new(prefix).join(&multiarch));
+    }
+    dirs.extend(["/lib64", "/usr/lib64", "/lib", "/usr/lib"].map(PathBuf::from));
+
+    let mut host_dirs = Vec::new();
+    for dir in dirs.into_iter().filter(|dir| dir.is_dir()) {
+        let canonical = dir.canonicalize()?;
+        for dir in [dir, canonical] {
+            if !host_dirs.contains(&dir) {
+                host_dirs.push(dir);
+            }
+        }
+    }
+    Ok(host_dirs)
+}
+
+/// Collects the directories of an `ld.so.conf` file into `dirs`
+///
+/// `include` lines are followed recursively, relative patterns are resolved
+/// against the directory of the including file. Files already seen are
+/// skipped, so that include cycles terminate.
+fn parse(conf: &Path, dirs: &mut Vec<PathBuf>, seen: &mut HashSet<PathBuf>) -> Result<()> {
+    if !seen.insert(conf.canonicalize()?) {
+        return Ok(());
+    }
+
+    let text = fs::read_to_string(conf)?;
+    let conf_dir = conf.parent().unwrap_or(Path::new("/"));
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(patterns) = line.strip_prefix("include") {
+            for pattern in patterns.split_whitespace() {
+                let pattern = conf_dir.join(pattern);
+                let mut includes: Vec<PathBuf> = glob::glob(&pattern.to_string_lossy())?
+                    .flatten()
+                    .filter(|include| include.is_file())
+                    .collect();
+                includes.sort();
+                for include in includes {
+                    parse(&include, dirs, seen)?;
+                }
+            }
+        } else if !line.starts_with("hwcap") {
+            for dir in line.split(|c: char| c.is_whitespace() || c == ':' || c == ',') {
+                // Directories spelled like `dir=libc5` carry a library type,
+                // which is not used anymore
+                let dir = dir.split('=').next().unwrap_or_default();
+                if dir.starts_with('/') && !dirs.iter().any(|known| known == Path::new(dir)) {
+                    dirs.push(PathBuf::from(dir));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let dir = TempDir::new("ld");
+        fs::create_dir_all(dir.join("ld.so.conf.d")).unwrap();
+        let conf = dir.join("ld.so.conf");
+        fs::write(
+            &conf,
+            "# host libraries\ninclude ld.so.conf.d/*.conf\n/opt/lib # vendor\n/usr/local/lib\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("ld.so.conf.d/a.conf"),
+            "include ../ld.so.conf\n/a/lib:/a/lib64\nhwcap 0 nosegneg\n",
+        )
+        .unwrap();
+        fs::write(dir.join("ld.so.conf.d/b.conf"), "/b/lib=libc6\n/opt/lib\n").unwrap();
+
+        let mut dirs = Vec::new();
+        parse(&conf, &mut dirs, &mut HashSet::new()).unwrap();
+        assert_eq!(
+            dirs,
+            ["/a/lib", "/a/lib64", "/b/lib", "/opt/lib", "/usr/local/lib"].map(PathBuf::from)
+        );
+
+        fs::create_dir(dir.join("lib")).unwrap();
+        fs::write(&conf, format!("{}/lib\n/nonexistent/lib\n", dir.display())).unwrap();
+        let host_dirs = host_lib_dirs(&conf).unwrap();
+        assert_eq!(host_dirs[0], dir.join("lib"));
+        assert!(!host_dirs.contains(&PathBuf::from("/nonexistent/lib")));
+    }
+}
@@ -2,131 +2,670 @@
 //
 // SPDX-License-Identifier: EUPL-1.2
 
+mod archive;
 mod cache;
 mod cli;
 mod concurrency;
 mod elf;
+mod inflate;
+mod ld_so_conf;
+mod manifest;
 mod misc;
+mod output;
+mod patchelf_version;
+mod per_file_args;
+mod pkg_config;
 mod state;
+mod xattr;
 
 use eyre::{eyre, Context, Result};
 use glob::Pattern;
+use miniserde::json::{self, Array, Number, Object, Value};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashSet},
     env,
+    ffi::OsString,
     fs::{self, File},
     io::Read,
+    mem,
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
     process::Command,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use crate::{
     cache::LibraryCache,
-    cli::{Cli, PatchConfig},
-    concurrency::SharedHandle,
-    elf::{machine_to_str, osabi_are_compatible, osabi_to_string, ElfFile},
-    misc::{glob, read_file},
-    state::DirState,
+    cli::{Cli, MissingAction, PatchConfig, Subcommand},
+    concurrency::{SharedHandle, METRICS},
+    elf::{
+        expand_rpath_tokens, machine_to_str, osabi_are_compatible, osabi_to_string, Arch, ElfFile,
+        KernelVersion,
+    },
+    misc::{
+        glob, normalize_path, path_string, read_file, read_lines, stable_hash, write_lines,
+        write_nul_delimited,
+    },
+    output::Event,
+    patchelf_version::{PatchelfVersion, RUNPATH_DEFAULT},
+    state::{DirState, StateChange},
 };
 
 const DEFAULT_BINTOOLS: &str = "@defaultBintools@";
 
+/// How often `--watch` looks for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Reported when files miss dependencies that are not ignored
+const MISSING_DEPENDENCIES: &str = "auto-patchelf failed to find all the required dependencies.\n\
+    Add the missing dependencies to --libs or use \
+    `--ignore-missing=\"foo.so.1 bar.so etc.so\"`.";
+
+/// Extended attribute `--xattr` records the RPATH of patched files in
+const RPATH_XATTR: &str = "user.auto-patchelf.rpath";
+
+/// Whether `--xattr` already warned about a filesystem without extended attributes
+static XATTR_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Executables up to this size may be launcher stubs for `--fast-stubs`
+const STUB_MAX_SIZE: u64 = 8 * 1024;
+
+/// What patching a single file did, or would do in a dry run
+#[derive(Debug, Default)]
+struct FilePatch {
+    dependencies: Vec<Dependency>,
+    /// Whether the interpreter is set
+    interpreter: bool,
+    rpath: Vec<String>,
+    /// Skipped because it is not of the `--expect-arch` architecture
+    unexpected_arch: bool,
+    /// Arguments of the patchelf invocations, including the executable
+    commands: Vec<Vec<OsString>>,
+}
+
 #[derive(Debug, Clone)]
 struct Dependency {
     file: PathBuf,
     name: PathBuf,
-    found: bool,
+    /// Library file the dependency was resolved to
+    found: Option<PathBuf>,
+    /// How reliably `found` was resolved
+    confidence: Option<Confidence>,
+}
+
+impl Dependency {
+    /// Ranks the dependency by how reliably it was resolved, missing ones first
+    fn reliability(&self) -> u8 {
+        match self.confidence {
+            None => 0,
+            Some(Confidence::Low) => 1,
+            Some(Confidence::Medium) => 2,
+            Some(Confidence::High) => 3,
+            Some(Confidence::LibcDefault) => 4,
+        }
+    }
+}
+
+/// How reliably a dependency was resolved, reported to help spotting fragile resolutions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Confidence {
+    /// Found with its exact, versioned soname
+    High,
+    /// Found with its exact soname, which has no version
+    Medium,
+    /// Found through a `--soname-alias` or as a library of a compatible architecture
+    Low,
+    /// Provided by libc
+    LibcDefault,
+}
+
+impl Confidence {
+    /// Rates a library found in the library set
+    fn of_found(soname: &str, relaxed: bool) -> Self {
+        let versioned = soname
+            .split_once(".so.")
+            .is_some_and(|(_, version)| version.starts_with(|c: char| c.is_ascii_digit()));
+        match (relaxed, versioned) {
+            (true, _) => Self::Low,
+            (false, true) => Self::High,
+            (false, false) => Self::Medium,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Confidence::High => "high",
+            Confidence::Medium => "medium",
+            Confidence::Low => "low",
+            Confidence::LibcDefault => "libc-default",
+        }
+    }
 }
 
 /// Patches a single ELF file
+///
+/// All output about the file is written at once when it is done.
 fn auto_patchelf_file(
     args: &PatchConfig,
     path: &Path,
     library_computation: &SharedHandle<LibraryCache>,
     interpreter_path: &Path,
-    interpreter: &ElfFile,
-    libc_lib: &Path,
-) -> Result<Vec<Dependency>> {
+    interpreter: Option<&ElfFile>,
+    libc_libs: &[PathBuf],
+) -> Result<FilePatch> {
+    output::grouped(|| {
+        patch_file(
+            args,
+            path,
+            library_computation,
+            interpreter_path,
+            interpreter,
+            libc_libs,
+        )
+    })
+}
+
+/// Patches a content-addressed blob, reporting it under its logical path
+fn auto_patchelf_blob(
+    args: &PatchConfig,
+    blob_path: &Path,
+    logical_path: &Path,
+    library_computation: &SharedHandle<LibraryCache>,
+    interpreter_path: &Path,
+    interpreter: Option<&ElfFile>,
+    libc_libs: &[PathBuf],
+) -> Result<FilePatch> {
+    output::grouped(|| {
+        patch_file_named(
+            args,
+            blob_path,
+            logical_path,
+            library_computation,
+            interpreter_path,
+            interpreter,
+            libc_libs,
+        )
+    })
+}
+
+fn patch_file(
+    args: &PatchConfig,
+    path: &Path,
+    library_computation: &SharedHandle<LibraryCache>,
+    interpreter_path: &Path,
+    interpreter: Option<&ElfFile>,
+    libc_libs: &[PathBuf],
+) -> Result<FilePatch> {
+    patch_file_named(
+        args,
+        path,
+        path,
+        library_computation,
+        interpreter_path,
+        interpreter,
+        libc_libs,
+    )
+}
+
+/// Patches `path`, naming it `name` in the output and in the dependencies
+///
+/// Content-addressed blobs are reported under their logical path.
+fn patch_file_named(
+    args: &PatchConfig,
+    path: &Path,
+    name: &Path,
+    library_computation: &SharedHandle<LibraryCache>,
+    interpreter_path: &Path,
+    interpreter: Option<&ElfFile>,
+    libc_libs: &[PathBuf],
+) -> Result<FilePatch> {
     let mut dependencies = Vec::new();
+    let explain = args.explain.is_some();
+    let dry_run = explain || args.plan_hash.is_some();
+
+    let size = fs::metadata(path)?.len();
+    if let Some(max_size) = args.max_size.filter(|&max_size| size > max_size) {
+        Event::warning(
+            "skipped",
+            format!(
+                "skipping {} because it is larger than --max-size ({size} > {max_size} bytes)",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "too-large")
+        .emit();
+        return Ok(FilePatch::default());
+    }
+    if let Some(min_size) = args.min_size.filter(|&min_size| size < min_size) {
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it is smaller than --min-size ({size} < {min_size} bytes)",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "too-small")
+        .emit();
+        return Ok(FilePatch::default());
+    }
 
     let content = read_file(path).unwrap();
     let elf_file: ElfFile = match ElfFile::new(&content) {
         Ok(elf) => elf,
-        Err(_) => return Ok(dependencies),
+        Err(_) => return Ok(FilePatch::default()),
     };
 
+    if args.validate {
+        let problems = elf_file.validate();
+        if args.fail_invalid && !problems.is_empty() {
+            return Err(eyre!(
+                "{} is malformed: {}",
+                name.display(),
+                problems.join(", ")
+            ));
+        }
+        for problem in problems {
+            Event::warning(
+                "invalid",
+                format!("{} is malformed: {problem}", name.display()),
+            )
+            .field("file", name.display())
+            .emit();
+        }
+    }
+
     // Skip files that don't need patching
+    if elf_file.is_relocatable() {
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it is a relocatable object",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "relocatable")
+        .emit();
+        return Ok(FilePatch::default());
+    }
+
+    if elf_file.is_executable() && elf_file.entry() == 0 {
+        Event::warning(
+            "zero-entry",
+            format!(
+                "{} is an executable without an entry point, it may be corrupt or a placeholder",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+    }
+
     if elf_file.is_static_executable() {
-        println!(
-            "skipping {} because it is statically linked",
-            path.display()
-        );
-        return Ok(dependencies);
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it is statically linked",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "static")
+        .emit();
+        return Ok(FilePatch::default());
+    }
+
+    if elf_file.is_static_pie() {
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it is a static PIE{}",
+                name.display(),
+                if elf_file.has_relr() {
+                    " with RELR relocations"
+                } else {
+                    ""
+                }
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "static-pie")
+        .emit();
+        return Ok(FilePatch::default());
     }
 
     if !elf_file.has_program_headers() {
-        println!("skipping {} because it contains no segment", path.display());
-        return Ok(dependencies);
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it contains no segments",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "no-segment")
+        .emit();
+        return Ok(FilePatch::default());
     }
 
-    if interpreter.get_arch() != elf_file.get_arch() {
-        println!(
-            "skipping {} because its architecture ({}) differs from target ({})",
-            path.display(),
-            machine_to_str(elf_file.get_arch()),
-            machine_to_str(interpreter.get_arch())
-        );
-        return Ok(dependencies);
+    if let Some(expected) = args.expect_arch.filter(|&arch| arch != elf_file.get_arch()) {
+        Event::warning(
+            "unexpected-arch",
+            format!(
+                "{} is built for {} instead of {}",
+                name.display(),
+                machine_to_str(elf_file.get_arch()),
+                machine_to_str(expected)
+            ),
+        )
+        .field("file", name.display())
+        .field("arch", machine_to_str(elf_file.get_arch()))
+        .emit();
+        return Ok(FilePatch {
+            unexpected_arch: true,
+            ..FilePatch::default()
+        });
     }
 
-    if !osabi_are_compatible(interpreter.get_osabi(), elf_file.get_osabi()) {
-        println!(
-            "skipping {} because its OS ABI ({}) is not compatible with target ({})",
-            path.display(),
-            osabi_to_string(elf_file.get_osabi()),
-            osabi_to_string(interpreter.get_osabi())
-        );
-        return Ok(dependencies);
+    // Without a readable interpreter there is nothing to compare against
+    if let Some(interpreter) = interpreter {
+        if interpreter.get_arch() != elf_file.get_arch() {
+            Event::info(
+                "skipped",
+                format!(
+                    "skipping {} because its architecture ({}) differs from target ({})",
+                    name.display(),
+                    machine_to_str(elf_file.get_arch()),
+                    machine_to_str(interpreter.get_arch())
+                ),
+            )
+            .field("file", name.display())
+            .field("reason", "arch")
+            .emit();
+            return Ok(FilePatch::default());
+        }
+
+        if !osabi_are_compatible(interpreter.get_osabi(), elf_file.get_osabi()) {
+            Event::info(
+                "skipped",
+                format!(
+                    "skipping {} because its OS ABI ({}) is not compatible with target ({})",
+                    name.display(),
+                    osabi_to_string(elf_file.get_osabi()),
+                    osabi_to_string(interpreter.get_osabi())
+                ),
+            )
+            .field("file", name.display())
+            .field("reason", "osabi")
+            .emit();
+            return Ok(FilePatch::default());
+        }
+    }
+
+    if !args.require_symbols.is_empty()
+        && !args
+            .require_symbols
+            .iter()
+            .any(|symbol| elf_file.references_symbol(symbol))
+    {
+        Event::info(
+            "skipped",
+            format!(
+                "skipping {} because it imports none of the required symbols",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .field("reason", "symbol")
+        .emit();
+        return Ok(FilePatch::default());
+    }
+
+    if args.detect_prior_patching && is_prepatched(&elf_file.get_rpath(), &args.store_dir) {
+        if args.skip_prepatched {
+            Event::info(
+                "skipped",
+                format!(
+                    "skipping {} because it seems to be patched already",
+                    name.display()
+                ),
+            )
+            .field("file", name.display())
+            .field("reason", "prepatched")
+            .emit();
+            return Ok(FilePatch::default());
+        }
+
+        Event::info(
+            "prepatched",
+            format!(
+                "{} already has an RPATH into the Nix store, it may not need patching",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+    }
+
+    if let Some(problem) = elf_file.check_interpreter() {
+        Event::warning(
+            "corrupt-interpreter",
+            format!(
+                "the PT_INTERP segment of {} {problem}, the input may be broken",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+    }
+
+    if let Some((required, min_kernel)) = elf_file.get_min_kernel().zip(args.min_kernel) {
+        if required > min_kernel {
+            let required = kernel_version_string(required);
+            Event::warning(
+                "kernel",
+                format!(
+                    "{} requires Linux {required}, newer than {}",
+                    name.display(),
+                    kernel_version_string(min_kernel)
+                ),
+            )
+            .field("file", name.display())
+            .field("kernel", required)
+            .emit();
+        }
     }
 
     let file_is_dynamic_executable = elf_file.is_dynamic_executable();
-    let file_dependencies = elf_file.get_dependencies();
+    let file_dependencies = elf_file.get_dependencies(&args.dlopen_notes);
+    if file_is_dynamic_executable && elf_file.has_empty_dynamic() {
+        Event::info(
+            "empty-dynamic",
+            format!(
+                "{} is a dynamic executable with an empty dynamic section, it may be malformed",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+    }
+
+    // Executables may also provide a soname for others to link against. The
+    // dynamic linker looks them up by file name, so they can only be resolved
+    // (and are only indexed) if a file named after the soname exists.
+    if let Some(soname) = elf_file.get_soname().filter(|_| file_is_dynamic_executable) {
+        let provides = path.with_file_name(soname);
+        if !provides.is_file() {
+            Event::warning(
+                "hybrid",
+                format!(
+                    "{} is an executable providing {soname}, but it cannot be found as {}",
+                    name.display(),
+                    provides.display()
+                ),
+            )
+            .field("file", name.display())
+            .field("soname", soname)
+            .emit();
+        }
+    }
 
     let mut rpath = Vec::new();
+    let metadata = fs::metadata(path)?;
+
+    // The cache indexes the files being patched too, so wait for it before
+    // modifying anything to keep the index consistent with the original state
+    let library_cache = library_computation.get_result()?;
+
+    let keep_interpreter = file_is_dynamic_executable && args.keep_interpreter_if_valid && {
+        let current = elf_file.get_interpreter();
+        current.is_some_and(|current| is_valid_interpreter(current, elf_file.get_arch()))
+    };
+
+    let mut commands = Vec::new();
 
     // Set interpreter for executables
-    if file_is_dynamic_executable {
-        println!("setting interpreter of {}", path.display());
-
-        let output = Command::new("patchelf")
-            .arg("--set-interpreter")
-            .arg(interpreter_path)
-            .arg(path)
-            .args(&args.extra_args)
-            .output()?;
-
-        if !output.status.success() {
-            let err =
-                core::str::from_utf8(&output.stderr).unwrap_or("Could not format command output");
-            return Err(eyre!(
-                "Failed to set interpreter for {}, output: {}",
-                path.display(),
-                err
-            ));
+    if keep_interpreter {
+        Event::info(
+            "interpreter",
+            format!(
+                "keeping interpreter of {}, {} is valid",
+                name.display(),
+                elf_file
+                    .get_interpreter()
+                    .unwrap_or(Path::new(""))
+                    .display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+    } else if file_is_dynamic_executable {
+        let command = patchelf_command(
+            args,
+            path,
+            ["--set-interpreter".into(), interpreter_path.into()],
+        );
+        if explain {
+            Event::info(
+                "explain",
+                format!(
+                    "would set interpreter of {} to {}",
+                    name.display(),
+                    interpreter_path.display()
+                ),
+            )
+            .emit();
+        } else if !dry_run {
+            Event::info(
+                "interpreter",
+                format!("setting interpreter of {}", name.display()),
+            )
+            .field("file", name.display())
+            .field("interpreter", interpreter_path.display())
+            .emit();
+
+            let start = METRICS.start();
+            let output = to_command(&command).output()?;
+            METRICS.record_patchelf(start);
+
+            if !output.status.success() {
+                let err = core::str::from_utf8(&output.stderr)
+                    .unwrap_or("Could not format command output");
+                return Err(eyre!(
+                    "Failed to set interpreter for {}, output: {}",
+                    name.display(),
+                    err
+                ));
+            }
+        }
+        commands.push(command);
+    }
+
+    if args.interpreter_relative_check && file_is_dynamic_executable {
+        let interpreter = if keep_interpreter {
+            elf_file.get_interpreter().unwrap_or(interpreter_path)
+        } else {
+            interpreter_path
+        };
+        if interpreter.starts_with(&args.store_dir) {
+            Event::warning(
+                "absolute-interpreter",
+                format!(
+                    "{} cannot be relocated, its interpreter {} is in the store; start it through a wrapper running the dynamic linker instead",
+                    name.display(),
+                    interpreter.display()
+                ),
+            )
+            .field("file", name.display())
+            .field("interpreter", interpreter.display())
+            .emit();
         }
+    }
+
+    if args.fast_stubs && is_launcher_stub(&elf_file, metadata.len(), libc_libs) {
+        Event::info(
+            "stub",
+            format!(
+                "{} is a launcher stub, skipping dependency resolution",
+                name.display()
+            ),
+        )
+        .field("file", name.display())
+        .emit();
+        return Ok(FilePatch {
+            interpreter: !keep_interpreter,
+            commands,
+            ..FilePatch::default()
+        });
+    }
 
+    if file_is_dynamic_executable {
         rpath.extend(args.runtime_dependencies.iter().cloned());
     }
 
-    println!("searching for dependencies of {}", path.display());
+    // $ORIGIN is kept in the preserved entries, so that they stay valid
+    // wherever the file ends up, but they are searched relative to where the
+    // file is in the output
+    let mut preserved_rpath = Vec::new();
+    if args.preserve_rpath {
+        preserved_rpath.extend(elf_file.get_rpath().into_iter().filter(|e| !e.is_empty()));
+    }
+    let preserved_dirs: Vec<PathBuf> = preserved_rpath
+        .iter()
+        .filter_map(|entry| {
+            let origin = path_string(path.parent().unwrap_or(Path::new(".")));
+            let entry = entry
+                .replace("${ORIGIN}", &origin)
+                .replace("$ORIGIN", &origin);
+            expand_rpath_tokens(&entry, elf_file.is_64(), elf_file.get_arch())
+        })
+        .map(PathBuf::from)
+        .collect();
+    rpath.extend(preserved_rpath.iter().map(PathBuf::from));
 
-    let library_cache = library_computation.get_result()?;
+    Event::info(
+        "searching",
+        format!("searching for dependencies of {}", name.display()),
+    )
+    .field("file", name.display())
+    .emit();
 
     // Process dependencies
-    for dep in file_dependencies {
+    let needed_count = elf_file.get_needed().len();
+    let dlopen_priorities = elf_file.get_dlopen_priorities(&args.dlopen_notes);
+    for (index, dep) in file_dependencies.into_iter().enumerate() {
         let mut was_found = false;
+        // DT_NEEDED entries come first, the rest is from dlopen notes
+        let is_dlopen = index >= needed_count;
+
+        if explain {
+            let names: Vec<String> = dep.iter().map(|p| p.display().to_string()).collect();
+            Event::info("explain", format!("dependency: {}", names.join(" | "))).emit();
+        }
 
         for candidate in &dep {
             // This loop determines which candidate for a given
@@ -134,12 +673,19 @@ fn auto_patchelf_file(
             // candidates for a dep because of '.note.dlopen'
             // dependencies.
             //
+            // 0. If a candidate is skipped with --skip-soname, it is
+            //    provided at runtime by other means, and nothing needs to
+            //    be done.
             // 1. If a candidate is an absolute path, it is already a
             //    valid dependency if that path exists, and nothing needs
             //    to be done. It should be an error if that path does not exist.
+            // 1a. If a candidate names the dynamic linker itself, it is
+            //    always loaded by it and nothing needs to be done.
             // 2. If a candidate is found within libc, it should be dropped
             //    and resolved automatically by the dynamic linker, unless
             //    keep_libc is enabled.
+            // 2a. If a candidate is found in a preserved RPATH entry, it is
+            //    already resolvable and nothing needs to be done.
             // 3. If a candidate is found in our library dependencies, that
             //    dependency should be added to rpath.
             // 4. If all of the above fail, libc dependencies should still be
@@ -151,40 +697,184 @@ fn auto_patchelf_file(
             // and #3 may both be true. In that case, we still want to
             // add the dependency to rpath, as the original binary
             // presumably had it and this should be preserved.
+            //
+            // With no_libc_skip, libc is not treated specially at all, so
+            // #2 and #4 never apply and libc has to be found in step 3.
+            //
+            // With no_default_lib, the dynamic linker will not search the
+            // libc directory by itself, so #2 does not apply and #4 adds
+            // the libc directory to rpath.
 
-            let is_libc = libc_lib.join(candidate).is_file();
+            let libc = find_libc(libc_libs, candidate).filter(|_| !args.no_libc_skip);
+            let is_libc = libc.is_some();
 
             #[allow(clippy::if_same_then_else)]
-            if candidate.is_absolute() && candidate.is_file() {
+            if is_skipped(&args.skip_sonames, candidate) {
+                Event::info(
+                    "skipped",
+                    format!(" {} -> skipped, provided at runtime", candidate.display()),
+                )
+                .field("file", name.display())
+                .field("dependency", candidate.display())
+                .emit();
+                was_found = true;
+                break;
+            } else if candidate.is_absolute() && candidate.is_file() {
+                if explain {
+                    Event::info(
+                        "explain",
+                        format!(" {} -> absolute path exists", candidate.display()),
+                    )
+                    .emit();
+                }
                 was_found = true;
                 break;
-            } else if is_libc && !args.keep_libc {
+            } else if is_dynamic_linker(candidate, interpreter_path) {
+                if explain {
+                    Event::info(
+                        "explain",
+                        format!(" {} -> provided by the dynamic linker", candidate.display()),
+                    )
+                    .emit();
+                }
+                was_found = true;
+                break;
+            } else if is_libc && !args.keep_libc && !args.no_default_lib {
+                Event::info(
+                    "libc",
+                    format!(
+                        " {} -> provided by libc, left to the dynamic linker",
+                        candidate.display()
+                    ),
+                )
+                .field("file", name.display())
+                .field("dependency", candidate.display())
+                .field("confidence", Confidence::LibcDefault.as_str())
+                .emit();
+                was_found = true;
+                break;
+            } else if let Some(found_library) = preserved_dirs
+                .iter()
+                .map(|dir| dir.join(candidate))
+                .find(|lib| candidate.is_relative() && is_library_of_arch(lib, elf_file.get_arch()))
+            {
+                Event::info(
+                    "found",
+                    format!(
+                        " {} -> found in preserved RPATH: {}",
+                        candidate.display(),
+                        found_library.display()
+                    ),
+                )
+                .field("file", name.display())
+                .field("dependency", candidate.display())
+                .field("library", found_library.display())
+                .emit();
+                dependencies.push(Dependency {
+                    file: name.to_path_buf(),
+                    name: candidate.clone(),
+                    found: Some(found_library),
+                    confidence: candidate
+                        .to_str()
+                        .map(|name| Confidence::of_found(name, false)),
+                });
                 was_found = true;
                 break;
             } else if let Some(candidate_name) = candidate.file_name().and_then(|n| n.to_str()) {
-                if let Some(found_dependency) = library_cache.find_dependency(
-                    candidate_name,
+                let soname = args.soname_alias(candidate_name);
+                if let Some(found_library) = library_cache.find_library(
+                    soname,
                     elf_file.get_arch(),
                     elf_file.get_osabi(),
+                    args.allow_compat_arch,
                 ) {
-                    rpath.push(found_dependency.clone());
+                    let compat_arch = args.allow_compat_arch
+                        && library_cache
+                            .find_library(soname, elf_file.get_arch(), elf_file.get_osabi(), false)
+                            .is_none();
+                    let confidence =
+                        Confidence::of_found(soname, soname != candidate_name || compat_arch);
+                    let found_dependency = found_library.parent().unwrap_or(Path::new(""));
+                    let on_host = !args.rpath_host
+                        && args.host_lib_dirs.iter().any(|dir| dir == found_dependency);
+                    if !(is_dlopen && args.no_rpath_for_dlopen || on_host) {
+                        let runtime_library = library_cache.runtime_path(&found_library);
+                        let runtime_dir = runtime_library.parent().unwrap_or(Path::new(""));
+                        rpath.push(args.runtime_path(runtime_dir));
+                    }
                     dependencies.push(Dependency {
-                        file: path.to_path_buf(),
+                        file: name.to_path_buf(),
                         name: candidate.clone(),
-                        found: true,
+                        found: Some(found_library.clone()),
+                        confidence: Some(confidence),
                     });
-                    println!(
-                        " {} -> found: {}",
-                        candidate.display(),
-                        found_dependency.display()
-                    );
+                    Event::info(
+                        "found",
+                        format!(
+                            " {} -> found{}: {}{}{}",
+                            candidate.display(),
+                            if on_host { " on the host" } else { "" },
+                            found_dependency.display(),
+                            if confidence == Confidence::Low {
+                                " (low confidence)"
+                            } else {
+                                ""
+                            },
+                            if on_host {
+                                ", left to the dynamic linker"
+                            } else {
+                                ""
+                            }
+                        ),
+                    )
+                    .field("file", name.display())
+                    .field("dependency", candidate.display())
+                    .field("directory", found_dependency.display())
+                    .field("confidence", confidence.as_str())
+                    .emit();
+                    was_found = true;
+                    break;
+                } else if let Some(libc) = libc.filter(|_| args.no_default_lib) {
+                    let libc_lib = libc.parent().unwrap_or(Path::new(""));
+                    rpath.push(libc_lib.to_path_buf());
+                    dependencies.push(Dependency {
+                        file: name.to_path_buf(),
+                        name: candidate.clone(),
+                        found: Some(libc.clone()),
+                        confidence: Some(Confidence::LibcDefault),
+                    });
+                    Event::info(
+                        "found",
+                        format!(
+                            " {} -> found in libc: {}",
+                            candidate.display(),
+                            libc_lib.display()
+                        ),
+                    )
+                    .field("file", name.display())
+                    .field("dependency", candidate.display())
+                    .field("directory", libc_lib.display())
+                    .field("confidence", Confidence::LibcDefault.as_str())
+                    .emit();
                     was_found = true;
                     break;
                 }
             } else if is_libc && args.keep_libc {
+                Event::info(
+                    "libc",
+                    format!(" {} -> provided by libc", candidate.display()),
+                )
+                .field("file", name.display())
+                .field("dependency", candidate.display())
+                .field("confidence", Confidence::LibcDefault.as_str())
+                .emit();
                 was_found = true;
                 break;
             }
+
+            if explain {
+                Event::info("explain", format!(" {} -> no match", candidate.display())).emit();
+            }
         }
 
         if !was_found {
@@ -195,196 +885,4634 @@ fn auto_patchelf_file(
                 PathBuf::from(format!("any({})", names.join(", ")))
             };
 
-            dependencies.push(Dependency {
-                file: path.to_path_buf(),
-                name: dep_name.clone(),
-                found: false,
-            });
+            let priority = index
+                .checked_sub(needed_count)
+                .and_then(|index| dlopen_priorities.get(index));
+            match priority.map(|&priority| (priority, args.dlopen_actions.get(priority))) {
+                None | Some((_, MissingAction::Fail)) => {
+                    dependencies.push(Dependency {
+                        file: name.to_path_buf(),
+                        name: dep_name.clone(),
+                        found: None,
+                        confidence: None,
+                    });
 
-            println!(" {} -> not found!", dep_name.display());
+                    Event::info(
+                        "not-found",
+                        format!(" {} -> not found!", dep_name.display()),
+                    )
+                    .field("file", name.display())
+                    .field("dependency", dep_name.display())
+                    .emit();
+                }
+                Some((priority, MissingAction::Warn)) => Event::warning(
+                    "optional-missing",
+                    format!(
+                        "auto-patchelf could not satisfy {} dlopen dependency {} wanted by {}",
+                        priority.as_str(),
+                        dep_name.display(),
+                        name.display()
+                    ),
+                )
+                .field("file", name.display())
+                .field("dependency", dep_name.display())
+                .field("priority", priority.as_str())
+                .emit(),
+                Some((priority, MissingAction::Ignore)) => Event::info(
+                    "optional-missing",
+                    format!(
+                        " {} -> not found, ignoring {} dlopen dependency",
+                        dep_name.display(),
+                        priority.as_str()
+                    ),
+                )
+                .field("file", name.display())
+                .field("dependency", dep_name.display())
+                .field("priority", priority.as_str())
+                .emit(),
+            }
         }
     }
 
-    rpath.extend(args.append_rpaths.iter().cloned());
+    if args.check_cfi {
+        check_cfi(name, &elf_file, &dependencies);
+    }
 
-    // Deduplicate rpath entries
-    let mut unique_paths = HashMap::new();
-    for path in rpath {
-        let path_str = path.to_string_lossy().to_string();
-        unique_paths.entry(path_str).or_insert(path);
+    if !args.no_dedup_append {
+        rpath.extend(args.append_rpaths.iter().cloned());
     }
 
-    let deduped_rpath: Vec<_> = unique_paths.keys().cloned().collect();
+    let replace_needed = needed_replacements(args, &dependencies);
+    if !replace_needed.is_empty() {
+        if explain {
+            Event::info(
+                "explain",
+                format!("would run patchelf {}", replace_needed.join(" ")),
+            )
+            .emit();
+        }
+        let command = patchelf_command(args, path, replace_needed.into_iter().map(OsString::from));
+        if !explain && !dry_run {
+            Event::info(
+                "replace-needed",
+                format!("replacing DT_NEEDED entries of {}", name.display()),
+            )
+            .field("file", name.display())
+            .emit();
 
-    if !deduped_rpath.is_empty() {
-        let rpath_str = deduped_rpath.join(":");
-        println!("setting RPATH to: {rpath_str}");
+            let start = METRICS.start();
+            let output = to_command(&command).output()?;
+            METRICS.record_patchelf(start);
 
-        Command::new("patchelf")
-            .arg("--set-rpath")
-            .arg(&rpath_str)
-            .arg(path)
-            .args(&args.extra_args)
-            .status()
-            .ok();
+            if !output.status.success() {
+                let err = core::str::from_utf8(&output.stderr)
+                    .unwrap_or("Could not format command output");
+                return Err(eyre!(
+                    "Failed to replace DT_NEEDED entries of {}, output: {}",
+                    name.display(),
+                    err
+                ));
+            }
+        }
+        commands.push(command);
     }
 
-    Ok(dependencies)
-}
-
-/// Main auto-patchelf function
-fn auto_patchelf(
-    cli: &Cli,
-    interpreter: &ElfFile,
-    interpreter_path: &Path,
-    libc_lib: &Path,
-) -> Result<()> {
-    if cli.patch.paths.is_empty() {
-        return Err(eyre!("No paths to patch, stopping."));
+    let mut deduped_rpath = dedup_rpath(rpath);
+    if args.dedup_by_inode {
+        deduped_rpath = dedup_rpath_by_inode(deduped_rpath);
+    }
+    if args.no_dedup_append {
+        deduped_rpath.extend(args.append_rpaths.iter().map(path_string));
     }
 
-    let add_existing = cli.libraries.add_existing;
-    let recurse = cli.patch.recurse;
-    let paths = cli.patch.paths.clone();
-    let libraries = cli.libraries.libraries.clone();
-    let cache_computation = SharedHandle::new(thread::spawn(move || {
-        let mut library_cache = LibraryCache::new();
+    let rpath_length = deduped_rpath.join(":").len();
+    if rpath_length > args.warn_rpath_length {
+        Event::warning(
+            "long-rpath",
+            format!(
+                "RPATH of {} is {} characters long ({} entries), consider `patchelf --shrink-rpath`",
+                name.display(),
+                rpath_length,
+                deduped_rpath.len()
+            ),
+        )
+        .field("file", name.display())
+        .number("length", rpath_length)
+        .emit();
+    }
 
-        // Add all shared objects of the current output path to the cache
-        if add_existing {
-            library_cache.populate_cache(&paths, recurse)?;
+    if !deduped_rpath.is_empty() {
+        let rpath_str = deduped_rpath.join(":");
+        let mut patch_args = vec!["--set-rpath".into(), rpath_str.clone().into()];
+        if args.no_default_lib {
+            patch_args.push("--no-default-lib".into());
         }
+        let command = patchelf_command(args, path, patch_args);
 
-        library_cache.populate_cache(&libraries, false)?;
-        Ok(library_cache)
-    }));
-
-    let mut all_dependencies = Vec::new();
+        if explain {
+            Event::info("explain", format!("would set RPATH to: {rpath_str}")).emit();
+        } else if !dry_run {
+            Event::info("rpath", format!("setting RPATH to: {rpath_str}"))
+                .field("file", name.display())
+                .field("rpath", &rpath_str)
+                .emit();
 
-    // Process all files
-    for path in &cli.patch.paths {
-        let mut state = DirState::deserialize(path)?;
+            let start = METRICS.start();
+            to_command(&command).status().ok();
+            METRICS.record_patchelf(start);
+        }
+        commands.push(command);
+    }
 
-        for file_path in glob(path, "*", cli.patch.recurse)? {
-            let file_path = file_path?;
-            let cache_path = file_path.strip_prefix(path)?;
+    // patchelf rewrites files in place, which should leave their metadata alone
+    if !dry_run {
+        let drift = metadata_drift(&metadata, &fs::metadata(path)?);
+        if !drift.is_empty() {
+            Event::warning(
+                "metadata-drift",
+                format!(
+                    "{} of {} changed while patching",
+                    drift.join(", "),
+                    name.display()
+                ),
+            )
+            .field("file", name.display())
+            .emit();
+        }
+    }
 
-            if file_path.is_symlink() || !file_path.is_file() {
-                continue; // We care about regular files only, and we don't want to traverse symlinks
-            }
+    if args.xattr && !dry_run {
+        record_rpath_xattr(path, &deduped_rpath.join(":"));
+    }
 
-            let mut buf = [0u8; 4];
-            let read = File::open(&file_path)?.read_exact(&mut buf);
-            if read.is_err() || buf != [0x7f, 0x45, 0x4c, 0x46] {
-                continue; // We care about elf files only
-            }
+    Ok(FilePatch {
+        dependencies,
+        interpreter: file_is_dynamic_executable && !keep_interpreter,
+        rpath: deduped_rpath,
+        unexpected_arch: false,
+        commands,
+    })
+}
 
-            let mtime = file_path.metadata()?.mtime();
+/// Tells why the state file lets `path` be skipped or not for `--explain-cache`
+fn explain_cache(path: &Path, recorded: Option<i64>, mtime: i64) {
+    let decision = match recorded {
+        Some(recorded) if recorded == mtime => "skipped (cached, mtime matches)".to_string(),
+        Some(recorded) => format!("processing (mtime changed from {recorded} to {mtime})"),
+        None => "processing (not in cache)".to_string(),
+    };
+    Event::info("explain-cache", format!("{}: {decision}", path.display()))
+        .field("file", path.display())
+        .emit();
+}
 
-            if state.up_to_date(cache_path, mtime) {
-                continue;
+/// Records the RPATH `path` was patched with for `--xattr`
+///
+/// The patch is not undone if this fails, the attribute is just a hint to
+/// later runs.
+fn record_rpath_xattr(path: &Path, rpath: &str) {
+    match xattr::set(path, RPATH_XATTR, rpath.as_bytes()) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::Unsupported => {
+            if !XATTR_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+                Event::warning(
+                    "xattr-unsupported",
+                    format!(
+                        "the filesystem of {} does not support extended attributes, not recording patched RPATHs",
+                        path.display()
+                    ),
+                )
+                .field("file", path.display())
+                .emit();
             }
-
-            auto_patchelf_file(
-                &cli.patch,
-                &file_path,
-                &cache_computation,
-                interpreter_path,
-                interpreter,
-                libc_lib,
-            )
-            .inspect_err(|e| {
-                println!("Coulld not patch file: {e}");
-            })
-            .and_then(|deps| {
-                let mtime = file_path.metadata()?.mtime();
-                state.update(cache_path.to_owned(), mtime);
-                all_dependencies.extend(deps);
-                Ok(())
-            })
-            .unwrap_or_default();
         }
-
-        state.serialize()?;
+        Err(err) => Event::warning(
+            "xattr",
+            format!("could not record the RPATH of {}: {err}", path.display()),
+        )
+        .field("file", path.display())
+        .emit(),
     }
+}
 
-    // Check for missing dependencies
-    let missing: Vec<&Dependency> = all_dependencies.iter().filter(|dep| !dep.found).collect();
+/// Checks whether `path` still has the RPATH `--xattr` recorded for it
+///
+/// Rebuilding the file drops the attribute, and patching it some other way
+/// changes the RPATH, so a match means it is patched already.
+fn has_recorded_rpath(path: &Path) -> Result<bool> {
+    let Ok(Some(recorded)) = xattr::get(path, RPATH_XATTR) else {
+        return Ok(false);
+    };
+    let content = read_file(path)?;
+    let Ok(elf) = ElfFile::new(&content) else {
+        return Ok(false);
+    };
+    Ok(elf.get_rpath().join(":").as_bytes() == recorded)
+}
 
-    println!(
-        "auto-patchelf: {} dependencies could not be satisfied",
-        missing.len()
-    );
+/// Builds the arguments of a patchelf invocation on `path`, starting with the executable
+///
+/// The arguments passed through for the file come after `patch_args` and
+/// the file itself.
+fn patchelf_command(
+    args: &PatchConfig,
+    path: &Path,
+    patch_args: impl IntoIterator<Item = OsString>,
+) -> Vec<OsString> {
+    let mut command = vec![args.patchelf.clone().into_os_string()];
+    command.extend(patch_args);
+    command.push(path.into());
+    command.extend(args.patchelf_args(path).into_iter().map(OsString::from));
+    command
+}
 
-    let mut failure = false;
+fn to_command(command: &[OsString]) -> Command {
+    let mut process = Command::new(&command[0]);
+    process.args(&command[1..]);
+    process
+}
 
-    for dep in missing {
-        let mut ignored = false;
+/// Builds the `--replace-needed` arguments for dependencies resolved through an alias
+fn needed_replacements(args: &PatchConfig, dependencies: &[Dependency]) -> Vec<String> {
+    if !args.rewrite_needed {
+        return Vec::new();
+    }
 
-        if let Some(name) = dep.name.file_name().and_then(|n| n.to_str()) {
-            for pattern in &cli.patch.ignore_missing {
-                if Pattern::new(pattern)
-                    .map(|p| p.matches(name))
-                    .unwrap_or(false)
-                {
-                    println!(
-                        "warn: auto-patchelf ignoring missing {} wanted by {}",
-                        dep.name.display(),
-                        dep.file.display()
-                    );
-                    ignored = true;
-                    break;
-                }
-            }
+    let mut replacements = Vec::new();
+    for dep in dependencies.iter().filter(|dep| dep.found.is_some()) {
+        let Some(name) = dep.name.to_str() else {
+            continue;
+        };
+        let alias = args.soname_alias(name);
+        if alias != name {
+            replacements.extend([
+                "--replace-needed".to_string(),
+                name.to_string(),
+                alias.to_string(),
+            ]);
         }
+    }
+    replacements
+}
 
-        if !ignored {
-            println!(
-                "error: auto-patchelf could not satisfy dependency {} wanted by {}",
-                dep.name.display(),
-                dep.file.display()
-            );
-            failure = true;
+/// Checks whether all RPATH entries point into existing Nix store paths
+///
+/// Such files were most likely patched before, e.g. by the Python
+/// auto-patchelf or upstream.
+fn is_prepatched(rpath: &[&str], store_dir: &Path) -> bool {
+    let mut entries = rpath.iter().filter(|entry| !entry.is_empty()).peekable();
+    entries.peek().is_some()
+        && entries.all(|entry| {
+            let path = Path::new(entry);
+            is_store_path(path, store_dir) && path.is_dir()
+        })
+}
+
+/// Checks whether a path is under a `<hash>-<name>` directory of the store
+fn is_store_path(path: &Path, store_dir: &Path) -> bool {
+    const NIX_BASE32: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+
+    let Some(name) = path
+        .strip_prefix(store_dir)
+        .ok()
+        .and_then(|rest| rest.components().next())
+        .and_then(|name| name.as_os_str().to_str())
+    else {
+        return false;
+    };
+    match name.split_once('-') {
+        Some((hash, name)) => {
+            hash.len() == 32 && hash.chars().all(|c| NIX_BASE32.contains(c)) && !name.is_empty()
         }
+        None => false,
     }
+}
 
-    if failure {
-        return Err(eyre!(
-            "auto-patchelf failed to find all the required dependencies.\n\
-            Add the missing dependencies to --libs or use \
-            `--ignore-missing=\"foo.so.1 bar.so etc.so\"`."
-        ));
+/// Gets the library directories of the libc entries in `nix-support/orig-libc`
+///
+/// Toolchains with a split libc layout list multiple paths, entries without
+/// a `lib` directory are dropped with a warning.
+fn libc_dirs(orig_libc: &str) -> Vec<PathBuf> {
+    orig_libc
+        .split_whitespace()
+        .map(|entry| Path::new(entry).join("lib"))
+        .filter(|dir| {
+            let exists = dir.is_dir();
+            if !exists {
+                Event::warning(
+                    "libc",
+                    format!("ignoring missing libc directory {}", dir.display()),
+                )
+                .field("directory", dir.display())
+                .emit();
+            }
+            exists
+        })
+        .collect()
+}
+
+/// Finds a dependency in the libc directories
+fn find_libc(libc_libs: &[PathBuf], candidate: &Path) -> Option<PathBuf> {
+    libc_libs
+        .iter()
+        .map(|dir| dir.join(candidate))
+        .find(|lib| lib.is_file())
+}
+
+/// Guesses whether an executable only execs another one, so only its interpreter matters
+///
+/// Such stubs are tiny and need nothing but libc.
+fn is_launcher_stub(elf_file: &ElfFile, size: u64, libc_libs: &[PathBuf]) -> bool {
+    elf_file.is_dynamic_executable()
+        && size <= STUB_MAX_SIZE
+        && elf_file
+            .get_needed()
+            .iter()
+            .all(|needed| find_libc(libc_libs, needed).is_some())
+}
+
+/// Warns about resolved dependencies not supporting the CFI features of a file
+///
+/// The loader disables a feature for the whole process as soon as one
+/// loaded library lacks it, or refuses to load such a library when enforcing.
+fn check_cfi(path: &Path, elf_file: &ElfFile, dependencies: &[Dependency]) {
+    let features = elf_file.get_cfi_features();
+    if features.is_empty() {
+        return;
     }
 
-    Ok(())
+    for library in dependencies.iter().filter_map(|dep| dep.found.as_ref()) {
+        // Libraries from archives cannot be read
+        let Ok(content) = read_file(library) else {
+            continue;
+        };
+        let Ok(library_elf) = ElfFile::new(&content) else {
+            continue;
+        };
+        let supported = library_elf.get_cfi_features();
+        let missing: Vec<&str> = features
+            .iter()
+            .copied()
+            .filter(|feature| !supported.contains(feature))
+            .collect();
+        if !missing.is_empty() {
+            Event::warning(
+                "cfi",
+                format!(
+                    "{} uses {}, but its dependency {} does not support it",
+                    path.display(),
+                    missing.join(", "),
+                    library.display()
+                ),
+            )
+            .field("file", path.display())
+            .field("library", library.display())
+            .field("features", missing.join(","))
+            .emit();
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let args = Cli::parse()?;
-    println!("automatically fixing dependencies for ELF files");
+fn kernel_version_string(version: KernelVersion) -> String {
+    version.map(|n| n.to_string()).join(".")
+}
 
-    // Get interpreter information
-    let nix_bintools = env::var("NIX_BINTOOLS").unwrap_or_else(|_| DEFAULT_BINTOOLS.to_string());
-    let nix_support = PathBuf::from(nix_bintools).join("nix-support");
+/// Checks whether an interpreter exists and is an ELF file of the given architecture
+fn is_valid_interpreter(interpreter: &Path, arch: Arch) -> bool {
+    is_library_of_arch(interpreter, arch)
+}
 
-    let interpreter_path =
-        PathBuf::from(fs::read_to_string(nix_support.join("dynamic-linker"))?.trim());
-    let libc_lib =
-        PathBuf::from(fs::read_to_string(nix_support.join("orig-libc"))?.trim()).join("lib");
+/// Checks whether a file exists and is an ELF file of the given architecture
+fn is_library_of_arch(library: &Path, arch: Arch) -> bool {
+    read_file(library)
+        .is_ok_and(|content| ElfFile::new(&content).is_ok_and(|elf| elf.get_arch() == arch))
+}
 
-    let content = read_file(&interpreter_path)
-        .wrap_err_with(|| format!("Failed to read file {}", interpreter_path.display(),))?;
-    let interpreter = ElfFile::new(&content).wrap_err_with(|| {
-        format!(
-            "Failed to parse dynamic linker properties from {}",
-            interpreter_path.display(),
-        )
-    })?;
+/// Checks whether a relative dependency names the dynamic linker
+fn is_dynamic_linker(candidate: &Path, interpreter_path: &Path) -> bool {
+    candidate.is_relative()
+        && candidate.file_name().is_some()
+        && candidate.file_name() == interpreter_path.file_name()
+}
 
-    if !interpreter_path.exists() || !libc_lib.exists() {
-        return Err(eyre!("Failed to parse dynamic linker properties."));
+/// Lists the permission and ownership attributes that differ
+fn metadata_drift(before: &fs::Metadata, after: &fs::Metadata) -> Vec<&'static str> {
+    let mut drift = Vec::new();
+    if before.mode() != after.mode() {
+        drift.push("mode");
+    }
+    if before.uid() != after.uid() {
+        drift.push("owner");
+    }
+    if before.gid() != after.gid() {
+        drift.push("group");
+    }
+    drift
+}
+
+/// Deduplicates rpath entries, treating differently spelled paths as equal
+///
+/// The first occurrence of each directory is kept in place, as the dynamic
+/// linker uses the first directory providing a library.
+fn dedup_rpath(rpath: Vec<PathBuf>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    rpath
+        .iter()
+        .map(normalize_path)
+        .filter(|dir| seen.insert(dir.clone()))
+        .collect()
+}
+
+/// Deduplicates rpath entries that are the same directory on disk
+///
+/// Directories that cannot be stat'ed are kept as they are.
+fn dedup_rpath_by_inode(rpath: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    rpath
+        .into_iter()
+        .filter(|dir| match fs::metadata(dir) {
+            Ok(metadata) => seen.insert((metadata.dev(), metadata.ino())),
+            Err(_) => true,
+        })
+        .collect()
+}
+
+/// Builds the library cache in the background
+fn spawn_library_cache(cli: &Cli) -> SharedHandle<LibraryCache> {
+    let add_existing = cli.libraries.add_existing;
+    let recurse = cli.patch.recurse;
+    let paths = cli.patch.paths.clone();
+    let libraries = cli.libraries.libraries.clone();
+    let seed_from_report = cli.libraries.seed_from_report.clone();
+    let pc_dirs = cli.libraries.pc_dirs.clone();
+    let sibling_outputs = cli.libraries.sibling_outputs.clone();
+    let libs_recursive = cli.libraries.libs_recursive;
+    let extensions = cli.libraries.library_extensions.clone();
+    let libs_archives = cli.libraries.libs_archives.clone();
+    let symlink_mode = cli.libraries.lib_symlink_mode;
+    let runtime_map = cli.libraries.lib_runtime_map.clone();
+    let debug_soname = cli.libraries.debug_soname.clone();
+    let max_scan_depth = cli.patch.max_scan_depth;
+    let host_lib_dirs = cli.patch.host_lib_dirs.clone();
+    let load_caches = cli.libraries.load_caches.clone();
+    let save_cache = cli.libraries.save_cache.clone();
+    SharedHandle::new(thread::spawn(move || {
+        let mut library_cache = LibraryCache::new(extensions)
+            .with_symlink_mode(symlink_mode)
+            .with_debug_soname(debug_soname)
+            .with_runtime_map(runtime_map)
+            .with_max_scan_depth(max_scan_depth);
+        library_cache.load(&load_caches)?;
+
+        // Add all shared objects of the current output path to the cache. No
+        // file is patched before this is done, so their original RPATHs are
+        // followed. Only the rebuilds of --watch see the patched RPATHs.
+        if add_existing {
+            library_cache.populate_cache(&paths, recurse)?;
+        }
+
+        // Outputs of the same derivation come before anything from outside of it
+        let sibling_lib_dirs: Vec<PathBuf> = sibling_outputs
+            .iter()
+            .map(|output| output.join("lib"))
+            .filter(|dir| dir.is_dir())
+            .collect();
+        library_cache.populate_cache(&sibling_lib_dirs, false)?;
+
+        // Directories resolved by a previous run are searched before --libs
+        if let Some(report) = seed_from_report {
+            let seed: Vec<PathBuf> = read_lines(&report)?
+                .into_iter()
+                .map(PathBuf::from)
+                .filter(|dir| {
+                    let exists = dir.is_dir();
+                    if !exists {
+                        Event::warning(
+                            "seed",
+                            format!("ignoring seeded directory {}", dir.display()),
+                        )
+                        .field("directory", dir.display())
+                        .emit();
+                    }
+                    exists
+                })
+                .collect();
+            library_cache.populate_cache(&seed, false)?;
+        }
+
+        for pc_dir in &pc_dirs {
+            library_cache.populate_cache(&pkg_config::libdirs(pc_dir)?, false)?;
+        }
+
+        library_cache.populate_cache(&libraries, libs_recursive)?;
+        for archive in &libs_archives {
+            library_cache.populate_from_archive(archive)?;
+        }
+
+        // The host is only a fallback for what the inputs do not provide
+        library_cache.populate_cache(&host_lib_dirs, false)?;
+
+        if let Some(save_cache) = save_cache {
+            library_cache.save(&save_cache)?;
+        }
+        Ok(library_cache)
+    }))
+}
+
+/// Traces the dependency resolution of a single file without patching it
+fn explain_file(
+    cli: &Cli,
+    path: &Path,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<()> {
+    let cache_computation = spawn_library_cache(cli);
+
+    let patch = auto_patchelf_file(
+        &cli.patch,
+        path,
+        &cache_computation,
+        interpreter_path,
+        interpreter,
+        libc_libs,
+    )?;
+
+    if let Some(out) = &cli.report.commands_log {
+        fs::write(out, commands_log(&BTreeMap::from([(path.into(), patch)]))?)?;
+    }
+    Ok(())
+}
+
+/// Writes a hash of what patching would do to all files, without patching them
+///
+/// The plan lists every file with its interpreter and sorted RPATH. Files
+/// are named relative to the path they were found in, so that the hash is
+/// stable across machines.
+fn write_plan_hash(
+    cli: &Cli,
+    out: &Path,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<()> {
+    let cache_computation = spawn_library_cache(cli);
+    let patch = |file: &Path| {
+        auto_patchelf_file(
+            &cli.patch,
+            file,
+            &cache_computation,
+            interpreter_path,
+            interpreter,
+            libc_libs,
+        )
+    };
+
+    let mut plan = Vec::new();
+    for (logical_path, blob_path) in cli.patch.path_manifest.iter().flat_map(|m| m.blobs()) {
+        if blob_path.is_file() && is_elf(&blob_path)? {
+            let patch = auto_patchelf_blob(
+                &cli.patch,
+                &blob_path,
+                logical_path,
+                &cache_computation,
+                interpreter_path,
+                interpreter,
+                libc_libs,
+            )?;
+            plan.push(plan_entry(logical_path, interpreter_path, &patch));
+        }
+    }
+    for path in &cli.patch.paths {
+        for file_path in elf_files(path, cli.patch.recurse, cli.patch.max_scan_depth, None)? {
+            let name = match file_path.strip_prefix(path) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => &file_path,
+            };
+            plan.push(plan_entry(name, interpreter_path, &patch(&file_path)?));
+        }
+    }
+
+    let hash = format!("{:016x}", plan_hash(plan));
+    if out == Path::new("-") {
+        println!("{hash}");
+    } else {
+        fs::write(out, hash + "\n")?;
+    }
+    Ok(())
+}
+
+/// Describes the patching of a single file in the `--plan-hash` plan
+fn plan_entry(file: &Path, interpreter_path: &Path, patch: &FilePatch) -> String {
+    let interpreter = if patch.interpreter {
+        path_string(interpreter_path)
+    } else {
+        "-".to_string()
+    };
+    let mut rpath = patch.rpath.clone();
+    rpath.sort();
+    format!("{}\t{interpreter}\t{}", file.display(), rpath.join(":"))
+}
+
+/// Hashes the plan independently of the order files were processed in
+fn plan_hash(mut plan: Vec<String>) -> u64 {
+    plan.sort();
+    stable_hash(plan.join("\n").as_bytes())
+}
+
+/// Checks that the dependencies of all libraries can be satisfied within the library set
+fn validate_libs(cli: &Cli, libc_libs: &[PathBuf]) -> Result<()> {
+    let cache_computation = spawn_library_cache(cli);
+    let unresolved = cache_computation.get_result()?.validate(libc_libs)?;
+
+    for (lib, needed) in &unresolved {
+        Event::error(
+            "invalid-lib",
+            format!(
+                "library {} depends on {} which is not in the library set",
+                lib.display(),
+                needed.display()
+            ),
+        )
+        .field("file", lib.display())
+        .field("dependency", needed.display())
+        .emit();
+    }
+
+    if !unresolved.is_empty() {
+        return Err(eyre!(
+            "{} library dependencies could not be satisfied within the library set.",
+            unresolved.len()
+        ));
+    }
+
+    Event::info("valid-libs", "all library dependencies are satisfied").emit();
+    Ok(())
+}
+
+/// Gets the directories reachable from a resolved library
+///
+/// These are the directory of the library itself and its own rpath. Unlike
+/// when the cache is built, `$ORIGIN` entries are followed relative to where
+/// the library is.
+fn hop_dirs(library: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if !library.is_file() {
+        return Ok(dirs);
+    }
+
+    let parent = library.parent().unwrap_or(Path::new("."));
+    dirs.push(parent.to_path_buf());
+
+    let content = read_file(library)?;
+    if let Ok(elf) = ElfFile::new(&content) {
+        let origin = path_string(parent);
+        dirs.extend(
+            elf.get_rpath()
+                .iter()
+                .filter(|p| !p.is_empty())
+                .filter_map(|p| {
+                    let p = p.replace("${ORIGIN}", &origin).replace("$ORIGIN", &origin);
+                    expand_rpath_tokens(&p, elf.is_64(), elf.get_arch())
+                })
+                .map(PathBuf::from),
+        );
+    }
+
+    Ok(dirs)
+}
+
+/// Lists the regular ELF files under `path`, or `path` itself if it is a file
+///
+/// Files last modified before `since` are skipped without being read.
+fn elf_files(
+    path: &Path,
+    recurse: bool,
+    max_depth: Option<usize>,
+    since: Option<i64>,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let candidates: Vec<PathBuf> = if path.is_file() {
+        vec![path.to_path_buf()]
+    } else {
+        glob(path, "*", recurse, max_depth)?.collect::<Result<_, _>>()?
+    };
+
+    for file_path in candidates {
+        if since.is_some_and(|since| {
+            file_path
+                .symlink_metadata()
+                .is_ok_and(|metadata| metadata.mtime() < since)
+        }) {
+            continue;
+        }
+
+        if is_elf(&file_path)? {
+            files.push(file_path);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Checks whether `path` is a regular ELF file
+fn is_elf(path: &Path) -> Result<bool> {
+    if path.is_symlink() || !path.is_file() {
+        return Ok(false); // We care about regular files only, and we don't want to traverse symlinks
+    }
+
+    let mut buf = [0u8; 4];
+    let read = File::open(path)?.read_exact(&mut buf);
+    Ok(read.is_ok() && buf == [0x7f, 0x45, 0x4c, 0x46]) // We care about elf files only
+}
+
+/// Gets the directory whose state records the files of a `--paths` entry
+///
+/// A single file shares the state of the directory it is in.
+fn state_dir(path: &Path) -> &Path {
+    if path.is_file() {
+        path.parent().unwrap_or(Path::new(""))
+    } else {
+        path
+    }
+}
+
+/// Records the current mtimes of `files` in the states of their `--paths`
+fn refresh_state(cli: &Cli, files: &[PathBuf]) -> Result<()> {
+    let mut state_paths: Vec<&Path> = Vec::new();
+    for path in &cli.patch.paths {
+        if !state_paths.contains(&state_dir(path)) {
+            state_paths.push(state_dir(path));
+        }
+    }
+    for state_path in state_paths {
+        let files: Vec<&PathBuf> = files
+            .iter()
+            .filter(|file| file.starts_with(state_path))
+            .collect();
+        if files.is_empty() {
+            continue;
+        }
+        let mut state = DirState::deserialize(state_path, cli.patch.state_size_limit)?;
+        for file in files {
+            let mtime = file.metadata()?.mtime();
+            state.update(file.strip_prefix(state_path)?.to_owned(), mtime);
+        }
+        state.serialize()?;
+    }
+    Ok(())
+}
+
+/// Records all ELF files under `path` as up to date without patching them
+fn touch_state(
+    path: &Path,
+    recurse: bool,
+    max_depth: Option<usize>,
+    state_size_limit: u64,
+) -> Result<()> {
+    let state_path = state_dir(path);
+    let mut state = DirState::deserialize(state_path, state_size_limit)?;
+
+    for file_path in elf_files(path, recurse, max_depth, None)? {
+        let mtime = file_path.metadata()?.mtime();
+        state.update(file_path.strip_prefix(state_path)?.to_owned(), mtime);
+    }
+
+    state.serialize()
+}
+
+/// Prints how two state files differ
+fn diff_state(old: &Path, new: &Path, size_limit: u64) -> Result<()> {
+    for (path, change) in DirState::diff(old, new, size_limit)? {
+        let message = match change {
+            StateChange::Added(mtime) => format!("added {} (mtime {mtime})", path.display()),
+            StateChange::Removed(mtime) => format!("removed {} (mtime {mtime})", path.display()),
+            StateChange::Modified(old_mtime, new_mtime) => format!(
+                "modified {} (mtime {old_mtime} -> {new_mtime})",
+                path.display()
+            ),
+        };
+        Event::info("state-diff", message)
+            .field("file", path.display())
+            .emit();
+    }
+    Ok(())
+}
+
+/// Gets the deduplicated set of library files the dependencies were resolved to
+fn used_libraries(dependencies: &[Dependency]) -> BTreeSet<&Path> {
+    dependencies
+        .iter()
+        .filter_map(|dep| dep.found.as_deref())
+        .collect()
+}
+
+/// Collects the library files loaded through the given ones, including themselves
+///
+/// Dependencies of libraries are looked up in the library cache, so libraries
+/// provided by libc or not indexed at all are not part of the closure.
+fn library_closure<'a>(
+    libraries: impl IntoIterator<Item = &'a Path>,
+    library_cache: &LibraryCache,
+    allow_compat_arch: bool,
+) -> Result<BTreeSet<PathBuf>> {
+    let mut closure = BTreeSet::new();
+    let mut queue: Vec<PathBuf> = libraries.into_iter().map(Path::to_path_buf).collect();
+
+    while let Some(library) = queue.pop() {
+        // Members of --libs-archive are left out, they are not on the disk
+        if closure.contains(&library) || !library.is_file() {
+            continue;
+        }
+
+        let content = read_file(&library)?;
+        if let Ok(elf) = ElfFile::new(&content) {
+            for needed in elf.get_needed() {
+                if needed.is_absolute() {
+                    queue.extend(needed.is_file().then(|| needed.to_path_buf()));
+                } else if let Some(name) = needed.to_str() {
+                    queue.extend(library_cache.find_library(
+                        name,
+                        elf.get_arch(),
+                        elf.get_osabi(),
+                        allow_compat_arch,
+                    ));
+                }
+            }
+        }
+        closure.insert(library);
+    }
+
+    Ok(closure)
+}
+
+/// Builds the `--closure-report` of the resolved dependencies
+///
+/// Each file is reported with its resolved dependencies and the number and
+/// total size of the libraries in its closure, the total counts every
+/// library only once.
+fn closure_report(
+    dependencies: &[Dependency],
+    library_cache: &LibraryCache,
+    allow_compat_arch: bool,
+) -> Result<Object> {
+    let mut direct: BTreeMap<&Path, Vec<&Dependency>> = BTreeMap::new();
+    for dep in dependencies {
+        direct.entry(&dep.file).or_default().push(dep);
+    }
+
+    let number = |n: u64| Value::Number(Number::U64(n));
+    let size = |libraries: &BTreeSet<PathBuf>| -> Result<u64> {
+        libraries
+            .iter()
+            .map(|library| Ok(library.metadata()?.len()))
+            .sum()
+    };
+
+    let mut files = Array::new();
+    let mut all_libraries = BTreeSet::new();
+    for (file, deps) in direct {
+        let libraries = deps.iter().filter_map(|dep| dep.found.as_deref());
+        let closure = library_closure(libraries, library_cache, allow_compat_arch)?;
+
+        let resolved = deps
+            .iter()
+            .filter_map(|dep| {
+                let mut resolved = Object::new();
+                resolved.insert("name".into(), Value::String(path_string(&dep.name)));
+                resolved.insert(
+                    "library".into(),
+                    Value::String(path_string(dep.found.as_ref()?)),
+                );
+                resolved.insert(
+                    "confidence".into(),
+                    dep.confidence.map_or(Value::Null, |confidence| {
+                        Value::String(confidence.as_str().into())
+                    }),
+                );
+                Some(Value::Object(resolved))
+            })
+            .collect();
+
+        let mut entry = Object::new();
+        entry.insert("file".into(), Value::String(path_string(file)));
+        entry.insert("dependencies".into(), Value::Array(resolved));
+        entry.insert("libraries".into(), number(closure.len() as u64));
+        entry.insert("size".into(), number(size(&closure)?));
+        files.push(Value::Object(entry));
+
+        all_libraries.extend(closure);
+    }
+
+    let mut report = Object::new();
+    report.insert("files".into(), Value::Array(files));
+    report.insert("libraries".into(), number(all_libraries.len() as u64));
+    report.insert("size".into(), number(size(&all_libraries)?));
+    Ok(report)
+}
+
+/// Builds the `--commands-log` list of patchelf invocations
+///
+/// Like `compile_commands.json`, every invocation is an object with the
+/// `directory` it is run in, the `file` it patches and its `arguments`.
+fn commands_log(patches: &BTreeMap<PathBuf, FilePatch>) -> Result<String> {
+    let directory = path_string(env::current_dir()?);
+    let mut commands = Array::new();
+    for (file, patch) in patches {
+        for command in &patch.commands {
+            let mut entry = Object::new();
+            entry.insert("directory".into(), Value::String(directory.clone()));
+            entry.insert("file".into(), Value::String(path_string(file)));
+            let arguments = command
+                .iter()
+                .map(|arg| Value::String(arg.to_string_lossy().into_owned()))
+                .collect();
+            entry.insert("arguments".into(), Value::Array(arguments));
+            commands.push(Value::Object(entry));
+        }
+    }
+    Ok(json::to_string(&commands))
+}
+
+/// Quotes a string as a Nix string literal
+fn nix_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Builds the `--rpath-histogram` report of how many files have each RPATH entry
+///
+/// Directories shared by many files are candidates to be merged, which
+/// shortens the RPATH of all of them.
+fn rpath_histogram(patches: &BTreeMap<PathBuf, FilePatch>) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for dir in patches.values().flat_map(|patch| &patch.rpath) {
+        *counts.entry(dir).or_default() += 1;
+    }
+
+    let mut histogram: Vec<(&str, usize)> = counts.into_iter().collect();
+    histogram.sort_by(|(_, a), (_, b)| b.cmp(a));
+    histogram
+        .into_iter()
+        .map(|(dir, count)| format!("{count}\t{dir}\n"))
+        .collect()
+}
+
+/// Builds the `--nix-report` attribute set of the patched files and missing dependencies
+fn nix_report(
+    patches: &BTreeMap<PathBuf, FilePatch>,
+    dependencies: &[Dependency],
+    missing: &[&Dependency],
+    interpreter_path: &Path,
+) -> String {
+    let mut out = String::from("{\n  files = {\n");
+    for (file, patch) in patches {
+        let interpreter = if patch.interpreter {
+            nix_string(&path_string(interpreter_path))
+        } else {
+            "null".to_string()
+        };
+        let rpath: String = patch
+            .rpath
+            .iter()
+            .map(|dir| format!(" {}", nix_string(dir)))
+            .collect();
+        let resolved: String = dependencies
+            .iter()
+            .filter(|dep| dep.file == *file)
+            .filter_map(|dep| {
+                let confidence = dep.confidence.map_or("null".to_string(), |confidence| {
+                    nix_string(confidence.as_str())
+                });
+                Some(format!(
+                    "        {{ name = {}; library = {}; confidence = {confidence}; }}\n",
+                    nix_string(&path_string(&dep.name)),
+                    nix_string(&path_string(dep.found.as_ref()?))
+                ))
+            })
+            .collect();
+        out += &format!(
+            "    {} = {{\n      interpreter = {interpreter};\n      rpath = [{rpath} ];\n      dependencies = [\n{resolved}      ];\n    }};\n",
+            nix_string(&path_string(file))
+        );
+    }
+    out += "  };\n  missing = [\n";
+    for dep in missing {
+        out += &format!(
+            "    {{ file = {}; dependency = {}; }}\n",
+            nix_string(&path_string(&dep.file)),
+            nix_string(&path_string(&dep.name))
+        );
+    }
+    out += "  ];\n}\n";
+    out
+}
+
+/// Orders the dependencies of each file from the least to the most reliably resolved
+///
+/// The files keep their order, so that the dependencies of a file stay together.
+fn sort_by_confidence(dependencies: &mut [Dependency]) {
+    for file_dependencies in dependencies.chunk_by_mut(|a, b| a.file == b.file) {
+        file_dependencies.sort_by_key(Dependency::reliability);
+    }
+}
+
+/// Groups missing dependencies by name, listing the files that need each
+fn group_missing<'a>(missing: &[&'a Dependency]) -> BTreeMap<&'a Path, Vec<&'a Path>> {
+    let mut groups: BTreeMap<&Path, Vec<&Path>> = BTreeMap::new();
+    for dep in missing {
+        let files = groups.entry(&dep.name).or_default();
+        if !files.contains(&dep.file.as_path()) {
+            files.push(&dep.file);
+        }
+    }
+    groups
+}
+
+/// Formats dependencies like `ldd` does, grouped by the file that needs them
+///
+/// There is no load address to print, so a zero placeholder is used.
+fn ldd_format(dependencies: &[Dependency]) -> String {
+    let mut out = String::new();
+    let mut file = None;
+    for dep in dependencies {
+        if file != Some(&dep.file) {
+            file = Some(&dep.file);
+            out += &format!("{}:\n", dep.file.display());
+        }
+        match &dep.found {
+            Some(found) => {
+                let confidence = dep
+                    .confidence
+                    .map(|confidence| format!(" [{}]", confidence.as_str()))
+                    .unwrap_or_default();
+                out += &format!(
+                    "\t{} => {} (0x0000000000000000){confidence}\n",
+                    dep.name.display(),
+                    found.display()
+                )
+            }
+            None => out += &format!("\t{} => not found\n", dep.name.display()),
+        }
+    }
+    out
+}
+
+/// Checks whether a dependency is listed in `--skip-soname`, by its full name or basename
+fn is_skipped(sonames: &[String], name: &Path) -> bool {
+    sonames
+        .iter()
+        .any(|soname| name == Path::new(soname) || name.file_name() == Some(soname.as_ref()))
+}
+
+/// Checks whether a missing dependency matches any of the `--ignore-missing` patterns
+///
+/// Patterns containing a `/` are matched against the full dependency name,
+/// other patterns against its basename only.
+fn is_ignored(patterns: &[String], name: &Path) -> bool {
+    let full_name = name.to_string_lossy();
+    let basename = name.file_name().and_then(|n| n.to_str());
+
+    patterns.iter().any(|pattern| {
+        let Ok(compiled) = Pattern::new(pattern) else {
+            return false;
+        };
+        if pattern.contains('/') {
+            compiled.matches(&full_name)
+        } else {
+            basename.is_some_and(|name| compiled.matches(name))
+        }
+    })
+}
+
+/// Finds the dependencies of the dynamic linker that none of the candidates satisfy
+///
+/// A candidate is satisfied if it is an absolute path that exists, is
+/// provided by libc or is in the library cache.
+fn unsatisfied_interpreter_deps(
+    interpreter: &ElfFile,
+    library_cache: &LibraryCache,
+    libc_libs: &[PathBuf],
+    dlopen_notes: &[String],
+) -> Vec<PathBuf> {
+    let satisfied = |candidate: &PathBuf| {
+        (candidate.is_absolute() && candidate.is_file())
+            || find_libc(libc_libs, candidate).is_some()
+            || candidate
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|name| {
+                    library_cache.find_library(
+                        name,
+                        interpreter.get_arch(),
+                        interpreter.get_osabi(),
+                        false,
+                    )
+                })
+                .is_some()
+    };
+
+    interpreter
+        .get_dependencies(dlopen_notes)
+        .into_iter()
+        .filter(|candidates| !candidates.iter().any(satisfied))
+        .filter_map(|candidates| candidates.into_iter().next())
+        .collect()
+}
+
+/// Checks that patchelf can be run before doing any work
+///
+/// Returns its version, if `--version` printed one that could be parsed.
+fn check_patchelf(patchelf: &Path) -> Result<Option<PatchelfVersion>> {
+    let output = Command::new(patchelf)
+        .arg("--version")
+        .output()
+        .wrap_err_with(|| {
+            format!(
+                "Failed to run {}, make sure patchelf is installed or pass its location with --patchelf",
+                patchelf.display()
+            )
+        })?;
+
+    if !output.status.success() {
+        return Err(eyre!(
+            "{} --version failed with {}, is it really patchelf?",
+            patchelf.display(),
+            output.status
+        ));
+    }
+    Ok(PatchelfVersion::parse(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Checks that the detected patchelf understands the options it will be given
+///
+/// Failing here is clearer than patchelf rejecting an option halfway through
+/// the run, after some of the files were already patched.
+fn check_patchelf_version(args: &PatchConfig, version: Option<PatchelfVersion>) -> Result<()> {
+    let Some(version) = version else {
+        Event::info(
+            "patchelf-version",
+            format!(
+                "could not determine the version of {}, not checking the options it is given",
+                args.patchelf.display()
+            ),
+        )
+        .emit();
+        return Ok(());
+    };
+    Event::info("patchelf-version", format!("using patchelf {version}"))
+        .field("version", version)
+        .emit();
+
+    if version < RUNPATH_DEFAULT {
+        Event::warning(
+            "old-patchelf",
+            format!(
+                "patchelf {version} sets DT_RPATH instead of DT_RUNPATH, \
+                 which takes precedence over LD_LIBRARY_PATH"
+            ),
+        )
+        .emit();
+    }
+
+    let options = args
+        .no_default_lib
+        .then_some("--no-default-lib")
+        .into_iter()
+        .chain((!args.clear_symbol_versions.is_empty()).then_some("--clear-symbol-version"))
+        .chain(args.extra_args.iter().map(String::as_str))
+        .chain(args.per_file_args.all_args());
+    version.check_options(options)
+}
+
+/// Counts the dependencies that were neither found nor ignored
+fn unsatisfied_count(args: &PatchConfig, dependencies: &[Dependency]) -> usize {
+    dependencies
+        .iter()
+        .filter(|dep| dep.found.is_none() && !is_ignored(&args.ignore_missing, &dep.name))
+        .count()
+}
+
+/// Stops the run once `--max-errors` unsatisfied dependencies accumulated
+///
+/// That many misses usually mean that the library search path is wrong, so
+/// it is not worth resolving the rest of the files.
+fn check_max_errors(args: &PatchConfig, unsatisfied: usize) -> Result<()> {
+    match args.max_errors {
+        Some(max_errors) if unsatisfied > 0 && unsatisfied >= max_errors => Err(eyre!(
+            "Aborting after {unsatisfied} unsatisfied dependencies (--max-errors {max_errors}), \
+             check that --libs and --runtime-dependencies point to the right directories."
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reports that patching `file` failed, the run carries on with the other files
+fn report_patch_failure(file: &Path, err: &eyre::Report) {
+    Event::error("patch-failed", format!("Could not patch file: {err}"))
+        .field("file", file.display())
+        .emit();
+}
+
+/// Reports the dependencies that could not be satisfied
+///
+/// Returns the files that miss dependencies which are not ignored.
+fn report_missing<'a>(cli: &Cli, missing: &[&'a Dependency], patched: usize) -> Vec<&'a Path> {
+    Event::info(
+        "summary",
+        format!(
+            "auto-patchelf: {} dependencies could not be satisfied",
+            missing.len()
+        ),
+    )
+    .number("missing", missing.len())
+    .number("patched", patched)
+    .emit();
+
+    let mut failed_files: Vec<&Path> = Vec::new();
+
+    for dep in missing {
+        let ignored = is_ignored(&cli.patch.ignore_missing, &dep.name);
+        if !ignored && !failed_files.contains(&dep.file.as_path()) {
+            failed_files.push(&dep.file);
+        }
+
+        if cli.report.group_missing {
+            continue;
+        }
+
+        if ignored {
+            Event::warning(
+                "ignored",
+                format!(
+                    "auto-patchelf ignoring missing {} wanted by {}",
+                    dep.name.display(),
+                    dep.file.display()
+                ),
+            )
+            .field("file", dep.file.display())
+            .field("dependency", dep.name.display())
+            .emit();
+        } else {
+            Event::error(
+                "missing",
+                format!(
+                    "auto-patchelf could not satisfy dependency {} wanted by {}",
+                    dep.name.display(),
+                    dep.file.display()
+                ),
+            )
+            .field("file", dep.file.display())
+            .field("dependency", dep.name.display())
+            .emit();
+        }
+    }
+
+    if cli.report.group_missing {
+        for (name, files) in group_missing(missing) {
+            let list: String = files
+                .iter()
+                .map(|file| format!("\n  {}", file.display()))
+                .collect();
+            let event = if is_ignored(&cli.patch.ignore_missing, name) {
+                Event::warning(
+                    "ignored",
+                    format!(
+                        "auto-patchelf ignoring missing {} wanted by {} files:{list}",
+                        name.display(),
+                        files.len()
+                    ),
+                )
+            } else {
+                Event::error(
+                    "missing",
+                    format!(
+                        "auto-patchelf could not satisfy dependency {} wanted by {} files:{list}",
+                        name.display(),
+                        files.len()
+                    ),
+                )
+            };
+            event
+                .field("dependency", name.display())
+                .list("files", files.iter().map(|file| file.display()))
+                .emit();
+        }
+    }
+
+    failed_files
+}
+
+/// Main auto-patchelf function, returns the number of patched files
+fn auto_patchelf(
+    cli: &Cli,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<usize> {
+    // The counter spans the whole process, only count what this run emits
+    let warnings_before = output::warning_count();
+    if cli.patch.paths.is_empty() && cli.patch.path_manifest.is_none() {
+        if cli.patch.allow_empty {
+            Event::info("empty", "no paths to patch, nothing to do").emit();
+            return Ok(0);
+        }
+        return Err(eyre!("No paths to patch, stopping."));
+    }
+
+    if cli.patch.touch_state {
+        for path in &cli.patch.paths {
+            Event::info(
+                "touch-state",
+                format!("marking files in {} as up to date", path.display()),
+            )
+            .emit();
+            touch_state(
+                path,
+                cli.patch.recurse,
+                cli.patch.max_scan_depth,
+                cli.patch.state_size_limit,
+            )?;
+        }
+        return Ok(0);
+    }
+
+    let patchelf_version = check_patchelf(&cli.patch.patchelf)?;
+    check_patchelf_version(&cli.patch, patchelf_version)?;
+
+    let mut cache_computation = spawn_library_cache(cli);
+
+    if cli.patch.check_interpreter_deps {
+        match interpreter {
+            Some(interpreter) => {
+                let library_cache = cache_computation.get_result()?;
+                let unsatisfied = unsatisfied_interpreter_deps(
+                    interpreter,
+                    library_cache,
+                    libc_libs,
+                    &cli.patch.dlopen_notes,
+                );
+                for dep in unsatisfied {
+                    Event::warning(
+                        "interpreter-deps",
+                        format!(
+                            "dependency {} of the dynamic linker {} cannot be satisfied",
+                            dep.display(),
+                            interpreter_path.display()
+                        ),
+                    )
+                    .field("dependency", dep.display())
+                    .emit();
+                }
+            }
+            None => Event::warning(
+                "interpreter-deps",
+                "cannot check the dependencies of an unreadable dynamic linker",
+            )
+            .emit(),
+        }
+    }
+
+    let mut all_dependencies = Vec::new();
+    let mut patched_files = Vec::new();
+    let mut patches: BTreeMap<PathBuf, FilePatch> = BTreeMap::new();
+    let unsatisfied = AtomicUsize::new(0);
+
+    // Process content-addressed blobs, reporting them under their logical path
+    for (logical_path, blob_path) in cli.patch.path_manifest.iter().flat_map(|m| m.blobs()) {
+        if !blob_path.is_file() {
+            Event::warning(
+                "missing-blob",
+                format!(
+                    "blob {} of {} does not exist",
+                    blob_path.display(),
+                    logical_path.display()
+                ),
+            )
+            .field("file", logical_path.display())
+            .emit();
+            continue;
+        }
+
+        if !is_elf(&blob_path)? {
+            continue;
+        }
+
+        match auto_patchelf_blob(
+            &cli.patch,
+            &blob_path,
+            logical_path,
+            &cache_computation,
+            interpreter_path,
+            interpreter,
+            libc_libs,
+        ) {
+            Ok(mut patch) => {
+                unsatisfied.fetch_add(
+                    unsatisfied_count(&cli.patch, &patch.dependencies),
+                    Ordering::Relaxed,
+                );
+                all_dependencies.append(&mut patch.dependencies);
+                patched_files.push(logical_path.to_path_buf());
+                patches.insert(logical_path.to_path_buf(), patch);
+            }
+            Err(e) => report_patch_failure(logical_path, &e),
+        }
+        check_max_errors(&cli.patch, unsatisfied.load(Ordering::Relaxed))?;
+    }
+
+    // Process all files
+    for mut root in patch_roots(
+        cli,
+        &mut cache_computation,
+        interpreter,
+        interpreter_path,
+        libc_libs,
+        &unsatisfied,
+    )? {
+        all_dependencies.append(&mut root.dependencies);
+        patched_files.append(&mut root.patched_files);
+        patches.append(&mut root.patches);
+    }
+
+    if cli.patch.deep_resolve && all_dependencies.iter().any(|dep| dep.found.is_none()) {
+        let mut retry_files: Vec<PathBuf> = Vec::new();
+        for dep in all_dependencies.iter().filter(|dep| dep.found.is_none()) {
+            if dep.file.is_file() && !retry_files.contains(&dep.file) {
+                retry_files.push(dep.file.clone());
+            }
+        }
+
+        // What the resolved libraries find their own dependencies in
+        let mut dirs: Vec<PathBuf> = Vec::new();
+        let mut found: Vec<&Path> = all_dependencies
+            .iter()
+            .filter_map(|dep| dep.found.as_deref())
+            .collect();
+        found.sort();
+        found.dedup();
+        for library in found {
+            for dir in hop_dirs(library)? {
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+
+        Event::info(
+            "deep-resolve",
+            format!(
+                "retrying {} files after searching {} more directories",
+                retry_files.len(),
+                dirs.len()
+            ),
+        )
+        .emit();
+
+        let mut library_cache = cache_computation.into_result()?;
+        library_cache.populate_cache(&dirs, false)?;
+        cache_computation = SharedHandle::ready(library_cache);
+
+        all_dependencies.retain(|dep| !retry_files.contains(&dep.file));
+        let mut repatched = Vec::new();
+        for file in &retry_files {
+            match auto_patchelf_file(
+                &cli.patch,
+                file,
+                &cache_computation,
+                interpreter_path,
+                interpreter,
+                libc_libs,
+            ) {
+                Ok(mut patch) => {
+                    all_dependencies.extend(mem::take(&mut patch.dependencies));
+                    patches.insert(file.clone(), patch);
+                    repatched.push(file.clone());
+                }
+                Err(e) => report_patch_failure(file, &e),
+            }
+        }
+        // The states of the roots were written before the retry changed
+        // the files again
+        refresh_state(cli, &repatched)?;
+    }
+
+    if cli.report.sort_by_confidence {
+        sort_by_confidence(&mut all_dependencies);
+    }
+
+    // Check for missing dependencies
+    let missing: Vec<&Dependency> = all_dependencies
+        .iter()
+        .filter(|dep| dep.found.is_none())
+        .collect();
+
+    let failed_files = report_missing(cli, &missing, patched_files.len());
+
+    if let Some(out) = &cli.report.print0_patched {
+        write_nul_delimited(out, patched_files.iter().map(PathBuf::as_path))?;
+    }
+
+    if let Some(out) = &cli.report.resolved_dirs {
+        let mut dirs: Vec<&Path> = Vec::new();
+        for dir in all_dependencies
+            .iter()
+            .filter_map(|dep| dep.found.as_deref()?.parent())
+        {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+        write_lines(out, dirs)?;
+    }
+
+    if let Some(out) = &cli.report.used_libs {
+        write_lines(out, used_libraries(&all_dependencies))?;
+    }
+
+    if let Some(out) = &cli.report.closure_report {
+        let library_cache = cache_computation.get_result()?;
+        let report = closure_report(
+            &all_dependencies,
+            library_cache,
+            cli.patch.allow_compat_arch,
+        )?;
+        fs::write(out, json::to_string(&report))?;
+    }
+
+    if let Some(out) = &cli.report.nix_report {
+        fs::write(
+            out,
+            nix_report(&patches, &all_dependencies, &missing, interpreter_path),
+        )?;
+    }
+
+    if let Some(out) = &cli.report.print_needed {
+        fs::write(out, ldd_format(&all_dependencies))?;
+    }
+
+    if let Some(out) = &cli.report.commands_log {
+        fs::write(out, commands_log(&patches)?)?;
+    }
+
+    if let Some(out) = &cli.report.rpath_histogram {
+        fs::write(out, rpath_histogram(&patches))?;
+    }
+
+    if cli.report.report_unused_libs {
+        let library_set: Vec<PathBuf> = cli
+            .libraries
+            .libraries
+            .iter()
+            .chain(&cli.libraries.libs_archives)
+            .cloned()
+            .collect();
+        for dir in cache_computation.get_result()?.unused_dirs(&library_set) {
+            Event::info(
+                "unused-lib",
+                format!("{} provided no dependency", dir.display()),
+            )
+            .field("directory", dir.display())
+            .emit();
+        }
+    }
+
+    if let Some(out) = &cli.report.print0_missing {
+        write_nul_delimited(out, failed_files.iter().copied())?;
+    }
+
+    let unexpected_arch: Vec<&PathBuf> = patches
+        .iter()
+        .filter(|(_, patch)| patch.unexpected_arch)
+        .map(|(file, _)| file)
+        .collect();
+    if !unexpected_arch.is_empty() && !cli.patch.warn_unexpected_arch {
+        let list: String = unexpected_arch
+            .iter()
+            .map(|file| format!("\n  {}", file.display()))
+            .collect();
+        return Err(eyre!(
+            "auto-patchelf found files of unexpected architectures:{list}\n\
+            Remove them from the output or pass --warn-unexpected-arch."
+        ));
+    }
+
+    if !failed_files.is_empty() {
+        return Err(eyre!(MISSING_DEPENDENCIES));
+    }
+
+    let warnings = output::warning_count() - warnings_before;
+    if cli.patch.fail_on_warning && warnings > 0 {
+        return Err(eyre!(
+            "auto-patchelf emitted {warnings} warnings and --fail-on-warning is set."
+        ));
+    }
+
+    Ok(patched_files.len())
+}
+
+/// What patching the ELF files under one of the `--paths` found
+#[derive(Default)]
+struct RootPatch {
+    dependencies: Vec<Dependency>,
+    patched_files: Vec<PathBuf>,
+    patches: BTreeMap<PathBuf, FilePatch>,
+}
+
+/// Access to the library cache while patching one of the `--paths`
+enum CacheAccess<'a> {
+    /// The paths are patched one after the other, so the cache can be reindexed
+    Exclusive(&'a mut SharedHandle<LibraryCache>),
+    /// The paths are patched in parallel with `--jobs`
+    Shared(&'a SharedHandle<LibraryCache>),
+}
+
+impl CacheAccess<'_> {
+    fn get(&self) -> &SharedHandle<LibraryCache> {
+        match self {
+            CacheAccess::Exclusive(cache_computation) => cache_computation,
+            CacheAccess::Shared(cache_computation) => cache_computation,
+        }
+    }
+}
+
+/// Patches the ELF files under each of the `--paths`, returns what was found in order
+///
+/// With `--jobs`, that many threads patch different paths at the same time.
+/// Paths sharing a state file, like files of the same directory, are patched
+/// one after the other by the same thread, so that they do not overwrite the
+/// state of each other. Apart from that and the shared library cache, the
+/// paths are independent.
+fn patch_roots(
+    cli: &Cli,
+    cache_computation: &mut SharedHandle<LibraryCache>,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+    unsatisfied: &AtomicUsize,
+) -> Result<Vec<RootPatch>> {
+    let paths = &cli.patch.paths;
+    let jobs = cli.patch.jobs.min(paths.len());
+    if jobs <= 1 {
+        return paths
+            .iter()
+            .map(|path| {
+                patch_root(
+                    cli,
+                    path,
+                    CacheAccess::Exclusive(cache_computation),
+                    interpreter,
+                    interpreter_path,
+                    libc_libs,
+                    unsatisfied,
+                )
+            })
+            .collect();
+    }
+
+    let mut groups: Vec<(&Path, Vec<usize>)> = Vec::new();
+    for (index, path) in paths.iter().enumerate() {
+        let state_path = state_dir(path);
+        match groups.iter_mut().find(|(group, _)| *group == state_path) {
+            Some((_, indices)) => indices.push(index),
+            None => groups.push((state_path, vec![index])),
+        }
+    }
+
+    let next = AtomicUsize::new(0);
+    let cache_computation = &*cache_computation;
+    let mut roots: Vec<(usize, Result<RootPatch>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.min(groups.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut roots = Vec::new();
+                    loop {
+                        let group = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((_, indices)) = groups.get(group) else {
+                            return roots;
+                        };
+                        for &index in indices {
+                            let root = patch_root(
+                                cli,
+                                &paths[index],
+                                CacheAccess::Shared(cache_computation),
+                                interpreter,
+                                interpreter_path,
+                                libc_libs,
+                                unsatisfied,
+                            );
+                            roots.push((index, root));
+                        }
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("Thread panicked"))
+            .collect()
+    });
+    roots.sort_by_key(|(index, _)| *index);
+    roots.into_iter().map(|(_, root)| root).collect()
+}
+
+/// Patches the ELF files under `path` that changed since the last run
+fn patch_root(
+    cli: &Cli,
+    path: &Path,
+    mut cache: CacheAccess,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+    unsatisfied: &AtomicUsize,
+) -> Result<RootPatch> {
+    let mut root = RootPatch::default();
+    let state_path = state_dir(path);
+    let mut state = DirState::deserialize(state_path, cli.patch.state_size_limit)?;
+
+    for file_path in elf_files(
+        path,
+        cli.patch.recurse,
+        cli.patch.max_scan_depth,
+        cli.patch.since,
+    )? {
+        let cache_path = file_path.strip_prefix(state_path)?;
+
+        let mtime = file_path.metadata()?.mtime();
+
+        if cli.report.explain_cache {
+            explain_cache(&file_path, state.mtime(cache_path), mtime);
+        }
+        if state.up_to_date(cache_path, mtime) {
+            continue;
+        }
+        if cli.patch.xattr && has_recorded_rpath(&file_path)? {
+            Event::info(
+                "xattr",
+                format!(
+                    "{} has the RPATH recorded in {RPATH_XATTR}, not patching it again",
+                    file_path.display()
+                ),
+            )
+            .field("file", file_path.display())
+            .emit();
+            state.update(cache_path.to_owned(), mtime);
+            continue;
+        }
+
+        let mut reindex_dirs = Vec::new();
+        auto_patchelf_file(
+            &cli.patch,
+            &file_path,
+            cache.get(),
+            interpreter_path,
+            interpreter,
+            libc_libs,
+        )
+        .inspect_err(|e| report_patch_failure(&file_path, e))
+        .and_then(|mut patch| {
+            if cli.patch.reindex_after_patch {
+                reindex_dirs = patch.rpath.iter().map(PathBuf::from).collect();
+            }
+
+            let mtime = file_path.metadata()?.mtime();
+            state.update(cache_path.to_owned(), mtime);
+            unsatisfied.fetch_add(
+                unsatisfied_count(&cli.patch, &patch.dependencies),
+                Ordering::Relaxed,
+            );
+            root.dependencies.extend(mem::take(&mut patch.dependencies));
+            root.patched_files.push(file_path.clone());
+            root.patches.insert(file_path.clone(), patch);
+            Ok(())
+        })
+        .unwrap_or_default();
+        if let CacheAccess::Exclusive(cache_computation) = &mut cache {
+            if !reindex_dirs.is_empty() {
+                // Later files may depend on this one, so make what its new
+                // RPATH provides available to them
+                cache_computation
+                    .get_mut()?
+                    .populate_cache(&reindex_dirs, false)?;
+            }
+        }
+        check_max_errors(&cli.patch, unsatisfied.load(Ordering::Relaxed))?;
+
+        if cli.report.classify {
+            let content = read_file(&file_path)?;
+            if let Some(language) = ElfFile::new(&content)
+                .ok()
+                .and_then(|elf| elf.detect_language())
+            {
+                Event::info(
+                    "classify",
+                    format!("{} looks like a {language} binary", file_path.display()),
+                )
+                .field("file", file_path.display())
+                .field("language", language)
+                .emit();
+            }
+        }
+    }
+
+    state.serialize()?;
+    Ok(root)
+}
+
+/// Runs the patching process `--repeat` times, timing every iteration
+///
+/// Only the first iteration is expected to do real work, the later ones
+/// show how much the incremental state saves.
+fn repeat_auto_patchelf(
+    cli: &Cli,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<()> {
+    let repeat = cli.patch.repeat;
+    for iteration in 1..=repeat {
+        let start = Instant::now();
+        let patched = auto_patchelf(cli, interpreter, interpreter_path, libc_libs)?;
+        if repeat > 1 {
+            let elapsed = start.elapsed();
+            Event::info(
+                "repeat",
+                format!(
+                    "iteration {iteration}/{repeat} patched {patched} files in {:.3}s",
+                    elapsed.as_secs_f64()
+                ),
+            )
+            .number("iteration", iteration)
+            .number("patched", patched)
+            .number("millis", elapsed.as_millis() as usize)
+            .emit();
+        }
+    }
+    Ok(())
+}
+
+/// Re-patches the ELF files under `--paths` whenever they change
+///
+/// Changes are polled for, so that this works on any file system. The
+/// library cache is only rebuilt if one of the `--libs` entries changes.
+fn watch(
+    cli: &Cli,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<()> {
+    let mut watcher = Watcher::new(cli)?;
+    Event::info("watch", "watching for changes").emit();
+
+    loop {
+        thread::sleep(WATCH_INTERVAL);
+        watcher.step(cli, interpreter, interpreter_path, libc_libs)?;
+    }
+}
+
+/// State of `--watch` kept between polls
+struct Watcher<'a> {
+    cache_computation: SharedHandle<LibraryCache>,
+    libs_mtimes: BTreeMap<PathBuf, SystemTime>,
+    /// The paths to patch grouped by the state file they share
+    states: Vec<(Vec<&'a Path>, DirState)>,
+    /// Changed files with their modification time when they were last seen
+    pending: BTreeMap<PathBuf, i64>,
+}
+
+impl<'a> Watcher<'a> {
+    fn new(cli: &'a Cli) -> Result<Self> {
+        let mut groups: Vec<(&Path, Vec<&Path>)> = Vec::new();
+        for path in &cli.patch.paths {
+            let state_path = state_dir(path);
+            match groups.iter_mut().find(|(group, _)| *group == state_path) {
+                Some((_, paths)) => paths.push(path),
+                None => groups.push((state_path, vec![path])),
+            }
+        }
+        let states = groups
+            .into_iter()
+            .map(|(state_path, paths)| {
+                Ok((
+                    paths,
+                    DirState::deserialize(state_path, cli.patch.state_size_limit)?,
+                ))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            cache_computation: spawn_library_cache(cli),
+            libs_mtimes: libs_mtimes(cli),
+            states,
+            pending: BTreeMap::new(),
+        })
+    }
+
+    /// Re-patches the files that changed, returns the patched files
+    ///
+    /// A changed file is only patched once its modification time is the
+    /// same as on the previous poll, so that it is not patched while it is
+    /// still being written. Missing dependencies of the patched files are
+    /// reported like after a full run.
+    fn step(
+        &mut self,
+        cli: &Cli,
+        interpreter: Option<&ElfFile>,
+        interpreter_path: &Path,
+        libc_libs: &[PathBuf],
+    ) -> Result<Vec<PathBuf>> {
+        let mtimes = libs_mtimes(cli);
+        if mtimes != self.libs_mtimes {
+            Event::info("watch", "libraries changed, rebuilding the library cache").emit();
+            self.cache_computation = spawn_library_cache(cli);
+            self.libs_mtimes = mtimes;
+        }
+
+        let mut patched = Vec::new();
+        let mut dependencies = Vec::new();
+        for (paths, state) in &mut self.states {
+            let mut changed = false;
+            for path in paths.iter() {
+                for file_path in elf_files(path, cli.patch.recurse, cli.patch.max_scan_depth, None)?
+                {
+                    let cache_path = file_path.strip_prefix(state_dir(path))?;
+                    let mtime = file_path.metadata()?.mtime();
+                    if state.up_to_date(cache_path, mtime) {
+                        self.pending.remove(&file_path);
+                        continue;
+                    }
+                    if self.pending.insert(file_path.clone(), mtime) != Some(mtime) {
+                        continue;
+                    }
+                    self.pending.remove(&file_path);
+
+                    match auto_patchelf_file(
+                        &cli.patch,
+                        &file_path,
+                        &self.cache_computation,
+                        interpreter_path,
+                        interpreter,
+                        libc_libs,
+                    ) {
+                        Ok(mut patch) => {
+                            dependencies.append(&mut patch.dependencies);
+                            state.update(cache_path.to_owned(), file_path.metadata()?.mtime());
+                            changed = true;
+                            patched.push(file_path);
+                        }
+                        Err(e) => report_patch_failure(&file_path, &e),
+                    }
+                }
+            }
+            if changed {
+                state.serialize()?;
+            }
+        }
+
+        if !patched.is_empty() {
+            let missing: Vec<&Dependency> = dependencies
+                .iter()
+                .filter(|dep| dep.found.is_none())
+                .collect();
+            // Watching goes on, the files may be fixed by the next change
+            if !report_missing(cli, &missing, patched.len()).is_empty() {
+                Event::error("missing-dependencies", MISSING_DEPENDENCIES).emit();
+            }
+        }
+        Ok(patched)
+    }
+}
+
+/// Gets the modification times of the `--libs` entries and of the files
+/// and directories indexed from them
+///
+/// Adding or removing a library changes the modification time of the
+/// directory containing it, rewriting one changes its own.
+fn libs_mtimes(cli: &Cli) -> BTreeMap<PathBuf, SystemTime> {
+    let mut mtimes = BTreeMap::new();
+    for lib in &cli.libraries.libraries {
+        collect_mtimes(lib, 0, cli, &mut mtimes);
+    }
+    mtimes
+}
+
+fn collect_mtimes(
+    path: &Path,
+    depth: usize,
+    cli: &Cli,
+    mtimes: &mut BTreeMap<PathBuf, SystemTime>,
+) {
+    let Ok(metadata) = path.metadata() else {
+        return;
+    };
+    if let Ok(mtime) = metadata.modified() {
+        mtimes.insert(path.to_owned(), mtime);
+    }
+    if !metadata.is_dir() {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            let too_deep = cli.patch.max_scan_depth.is_some_and(|max| depth >= max);
+            if cli.libraries.libs_recursive && !too_deep {
+                collect_mtimes(&entry_path, depth + 1, cli, mtimes);
+            }
+        } else if let Ok(mtime) = entry_path.metadata().and_then(|m| m.modified()) {
+            mtimes.insert(entry_path, mtime);
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Cli::parse()?;
+    output::init(args.report.format);
+    if args.report.summary_only {
+        output::set_summary_only();
+    }
+    if args.report.normalize_output_paths {
+        output::set_normalize_store_paths(&args.patch.store_dir);
+    }
+    if let Some(log_file) = &args.report.log_file {
+        output::set_log_file(log_file)
+            .wrap_err_with(|| format!("Failed to open log file {}", log_file.display()))?;
+    }
+    if let Some(socket) = &args.report.report_socket {
+        if let Err(err) = output::set_report_socket(socket) {
+            Event::warning(
+                "report-socket",
+                format!(
+                    "could not connect to the report socket {}, only reporting on stdout: {err}",
+                    socket.display()
+                ),
+            )
+            .emit();
+        }
+    }
+    if args.report.concurrency_metrics {
+        METRICS.enable();
+    }
+
+    if let Some((old, new)) = &args.patch.diff_state {
+        return diff_state(old, new, args.patch.state_size_limit);
+    }
+
+    Event::info("start", "automatically fixing dependencies for ELF files").emit();
+
+    // Get interpreter information
+    let nix_bintools = env::var("NIX_BINTOOLS").unwrap_or_else(|_| DEFAULT_BINTOOLS.to_string());
+    let nix_support = PathBuf::from(nix_bintools).join("nix-support");
+
+    let interpreter_path =
+        PathBuf::from(fs::read_to_string(nix_support.join("dynamic-linker"))?.trim());
+    let libc_libs = libc_dirs(&fs::read_to_string(nix_support.join("orig-libc"))?);
+
+    let content = read_file(&interpreter_path)
+        .wrap_err_with(|| format!("Failed to read file {}", interpreter_path.display(),));
+    let interpreter = content
+        .as_deref()
+        .map_err(|err| eyre!("{err}"))
+        .and_then(|content| {
+            ElfFile::new(content).wrap_err_with(|| {
+                format!(
+                    "Failed to parse dynamic linker properties from {}",
+                    interpreter_path.display(),
+                )
+            })
+        });
+
+    let interpreter = if args.patch.no_interpreter_check {
+        match interpreter {
+            Ok(interpreter) => Some(interpreter),
+            Err(err) => {
+                Event::warning(
+                    "interpreter",
+                    format!("not checking architecture of patched files: {err:#}"),
+                )
+                .field("interpreter", interpreter_path.display())
+                .emit();
+                None
+            }
+        }
+    } else {
+        let interpreter = interpreter?;
+        if !interpreter_path.exists() || libc_libs.is_empty() {
+            return Err(eyre!("Failed to parse dynamic linker properties."));
+        }
+        Some(interpreter)
+    };
+
+    match args.command {
+        Subcommand::Check => validate_libs(&args, &libc_libs),
+        Subcommand::Inspect => {
+            let file = args.patch.explain.as_deref().expect("inspect has a file");
+            explain_file(
+                &args,
+                file,
+                interpreter.as_ref(),
+                &interpreter_path,
+                &libc_libs,
+            )
+        }
+        Subcommand::Patch => patch(&args, interpreter.as_ref(), &interpreter_path, &libc_libs),
+    }
+}
+
+/// Runs the `patch` command, the default when no command is given
+fn patch(
+    args: &Cli,
+    interpreter: Option<&ElfFile>,
+    interpreter_path: &Path,
+    libc_libs: &[PathBuf],
+) -> Result<()> {
+    if let Some(out) = &args.patch.plan_hash {
+        return write_plan_hash(args, out, interpreter, interpreter_path, libc_libs);
     }
 
     // Run the patching process
-    auto_patchelf(&args, &interpreter, &interpreter_path, &libc_lib)
+    let result = repeat_auto_patchelf(args, interpreter, interpreter_path, libc_libs);
+
+    if args.patch.watch {
+        if let Err(err) = result {
+            Event::error("failed", format!("{err:#}")).emit();
+        }
+        return watch(args, interpreter, interpreter_path, libc_libs);
+    }
+
+    if args.report.concurrency_metrics {
+        METRICS.report().emit();
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::{asset, TempDir};
+    use goblin::elf::header;
+    use std::{ffi::OsStr, io, slice};
+
+    #[test]
+    fn test_hop_dirs() {
+        let lib = &asset("pam_systemd_home.so");
+
+        let dirs = hop_dirs(lib).unwrap();
+
+        assert_eq!(dirs[0], lib.parent().unwrap());
+        assert!(dirs.contains(&PathBuf::from(
+            "/nix/store/0szrc79hm06rprwd4v5lg80fwg4sn2wj-libxcrypt-4.4.36/lib"
+        )));
+        assert!(hop_dirs(Path::new("/nonexistent/libfoo.so"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_deep_resolve() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // libtwohop.so needs liborigin.so from --libs and libdep.so, which is
+        // only reachable through the $ORIGIN/../lib RUNPATH of liborigin.so
+        let dir = TempDir::new("deep-resolve");
+        let out = dir.join("out");
+        dir.copy_asset("libtwohop.so", "out/libtwohop.so");
+        dir.copy_asset("liborigin.so", "deps/bin/liborigin.so");
+        let libdep = dir.copy_asset("pam_systemd_home.so", "deps/lib/libdep.so");
+
+        // Every patch gets a new mtime, like a real patchelf run would
+        let patchelf = dir.join("patchelf");
+        fs::write(dir.join("counter"), "1").unwrap();
+        fs::write(
+            &patchelf,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = --version ] && exec echo patchelf 0.18.0\n\
+                 for file; do :; done\ncounter={}\n\
+                 n=$(( $(cat \"$counter\") + 1 ))\necho $n > \"$counter\"\n\
+                 touch -d \"@$n\" \"$file\"\n",
+                dir.join("counter").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&patchelf, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let libs = dir.join("deps/bin");
+        let run = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                patchelf.as_os_str(),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--libs"),
+                libs.as_os_str(),
+                OsStr::new("--paths"),
+                out.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            output::capture(|| {
+                auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+            })
+        };
+        let output = run(&[]);
+        assert!(output.contains("ignoring missing libdep.so"), "{output}");
+
+        fs::remove_file(out.join(".auto-patchelf.state")).unwrap();
+        let output = run(&["--deep-resolve"]);
+        assert!(output.contains("retrying 1 files"), "{output}");
+        assert!(!output.contains("ignoring missing libdep.so"), "{output}");
+        assert!(output.contains(&path_string(libdep.parent().unwrap())));
+
+        // The retry is part of the recorded state
+        let output = run(&["--deep-resolve"]);
+        assert!(!output.contains("searching for dependencies"), "{output}");
+    }
+
+    #[test]
+    fn test_dedup_rpath_ignores_trailing_slashes() {
+        let rpath = vec![
+            PathBuf::from("/nix/store/abc/lib"),
+            PathBuf::from("/nix/store/abc/lib/"),
+            PathBuf::from("/nix/store//abc/lib//"),
+        ];
+
+        assert_eq!(dedup_rpath(rpath), vec!["/nix/store/abc/lib"]);
+    }
+
+    #[test]
+    fn test_dedup_rpath_keeps_order() {
+        // A dependency, a runtime dependency and an appended rpath
+        let rpath = ["/a/lib", "/b/lib", "/a/lib"].map(PathBuf::from).to_vec();
+        assert_eq!(dedup_rpath(rpath).join(":"), "/a/lib:/b/lib");
+
+        let rpath = ["/c/lib", "/b/lib", "/a/lib", "/b/lib/"]
+            .map(PathBuf::from)
+            .to_vec();
+        assert_eq!(dedup_rpath(rpath), ["/c/lib", "/b/lib", "/a/lib"]);
+    }
+
+    #[test]
+    fn test_dedup_rpath_by_inode() {
+        let dir = TempDir::new("inode");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        std::os::unix::fs::symlink("lib", dir.join("lib64")).unwrap();
+        std::os::unix::fs::symlink("lib", dir.join("lib32")).unwrap();
+        let spelling = |name: &str| path_string(dir.join(name));
+
+        let rpath = vec![
+            spelling("lib64"),
+            spelling("lib"),
+            spelling("missing"),
+            spelling("lib32"),
+        ];
+        assert_eq!(
+            dedup_rpath_by_inode(rpath),
+            vec![spelling("lib64"), spelling("missing")]
+        );
+    }
+
+    #[test]
+    fn test_used_libraries() {
+        let dependency = |file: &str, name: &str, found: Option<&str>| Dependency {
+            file: PathBuf::from(file),
+            name: PathBuf::from(name),
+            found: found.map(PathBuf::from),
+            confidence: found.map(|_| Confidence::Medium),
+        };
+        let dependencies = [
+            dependency("/bin/a", "libfoo.so", Some("/lib/libfoo.so")),
+            dependency("/bin/b", "libfoo.so", Some("/lib/libfoo.so")),
+            dependency("/bin/b", "libbar.so.1", Some("/opt/libbar.so.1")),
+            dependency("/bin/b", "libmissing.so", None),
+        ];
+
+        assert_eq!(
+            used_libraries(&dependencies),
+            BTreeSet::from([Path::new("/lib/libfoo.so"), Path::new("/opt/libbar.so.1")])
+        );
+    }
+
+    #[test]
+    fn test_closure_report() {
+        let lib = &asset("pam_systemd_home.so");
+        let hybrid = &asset("hybrid");
+        let dependency = |file: &str, found: Option<&Path>| Dependency {
+            file: PathBuf::from(file),
+            name: PathBuf::from("libfoo.so"),
+            found: found.map(Path::to_path_buf),
+            confidence: found.map(|_| Confidence::Medium),
+        };
+        let dependencies = [
+            dependency("/bin/a", Some(lib)),
+            dependency("/bin/b", Some(lib)),
+            dependency("/bin/b", Some(hybrid)),
+            dependency("/bin/b", None),
+        ];
+
+        let library_cache = LibraryCache::new(vec!["so".into()]);
+        let report = closure_report(&dependencies, &library_cache, false).unwrap();
+
+        let lib_size = lib.metadata().unwrap().len();
+        let hybrid_size = hybrid.metadata().unwrap().len();
+        let entry = |value: &Value| match value {
+            Value::Object(entry) => match (&entry["file"], &entry["size"]) {
+                (Value::String(file), Value::Number(Number::U64(size))) => (file.clone(), *size),
+                _ => panic!("unexpected entry {entry:?}"),
+            },
+            _ => panic!("unexpected entry {value:?}"),
+        };
+        let Value::Array(files) = &report["files"] else {
+            panic!("files is not an array");
+        };
+
+        assert_eq!(
+            files.iter().map(entry).collect::<Vec<_>>(),
+            [
+                ("/bin/a".to_string(), lib_size),
+                ("/bin/b".to_string(), lib_size + hybrid_size),
+            ]
+        );
+        assert!(matches!(
+            report["size"],
+            Value::Number(Number::U64(size)) if size == lib_size + hybrid_size
+        ));
+
+        let Value::Object(file) = &files[1] else {
+            panic!("{:?} is not an object", files[1]);
+        };
+        assert_eq!(
+            json::to_string(&file["dependencies"]),
+            format!(
+                r#"[{{"confidence":"medium","library":"{}","name":"libfoo.so"}},{{"confidence":"medium","library":"{}","name":"libfoo.so"}}]"#,
+                lib.display(),
+                hybrid.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_nix_report() {
+        assert_eq!(nix_string(r#"a"b\c${d}$e"#), r#""a\"b\\c\${d}$e""#);
+
+        let patches = BTreeMap::from([
+            (
+                PathBuf::from("/bin/a"),
+                FilePatch {
+                    interpreter: true,
+                    rpath: vec!["/lib".to_string(), "/opt/lib".to_string()],
+                    ..FilePatch::default()
+                },
+            ),
+            (PathBuf::from("/lib/libb.so"), FilePatch::default()),
+        ]);
+        let dependencies = [
+            Dependency {
+                file: PathBuf::from("/bin/a"),
+                name: PathBuf::from("libfoo.so.1"),
+                found: Some(PathBuf::from("/lib/libfoo.so.1")),
+                confidence: Some(Confidence::High),
+            },
+            Dependency {
+                file: PathBuf::from("/bin/a"),
+                name: PathBuf::from("libmissing.so"),
+                found: None,
+                confidence: None,
+            },
+        ];
+
+        let report = nix_report(
+            &patches,
+            &dependencies,
+            &[&dependencies[1]],
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+        );
+        assert_eq!(
+            report,
+            r#"{
+  files = {
+    "/bin/a" = {
+      interpreter = "/lib64/ld-linux-x86-64.so.2";
+      rpath = [ "/lib" "/opt/lib" ];
+      dependencies = [
+        { name = "libfoo.so.1"; library = "/lib/libfoo.so.1"; confidence = "high"; }
+      ];
+    };
+    "/lib/libb.so" = {
+      interpreter = null;
+      rpath = [ ];
+      dependencies = [
+      ];
+    };
+  };
+  missing = [
+    { file = "/bin/a"; dependency = "libmissing.so"; }
+  ];
+}
+"#
+        );
+
+        // Names needing escapes must still parse
+        let patches = BTreeMap::from([(
+            PathBuf::from("/bin/a \"b\" \\ ${c}\n"),
+            FilePatch::default(),
+        )]);
+        let tricky = nix_report(&patches, &[], &[], Path::new("/lib64/ld.so"));
+
+        let dir = TempDir::new("nix-report");
+        for (name, report) in [("report.nix", &report), ("tricky.nix", &tricky)] {
+            let path = dir.join(name);
+            fs::write(&path, report).unwrap();
+            match Command::new("nix-instantiate")
+                .arg("--parse")
+                .arg(&path)
+                .output()
+            {
+                Ok(output) => assert!(
+                    output.status.success(),
+                    "{report}\n{}",
+                    String::from_utf8_lossy(&output.stderr)
+                ),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    eprintln!("nix-instantiate is not available, not parsing the reports");
+                    return;
+                }
+                Err(err) => panic!("failed to run nix-instantiate: {err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_rpath_histogram() {
+        let patch = |rpath: &[&str]| FilePatch {
+            rpath: rpath.iter().map(|p| p.to_string()).collect(),
+            ..FilePatch::default()
+        };
+        let patches = BTreeMap::from([
+            (PathBuf::from("/bin/a"), patch(&["/lib/b", "/lib/a"])),
+            (PathBuf::from("/bin/b"), patch(&["/lib/c", "/lib/a"])),
+            (PathBuf::from("/bin/c"), patch(&["/lib/a", "/lib/c"])),
+            (PathBuf::from("/bin/d"), patch(&[])),
+        ]);
+
+        assert_eq!(
+            rpath_histogram(&patches),
+            "3\t/lib/a\n2\t/lib/c\n1\t/lib/b\n"
+        );
+    }
+
+    #[test]
+    fn test_group_missing() {
+        let dependency = |file: &str, name: &str| Dependency {
+            file: PathBuf::from(file),
+            name: PathBuf::from(name),
+            found: None,
+            confidence: None,
+        };
+        let dependencies = [
+            dependency("/bin/b", "libfoo.so.1"),
+            dependency("/bin/a", "libfoo.so.1"),
+            dependency("/bin/a", "libbar.so"),
+            dependency("/bin/b", "libfoo.so.1"),
+        ];
+        let missing: Vec<&Dependency> = dependencies.iter().collect();
+
+        assert_eq!(
+            group_missing(&missing),
+            BTreeMap::from([
+                (Path::new("libbar.so"), vec![Path::new("/bin/a")]),
+                (
+                    Path::new("libfoo.so.1"),
+                    vec![Path::new("/bin/b"), Path::new("/bin/a")]
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_ldd_format() {
+        let dependency = |file: &str, name: &str, found: Option<&str>, confidence| Dependency {
+            file: PathBuf::from(file),
+            name: PathBuf::from(name),
+            found: found.map(PathBuf::from),
+            confidence,
+        };
+        let mut dependencies = [
+            dependency(
+                "/bin/a",
+                "libfoo.so",
+                Some("/lib/libfoo.so"),
+                Some(Confidence::Medium),
+            ),
+            dependency(
+                "/bin/b",
+                "libc.so.6",
+                Some("/lib/libc.so.6"),
+                Some(Confidence::LibcDefault),
+            ),
+            dependency(
+                "/bin/b",
+                "libbar.so.1",
+                Some("/lib/libbar.so.1"),
+                Some(Confidence::High),
+            ),
+            dependency(
+                "/bin/b",
+                "libfoo.so",
+                Some("/lib/libfoo.so"),
+                Some(Confidence::Low),
+            ),
+            dependency("/bin/b", "libmissing.so", None, None),
+        ];
+
+        assert_eq!(
+            ldd_format(&dependencies),
+            "/bin/a:\n\
+             \tlibfoo.so => /lib/libfoo.so (0x0000000000000000) [medium]\n\
+             /bin/b:\n\
+             \tlibc.so.6 => /lib/libc.so.6 (0x0000000000000000) [libc-default]\n\
+             \tlibbar.so.1 => /lib/libbar.so.1 (0x0000000000000000) [high]\n\
+             \tlibfoo.so => /lib/libfoo.so (0x0000000000000000) [low]\n\
+             \tlibmissing.so => not found\n"
+        );
+
+        sort_by_confidence(&mut dependencies);
+        assert_eq!(
+            ldd_format(&dependencies),
+            "/bin/a:\n\
+             \tlibfoo.so => /lib/libfoo.so (0x0000000000000000) [medium]\n\
+             /bin/b:\n\
+             \tlibmissing.so => not found\n\
+             \tlibfoo.so => /lib/libfoo.so (0x0000000000000000) [low]\n\
+             \tlibbar.so.1 => /lib/libbar.so.1 (0x0000000000000000) [high]\n\
+             \tlibc.so.6 => /lib/libc.so.6 (0x0000000000000000) [libc-default]\n"
+        );
+    }
+
+    #[test]
+    fn test_ignore_missing_basename_and_full_path() {
+        let dep = Path::new("engines-3/libpkcs11.so");
+        let patterns = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+
+        assert!(is_ignored(&patterns(&["libpkcs11.so"]), dep));
+        assert!(is_ignored(&patterns(&["engines-3/*"]), dep));
+        assert!(!is_ignored(&patterns(&["engines-3"]), dep));
+        assert!(!is_ignored(&patterns(&["other/libpkcs11.so"]), dep));
+        assert!(!is_ignored(
+            &patterns(&["libpkcs11.so"]),
+            Path::new("libfoo.so")
+        ));
+    }
+
+    #[test]
+    fn test_since_skips_older_files() {
+        let dir = TempDir::new("since");
+        let asset = &asset("pam_systemd_home.so");
+        let old = dir.join("old.so");
+        let new = dir.join("new.so");
+        fs::copy(asset, &old).unwrap();
+        fs::copy(asset, &new).unwrap();
+        let epoch = |secs| std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(epoch(1_000))
+            .unwrap();
+        File::options()
+            .write(true)
+            .open(&new)
+            .unwrap()
+            .set_modified(epoch(3_000))
+            .unwrap();
+
+        assert_eq!(elf_files(&dir, true, None, None).unwrap().len(), 2);
+        assert_eq!(
+            elf_files(&dir, true, None, Some(2_000)).unwrap(),
+            slice::from_ref(&new)
+        );
+        assert_eq!(elf_files(&dir, true, None, Some(3_000)).unwrap(), [new]);
+    }
+
+    #[test]
+    fn test_single_file_path() {
+        let dir = TempDir::new("single");
+        let lib = dir.join("libfoo.so");
+        let sibling = dir.join("libbar.so");
+        let asset = &asset("pam_systemd_home.so");
+        fs::copy(asset, &lib).unwrap();
+        fs::copy(asset, &sibling).unwrap();
+        let patched = dir.join("patched");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--print0-patched"),
+            patched.as_os_str(),
+            OsStr::new("--paths"),
+            lib.as_os_str(),
+        ])
+        .unwrap();
+        auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+
+        let mut expected = lib.as_os_str().as_encoded_bytes().to_vec();
+        expected.push(0);
+        assert_eq!(fs::read(&patched).unwrap(), expected);
+        assert!(!lib.join(".auto-patchelf.state").exists());
+        let state = DirState::deserialize(&dir, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+        assert!(state.up_to_date("libfoo.so", lib.metadata().unwrap().mtime()));
+        assert!(!state.up_to_date("libbar.so", sibling.metadata().unwrap().mtime()));
+    }
+
+    #[test]
+    fn test_skip_soname() {
+        let dir = TempDir::new("skip");
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, dir.join("libpam.so.0")).unwrap();
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let patch = |extra_args: &[&str]| {
+            let mut args = vec![OsStr::new("--explain"), lib.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+        };
+        let mentions_libpam = |patch: &FilePatch| {
+            patch
+                .dependencies
+                .iter()
+                .any(|dep| dep.name == Path::new("libpam.so.0"))
+        };
+
+        let resolved = patch(&[]);
+        assert!(resolved.rpath.contains(&path_string(&dir)));
+        assert!(mentions_libpam(&resolved));
+
+        let skipped = patch(&["--skip-soname", "libpam.so.0", "libcrypt.so.2"]);
+        assert!(!skipped.rpath.contains(&path_string(&dir)));
+        assert!(!mentions_libpam(&skipped));
+        assert!(skipped
+            .dependencies
+            .iter()
+            .all(|dep| dep.name != Path::new("libcrypt.so.2")));
+    }
+
+    #[test]
+    fn test_host_libs() {
+        let dir = TempDir::new("host");
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, dir.join("libpam.so.0")).unwrap();
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let rpath = |rpath_host| {
+            let mut cli = Cli::parse_from([OsStr::new("--explain"), lib.as_os_str()]).unwrap();
+            // Stand in for what --host-libs reads from /etc/ld.so.conf
+            cli.patch.host_lib_dirs = vec![dir.to_path_buf()];
+            cli.patch.rpath_host = rpath_host;
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+            .rpath
+        };
+
+        assert!(!rpath(false).contains(&path_string(&dir)));
+        assert!(rpath(true).contains(&path_string(&dir)));
+        assert!(Cli::parse_from(["--rpath-host"]).is_err());
+    }
+
+    #[test]
+    fn test_parallel_roots() {
+        let dir = TempDir::new("jobs");
+        let roots: Vec<PathBuf> = (0..4).map(|root| dir.join(format!("root{root}"))).collect();
+        for root in &roots {
+            fs::create_dir_all(root.join("lib")).unwrap();
+            fs::copy(asset("pam_systemd_home.so"), root.join("lib/libfoo.so")).unwrap();
+        }
+        let patched = dir.join("patched");
+
+        let mut args = vec![
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--jobs"),
+            OsStr::new("3"),
+            OsStr::new("--print0-patched"),
+            patched.as_os_str(),
+            OsStr::new("--paths"),
+        ];
+        args.extend(roots.iter().map(|root| root.as_os_str()));
+        let cli = Cli::parse_from(args).unwrap();
+        assert_eq!(
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap(),
+            roots.len()
+        );
+
+        let mut expected = Vec::new();
+        for root in &roots {
+            let lib = root.join("lib/libfoo.so");
+            expected.extend_from_slice(lib.as_os_str().as_encoded_bytes());
+            expected.push(0);
+
+            let state = DirState::deserialize(root, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+            assert!(state.up_to_date("lib/libfoo.so", lib.metadata().unwrap().mtime()));
+        }
+        assert_eq!(fs::read(&patched).unwrap(), expected);
+        assert_eq!(
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap(),
+            0
+        );
+        assert!(Cli::parse_from(["--jobs", "2", "--reindex-after-patch"]).is_err());
+    }
+
+    #[test]
+    fn test_parallel_files_share_state() {
+        let dir = TempDir::new("jobs-state");
+        let libs = [
+            dir.copy_asset("pam_systemd_home.so", "liba.so"),
+            dir.copy_asset("pam_systemd_home.so", "libb.so"),
+        ];
+
+        let mut args = vec![
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--jobs"),
+            OsStr::new("2"),
+            OsStr::new("--paths"),
+        ];
+        args.extend(libs.iter().map(|lib| lib.as_os_str()));
+        let cli = Cli::parse_from(args).unwrap();
+        let run = || {
+            let mut patched = None;
+            output::capture(|| {
+                patched = Some(auto_patchelf(
+                    &cli,
+                    None,
+                    Path::new("/nonexistent/ld.so"),
+                    &[],
+                ));
+            });
+            patched.unwrap().unwrap()
+        };
+        assert_eq!(run(), 2);
+
+        let state = DirState::deserialize(&*dir, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+        for lib in &libs {
+            let name = lib.file_name().unwrap();
+            assert!(state.up_to_date(name, lib.metadata().unwrap().mtime()));
+        }
+        assert_eq!(run(), 0);
+    }
+
+    #[test]
+    fn test_repeat() {
+        let dir = TempDir::new("repeat");
+        dir.copy_asset("pam_systemd_home.so", "libfoo.so");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--repeat"),
+            OsStr::new("2"),
+            OsStr::new("--paths"),
+            dir.as_os_str(),
+        ])
+        .unwrap();
+        let output = output::capture(|| {
+            repeat_auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+        });
+
+        assert!(output.contains("iteration 1/2 patched 1 files"), "{output}");
+        assert!(output.contains("iteration 2/2 patched 0 files"), "{output}");
+        assert!(Cli::parse_from(["--repeat", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_touch_state_marks_files_up_to_date() {
+        let dir = TempDir::new("touch");
+        let lib = dir.join("lib/pam_systemd_home.so");
+        fs::create_dir_all(lib.parent().unwrap()).unwrap();
+        fs::copy(asset("pam_systemd_home.so"), &lib).unwrap();
+        fs::write(dir.join("lib/not-an-elf.so"), "").unwrap();
+
+        touch_state(&dir, true, None, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+
+        let state = DirState::deserialize(&dir, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+        let mtime = lib.metadata().unwrap().mtime();
+        assert!(state.up_to_date("lib/pam_systemd_home.so", mtime));
+        assert!(!state.up_to_date("lib/not-an-elf.so", 0));
+    }
+
+    #[test]
+    fn test_dynamic_linker_is_not_resolved() {
+        let lib = &asset("pam_systemd_home.so");
+        let interpreter_path = Path::new("/nix/store/glibc/lib/ld-linux-x86-64.so.2");
+        assert!(is_dynamic_linker(
+            Path::new("ld-linux-x86-64.so.2"),
+            interpreter_path
+        ));
+        assert!(!is_dynamic_linker(
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+            interpreter_path
+        ));
+
+        let cli = Cli::parse_from([OsStr::new("--explain"), lib.as_os_str()]).unwrap();
+        let library_cache = SharedHandle::ready(LibraryCache::new(vec!["so".into()]));
+        let dependencies =
+            auto_patchelf_file(&cli.patch, lib, &library_cache, interpreter_path, None, &[])
+                .unwrap()
+                .dependencies;
+
+        assert!(dependencies
+            .iter()
+            .any(|dep| dep.name == Path::new("libc.so.6")));
+        assert!(!dependencies
+            .iter()
+            .any(|dep| dep.name == Path::new("ld-linux-x86-64.so.2")));
+    }
+
+    #[test]
+    fn test_dlopen_priority() {
+        let lib = &asset("pam_systemd_home.so");
+        // All dlopen dependencies of the file are suggested
+        let resolve = |extra_args: &[&str]| {
+            let (patch, output) = explain_output(lib, extra_args);
+            let idn_missing = patch
+                .dependencies
+                .iter()
+                .any(|dep| dep.found.is_none() && dep.name == Path::new("libidn2.so.0"));
+            (idn_missing, output)
+        };
+        let warning =
+            "warn: auto-patchelf could not satisfy suggested dlopen dependency libidn2.so.0";
+
+        let (idn_missing, output) = resolve(&[]);
+        assert!(!idn_missing);
+        assert!(output.contains(warning), "{output}");
+
+        let (idn_missing, output) = resolve(&["--dlopen-priority", "suggested=fail"]);
+        assert!(idn_missing);
+        assert!(!output.contains(warning));
+
+        let (idn_missing, output) = resolve(&["--dlopen-priority", "suggested=ignore"]);
+        assert!(!idn_missing);
+        assert!(!output.contains(warning));
+        assert!(output.contains(" libidn2.so.0 -> not found, ignoring suggested dlopen dependency"));
+
+        // DT_NEEDED entries have no priority and always fail
+        let (_, output) = resolve(&["--dlopen-priority", "required=ignore"]);
+        assert!(output.contains(" libpam.so.0 -> not found!"));
+
+        let (idn_missing, output) =
+            resolve(&["--dlopen-priority", "suggested=fail", "--dlopen-optional"]);
+        assert!(!idn_missing);
+        assert!(output.contains(warning));
+        assert!(output.contains(" libpam.so.0 -> not found!"));
+    }
+
+    #[test]
+    fn test_no_rpath_for_dlopen() {
+        let dir = TempDir::new("dlopen");
+        let needed = dir.join("needed");
+        let dlopen = dir.join("dlopen");
+        fs::create_dir_all(&needed).unwrap();
+        fs::create_dir_all(&dlopen).unwrap();
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, needed.join("libpam.so.0")).unwrap();
+        fs::copy(lib, dlopen.join("libcryptsetup.so.12")).unwrap();
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[needed.clone(), dlopen.clone()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let resolve = |extra_args: &[&str]| {
+            let mut args = vec![OsStr::new("--explain"), lib.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+        };
+        let found = |patch: &FilePatch| {
+            patch
+                .dependencies
+                .iter()
+                .filter_map(|dep| dep.found.clone())
+                .collect::<Vec<_>>()
+        };
+        let has_dir = |patch: &FilePatch, dir: &Path| {
+            patch
+                .rpath
+                .contains(&dir.canonicalize().unwrap().display().to_string())
+        };
+
+        let patch = resolve(&[]);
+        assert!(has_dir(&patch, &needed));
+        assert!(has_dir(&patch, &dlopen));
+
+        let patch_without_dlopen = resolve(&["--no-rpath-for-dlopen"]);
+        assert!(has_dir(&patch_without_dlopen, &needed));
+        assert!(!has_dir(&patch_without_dlopen, &dlopen));
+        assert_eq!(found(&patch_without_dlopen), found(&patch));
+    }
+
+    #[test]
+    fn test_check_cfi() {
+        let dir = TempDir::new("cfi");
+        let binary = asset("libcfi.so");
+        let cli = Cli::parse_from([
+            OsStr::new("--explain"),
+            binary.as_os_str(),
+            OsStr::new("--check-cfi"),
+        ])
+        .unwrap();
+
+        let check = |dependency: &str| {
+            fs::copy(asset(dependency), dir.join("libnocfi.so")).unwrap();
+            let mut library_cache = LibraryCache::new(vec!["so".into()]);
+            library_cache
+                .populate_cache(&[dir.to_path_buf()], false)
+                .unwrap();
+            output::capture(|| {
+                let patch = patch_file(
+                    &cli.patch,
+                    &binary,
+                    &SharedHandle::ready(library_cache),
+                    Path::new("/lib64/ld-linux-x86-64.so.2"),
+                    None,
+                    &[],
+                )
+                .unwrap();
+                assert!(patch.dependencies[0].found.is_some());
+            })
+        };
+
+        let output = check("libnocfi.so");
+        assert!(output.contains("uses IBT, SHSTK, but its dependency"));
+
+        // A library providing the same features is fine
+        let output = check("libcfi.so");
+        assert!(!output.contains("does not support"));
+    }
+
+    #[test]
+    fn test_no_dedup_append() {
+        let dir = TempDir::new("append");
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, dir.join("libpam.so.0")).unwrap();
+        let resolved = path_string(dir.canonicalize().unwrap());
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let rpath = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--explain"),
+                lib.as_os_str(),
+                OsStr::new("--append-rpaths"),
+                OsStr::new(&resolved),
+                OsStr::new("/extra"),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+            .rpath
+        };
+
+        let deduped = rpath(&[]);
+        assert_eq!(deduped.iter().filter(|dir| **dir == resolved).count(), 1);
+
+        let verbatim = rpath(&["--no-dedup-append"]);
+        assert_eq!(verbatim.iter().filter(|dir| **dir == resolved).count(), 2);
+        assert_eq!(
+            verbatim[verbatim.len() - 2..],
+            [resolved.as_str(), "/extra"]
+        );
+    }
+
+    #[test]
+    fn test_preserve_rpath() {
+        let dir = TempDir::new("preserve");
+        let bin = dir.join("out/bin");
+        let lib = dir.join("out/lib");
+        fs::create_dir_all(&bin).unwrap();
+        fs::create_dir_all(&lib).unwrap();
+        let binary = bin.join("liborigin.so");
+        fs::copy(asset("liborigin.so"), &binary).unwrap();
+        fs::copy(asset("libnocfi.so"), lib.join("libdep.so")).unwrap();
+
+        let patch = |extra_args: &[&str]| {
+            let mut args = vec![OsStr::new("--explain"), binary.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                &binary,
+                &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+        };
+
+        let replaced = patch(&[]);
+        assert_eq!(replaced.dependencies[0].found, None);
+        assert!(replaced.rpath.is_empty());
+
+        let preserved = patch(&["--preserve-rpath"]);
+        assert_eq!(preserved.rpath, ["$ORIGIN/../lib"]);
+        assert_eq!(
+            preserved.dependencies[0].found,
+            Some(bin.join("../lib/libdep.so"))
+        );
+    }
+
+    #[test]
+    fn test_no_default_lib() {
+        let libc_lib = TempDir::new("nodeflib");
+        fs::write(libc_lib.join("libc.so.6"), "").unwrap();
+        let lib = &asset("pam_systemd_home.so");
+
+        let library_cache = SharedHandle::ready(LibraryCache::new(vec!["so".into()]));
+        let resolve = |extra_args: &[&str]| {
+            let mut args = vec![OsStr::new("--explain"), lib.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[libc_lib.to_path_buf()],
+            )
+            .unwrap()
+        };
+
+        let patch = resolve(&[]);
+        assert!(patch.rpath.is_empty());
+        assert!(!patch
+            .dependencies
+            .iter()
+            .any(|dep| dep.name == Path::new("libc.so.6")));
+
+        let patch = resolve(&["--no-default-lib"]);
+        assert_eq!(patch.rpath, [libc_lib.display().to_string()]);
+        let libc = patch
+            .dependencies
+            .iter()
+            .find(|dep| dep.name == Path::new("libc.so.6"))
+            .unwrap();
+        assert_eq!(libc.found, Some(libc_lib.join("libc.so.6")));
+    }
+
+    #[test]
+    fn test_confidence() {
+        assert_eq!(Confidence::of_found("libfoo.so.1", false), Confidence::High);
+        assert_eq!(
+            Confidence::of_found("libfoo.so.1.2.3", false),
+            Confidence::High
+        );
+        assert_eq!(Confidence::of_found("libfoo.so", false), Confidence::Medium);
+        assert_eq!(
+            Confidence::of_found("addon.node", false),
+            Confidence::Medium
+        );
+        assert_eq!(
+            Confidence::of_found("libfoo.so.git", false),
+            Confidence::Medium
+        );
+        assert_eq!(Confidence::of_found("libfoo.so.1", true), Confidence::Low);
+        assert_eq!(Confidence::of_found("libfoo.so", true), Confidence::Low);
+        assert_eq!(Confidence::LibcDefault.as_str(), "libc-default");
+
+        let dir = TempDir::new("confidence");
+        let libc_lib = dir.join("libc");
+        fs::create_dir_all(&libc_lib).unwrap();
+        fs::write(libc_lib.join("libc.so.6"), "").unwrap();
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, dir.join("libpam.so.0")).unwrap();
+        fs::copy(lib, dir.join("libcap-ng.so.0")).unwrap();
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let patch = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--explain"),
+                lib.as_os_str(),
+                OsStr::new("--soname-alias"),
+                OsStr::new("libcap.so.2=libcap-ng.so.0"),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            let mut patch = None;
+            let output = output::capture(|| {
+                patch = Some(
+                    auto_patchelf_file(
+                        &cli.patch,
+                        lib,
+                        &library_cache,
+                        Path::new("/lib64/ld-linux-x86-64.so.2"),
+                        None,
+                        slice::from_ref(&libc_lib),
+                    )
+                    .unwrap(),
+                );
+            });
+            (patch.unwrap(), output)
+        };
+        let confidence = |patch: &FilePatch, dependency: &str| {
+            patch
+                .dependencies
+                .iter()
+                .find(|dep| dep.name == Path::new(dependency))
+                .and_then(|dep| dep.confidence)
+        };
+
+        let (file_patch, output) = patch(&[]);
+        let line = |dependency: &str| {
+            output
+                .lines()
+                .find(|line| line.starts_with(&format!(" {dependency} -> ")))
+                .unwrap()
+        };
+        assert!(line("libc.so.6").ends_with("provided by libc, left to the dynamic linker"));
+        assert!(!line("libpam.so.0").ends_with("(low confidence)"));
+        assert!(line("libcap.so.2").ends_with("(low confidence)"));
+        assert_eq!(
+            confidence(&file_patch, "libpam.so.0"),
+            Some(Confidence::High)
+        );
+        assert_eq!(
+            confidence(&file_patch, "libcap.so.2"),
+            Some(Confidence::Low)
+        );
+
+        let (file_patch, _) = patch(&["--no-default-lib"]);
+        assert_eq!(
+            confidence(&file_patch, "libc.so.6"),
+            Some(Confidence::LibcDefault)
+        );
+    }
+
+    #[test]
+    fn test_keep_interpreter_if_valid() {
+        let content = read_file(asset("hybrid")).unwrap();
+        let elf = ElfFile::new(&content).unwrap();
+        assert_eq!(
+            elf.get_interpreter(),
+            Some(Path::new("/lib64/ld-linux-x86-64.so.2"))
+        );
+
+        let dir = TempDir::new("keep-interp");
+        let interpreter = dir.join("ld.so");
+        fs::copy(asset("pam_systemd_home.so"), &interpreter).unwrap();
+        let not_elf = dir.join("ld-script.so");
+        fs::write(&not_elf, "GROUP ( libc.so.6 )").unwrap();
+
+        assert!(is_valid_interpreter(&interpreter, elf.get_arch()));
+        assert!(!is_valid_interpreter(&interpreter, header::EM_AARCH64));
+        assert!(!is_valid_interpreter(&not_elf, elf.get_arch()));
+        assert!(!is_valid_interpreter(&dir.join("missing"), elf.get_arch()));
+
+        // Point PT_INTERP of a copy of the binary to a valid interpreter,
+        // its path has to fit in place of the original one
+        let original = b"/lib64/ld-linux-x86-64.so.2";
+        let short_interpreter = env::temp_dir().join(format!("ap-ld-{}", std::process::id()));
+        fs::copy(&interpreter, &short_interpreter).unwrap();
+        let replacement = short_interpreter.as_os_str().as_encoded_bytes();
+        if replacement.len() <= original.len() {
+            let offset = content
+                .windows(original.len())
+                .position(|window| window == original)
+                .unwrap();
+            let mut patched = content.clone();
+            patched[offset..offset + original.len()].fill(0);
+            patched[offset..offset + replacement.len()].copy_from_slice(replacement);
+            let binary = dir.join("hybrid");
+            fs::write(&binary, patched).unwrap();
+
+            let cli = Cli::parse_from([
+                OsStr::new("--explain"),
+                binary.as_os_str(),
+                OsStr::new("--keep-interpreter-if-valid"),
+            ])
+            .unwrap();
+            let patch = auto_patchelf_file(
+                &cli.patch,
+                &binary,
+                &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                Path::new("/nix/store/glibc/lib/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap();
+            assert!(!patch.interpreter);
+
+            let cli = Cli::parse_from([OsStr::new("--explain"), binary.as_os_str()]).unwrap();
+            let patch = auto_patchelf_file(
+                &cli.patch,
+                &binary,
+                &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                Path::new("/nix/store/glibc/lib/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap();
+            assert!(patch.interpreter);
+        }
+
+        fs::remove_file(&short_interpreter).unwrap();
+    }
+
+    /// Explains how `file` is patched, returns the patch and the output
+    fn explain_output(file: &Path, extra_args: &[&str]) -> (FilePatch, String) {
+        explain_output_with_libc(file, extra_args, &[])
+    }
+
+    fn explain_output_with_libc(
+        file: &Path,
+        extra_args: &[&str],
+        libc_libs: &[PathBuf],
+    ) -> (FilePatch, String) {
+        let mut args = vec![OsStr::new("--explain"), file.as_os_str()];
+        args.extend(extra_args.iter().map(OsStr::new));
+        let cli = Cli::parse_from(args).unwrap();
+        let mut patch = None;
+        let output = output::capture(|| {
+            patch = Some(
+                patch_file(
+                    &cli.patch,
+                    file,
+                    &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                    Path::new("/lib64/ld-linux-x86-64.so.2"),
+                    None,
+                    libc_libs,
+                )
+                .unwrap(),
+            )
+        });
+        (patch.unwrap(), output)
+    }
+
+    #[test]
+    fn test_fast_stubs() {
+        let dir = TempDir::new("stubs");
+        let libc = dir.join("libc");
+        fs::create_dir_all(&libc).unwrap();
+        fs::write(libc.join("libc.so.6"), "").unwrap();
+
+        let patch = |file: &str| {
+            explain_output_with_libc(&asset(file), &["--fast-stubs"], slice::from_ref(&libc))
+        };
+
+        let (stub, output) = patch("stub");
+        assert!(stub.interpreter);
+        assert!(output.contains("launcher stub"));
+        assert!(!output.contains("searching for dependencies"));
+
+        let (normal, output) = patch("hybrid");
+        assert!(normal.interpreter);
+        assert!(!output.contains("launcher stub"));
+        assert!(output.contains("libc.so.6 -> provided by libc"));
+    }
+
+    #[test]
+    fn test_min_kernel() {
+        let warns = |file: &str, min_kernel: &str| {
+            let (_, output) = explain_output(&asset(file), &["--min-kernel", min_kernel]);
+            output.contains("requires Linux")
+        };
+
+        assert!(!warns("stub", "5.10"));
+        assert!(warns("stub-linux6", "5.10"));
+        assert!(!warns("stub-linux6", "6.1"));
+    }
+
+    #[test]
+    fn test_interpreter_relative_check() {
+        let binary = &asset("hybrid");
+        let cli = Cli::parse_from([
+            OsStr::new("--explain"),
+            binary.as_os_str(),
+            OsStr::new("--interpreter-relative-check"),
+        ])
+        .unwrap();
+        let warns = |interpreter_path: &str| {
+            output::capture(|| {
+                patch_file(
+                    &cli.patch,
+                    binary,
+                    &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                    Path::new(interpreter_path),
+                    None,
+                    &[],
+                )
+                .unwrap();
+            })
+            .contains("cannot be relocated")
+        };
+
+        assert!(warns("/nix/store/glibc/lib/ld-linux-x86-64.so.2"));
+        assert!(!warns("/lib64/ld-linux-x86-64.so.2"));
+    }
+
+    #[test]
+    fn test_corrupt_interpreter() {
+        let warning = |file: &str| {
+            let (patch, output) = explain_output(&asset(file), &[]);
+            assert!(patch.interpreter);
+            output
+                .lines()
+                .find(|line| line.contains("PT_INTERP"))
+                .map(str::to_string)
+        };
+
+        assert_eq!(warning("stub"), None);
+        assert!(warning("stub-empty-interp")
+            .unwrap()
+            .ends_with("is empty, the input may be broken"));
+    }
+
+    #[test]
+    fn test_size_window() {
+        let binary = &asset("stub");
+        let skipped = |extra_args: &[&str]| {
+            let (patch, output) = explain_output(binary, extra_args);
+            let skipped = output
+                .lines()
+                .find(|line| line.contains("skipping"))
+                .map(str::to_string);
+            assert_eq!(skipped.is_some(), !patch.interpreter);
+            skipped
+        };
+        let size = binary.metadata().unwrap().len().to_string();
+
+        assert_eq!(skipped(&["--max-size", &size, "--min-size", &size]), None);
+        assert_eq!(
+            skipped(&["--max-size", "4096"]).unwrap(),
+            format!(
+                "warn: skipping {} because it is larger than --max-size ({size} > 4096 bytes)",
+                binary.display()
+            )
+        );
+        assert_eq!(
+            skipped(&["--min-size", "1000000"]).unwrap(),
+            format!(
+                "skipping {} because it is smaller than --min-size ({size} < 1000000 bytes)",
+                binary.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_zero_entry() {
+        let warning = |file: &str| {
+            let (_, output) = explain_output(&asset(file), &[]);
+            output
+                .lines()
+                .find(|line| line.contains("without an entry point"))
+                .map(str::to_string)
+        };
+
+        assert_eq!(warning("stub"), None);
+        assert!(warning("stub-zero-entry").unwrap().starts_with("warn: "));
+    }
+
+    #[test]
+    fn test_commands_log() {
+        let binary = &asset("stub");
+        let dir = TempDir::new("commands");
+        let log = dir.join("commands.json");
+        let cli = Cli::parse_from([
+            OsStr::new("--explain"),
+            binary.as_os_str(),
+            OsStr::new("--commands-log"),
+            log.as_os_str(),
+        ])
+        .unwrap();
+        explain_file(
+            &cli,
+            binary,
+            None,
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+            &[],
+        )
+        .unwrap();
+
+        let commands: Value = json::from_str(&fs::read_to_string(&log).unwrap()).unwrap();
+        let Value::Array(commands) = commands else {
+            panic!("{commands:?} is not a list");
+        };
+        let [Value::Object(command)] = &commands[..] else {
+            panic!("{commands:?} is not a single command");
+        };
+        let string = |value: &Value| match value {
+            Value::String(string) => string.clone(),
+            _ => panic!("{value:?} is not a string"),
+        };
+        assert_eq!(
+            string(&command["directory"]),
+            env::current_dir().unwrap().display().to_string()
+        );
+        assert_eq!(string(&command["file"]), binary.display().to_string());
+        let Value::Array(arguments) = &command["arguments"] else {
+            panic!("{command:?} has no arguments");
+        };
+        assert_eq!(
+            arguments.iter().map(string).collect::<Vec<_>>(),
+            [
+                "patchelf",
+                "--set-interpreter",
+                "/lib64/ld-linux-x86-64.so.2",
+                &binary.display().to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_dynamic() {
+        let log = |file: &str| {
+            let (_, output) = explain_output(&asset(file), &[]);
+            output.contains("with an empty dynamic section")
+        };
+
+        assert!(!log("stub"));
+        assert!(log("stub-empty-dynamic"));
+    }
+
+    #[test]
+    fn test_soname_alias() {
+        let dir = TempDir::new("alias");
+        let hybrid = dir.join("libhybrid.so.1");
+        fs::copy(asset("hybrid"), &hybrid).unwrap();
+        let lib = &asset("pam_systemd_home.so");
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let resolve = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--explain"),
+                lib.as_os_str(),
+                OsStr::new("--soname-alias"),
+                OsStr::new("libpam.so.0=libhybrid.so.1"),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            let dependencies = auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap()
+            .dependencies;
+            (needed_replacements(&cli.patch, &dependencies), dependencies)
+        };
+
+        // Lookup only
+        let (replacements, dependencies) = resolve(&[]);
+        let pam = dependencies
+            .iter()
+            .find(|dep| dep.name == Path::new("libpam.so.0"))
+            .unwrap();
+        assert_eq!(pam.found, Some(hybrid.canonicalize().unwrap()));
+        assert!(replacements.is_empty());
+
+        // Rewriting DT_NEEDED too
+        let (replacements, _) = resolve(&["--rewrite-needed"]);
+        assert_eq!(
+            replacements,
+            ["--replace-needed", "libpam.so.0", "libhybrid.so.1"]
+        );
+    }
+
+    #[test]
+    fn test_replace_needed_without_rpath() {
+        let dir = TempDir::new("alias-host");
+        fs::copy(asset("hybrid"), dir.join("libhybrid.so.1")).unwrap();
+        let lib = &asset("pam_systemd_home.so");
+
+        let mut library_cache = LibraryCache::new(vec!["so".into()]);
+        library_cache
+            .populate_cache(&[dir.to_path_buf()], false)
+            .unwrap();
+        let library_cache = SharedHandle::ready(library_cache);
+        let patch = |first_arg: &str| {
+            let mut cli = Cli::parse_from([
+                OsStr::new(first_arg),
+                lib.as_os_str(),
+                OsStr::new("--patchelf"),
+                OsStr::new("false"),
+                OsStr::new("--soname-alias"),
+                OsStr::new("libpam.so.0=libhybrid.so.1"),
+                OsStr::new("--rewrite-needed"),
+            ])
+            .unwrap();
+            // Found on the host, so that the RPATH stays empty
+            cli.patch.host_lib_dirs = vec![dir.to_path_buf()];
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+        };
+
+        let patch_result = patch("--explain").unwrap();
+        assert!(patch_result.rpath.is_empty());
+        let arguments: Vec<_> = patch_result.commands[0]
+            .iter()
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+        assert_eq!(patch_result.commands.len(), 1);
+        assert_eq!(
+            arguments,
+            [
+                "false",
+                "--replace-needed",
+                "libpam.so.0",
+                "libhybrid.so.1",
+                lib.to_str().unwrap()
+            ]
+        );
+
+        let err = patch("--paths").unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "Failed to replace DT_NEEDED entries of {}",
+            lib.display()
+        )));
+    }
+
+    #[test]
+    fn test_fail_invalid() {
+        let lib = &asset("libcorrupt.so");
+        let library_cache = SharedHandle::ready(LibraryCache::new(vec!["so".into()]));
+        let patch = |flag: &str| {
+            let cli = Cli::parse_from([OsStr::new("--explain"), lib.as_os_str(), OsStr::new(flag)])
+                .unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &library_cache,
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+        };
+
+        assert!(patch("--validate").is_ok());
+        let err = patch("--fail-invalid").unwrap_err();
+        assert!(err.to_string().contains("DT_NEEDED offset"));
+    }
+
+    #[test]
+    fn test_relocatable_is_skipped() {
+        let object = &asset("relocatable.o");
+        let content = read_file(object).unwrap();
+        assert!(ElfFile::new(&content).unwrap().is_relocatable());
+
+        let cli = Cli::parse_from([OsStr::new("--explain"), object.as_os_str()]).unwrap();
+        let library_cache = SharedHandle::ready(LibraryCache::new(vec!["so".into()]));
+        let dependencies = auto_patchelf_file(
+            &cli.patch,
+            object,
+            &library_cache,
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+            None,
+            &[],
+        )
+        .unwrap()
+        .dependencies;
+        assert!(dependencies.is_empty());
+    }
+
+    #[test]
+    fn test_shared_object_with_segments_is_patched() {
+        let lib = &asset("pam_systemd_home.so");
+        let cli = Cli::parse_from([OsStr::new("--explain"), lib.as_os_str()]).unwrap();
+        let output = output::capture(|| {
+            let patch = patch_file(
+                &cli.patch,
+                lib,
+                &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap();
+            assert!(!patch.dependencies.is_empty());
+        });
+        assert!(!output.contains("contains no segments"));
+    }
+
+    #[test]
+    fn test_static_pie_is_skipped() {
+        let binary = &asset("static-pie-relr");
+        let cli = Cli::parse_from([OsStr::new("--explain"), binary.as_os_str()]).unwrap();
+        let output = output::capture(|| {
+            let patch = patch_file(
+                &cli.patch,
+                binary,
+                &SharedHandle::ready(LibraryCache::new(vec!["so".into()])),
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap();
+            assert!(!patch.interpreter);
+            assert!(patch.commands.is_empty());
+        });
+        assert!(output.contains("because it is a static PIE with RELR relocations"));
+    }
+
+    #[test]
+    fn test_plan_hash_is_canonical() {
+        let patch = |interpreter, rpath: &[&str]| FilePatch {
+            interpreter,
+            rpath: rpath.iter().map(|p| p.to_string()).collect(),
+            ..FilePatch::default()
+        };
+        let ld = Path::new("/lib64/ld-linux-x86-64.so.2");
+
+        let bin = plan_entry(Path::new("bin/foo"), ld, &patch(true, &["/b", "/a"]));
+        let lib = plan_entry(Path::new("lib/libfoo.so"), ld, &patch(false, &[]));
+        assert_eq!(bin, "bin/foo\t/lib64/ld-linux-x86-64.so.2\t/a:/b");
+        assert_eq!(lib, "lib/libfoo.so\t-\t");
+        assert_eq!(
+            plan_entry(Path::new("bin/foo"), ld, &patch(true, &["/a", "/b"])),
+            bin
+        );
+
+        assert_eq!(
+            plan_hash(vec![bin.clone(), lib.clone()]),
+            plan_hash(vec![lib.clone(), bin.clone()])
+        );
+        assert_ne!(plan_hash(vec![bin]), plan_hash(vec![lib]));
+    }
+
+    #[test]
+    fn test_unsatisfied_interpreter_deps() {
+        let content = read_file(asset("pam_systemd_home.so")).unwrap();
+        let interpreter = ElfFile::new(&content).unwrap();
+        let library_cache = LibraryCache::new(vec!["so".into()]);
+
+        let notes = [elf::DLOPEN_NOTE.to_string()];
+        let unsatisfied = unsatisfied_interpreter_deps(&interpreter, &library_cache, &[], &notes);
+        assert!(unsatisfied.contains(&PathBuf::from("libpam.so.0")));
+        assert!(unsatisfied.contains(&PathBuf::from("libc.so.6")));
+
+        let content = read_file(asset("libvendornote.so")).unwrap();
+        let interpreter = ElfFile::new(&content).unwrap();
+        assert!(
+            unsatisfied_interpreter_deps(&interpreter, &library_cache, &[], &notes,).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_libc_dirs() {
+        let dir = TempDir::new("orig-libc");
+        let glibc = dir.join("glibc");
+        let libm = dir.join("glibc-libm");
+        fs::create_dir_all(glibc.join("lib")).unwrap();
+        fs::create_dir_all(libm.join("lib")).unwrap();
+        fs::write(glibc.join("lib/libc.so.6"), "").unwrap();
+        fs::write(libm.join("lib/libm.so.6"), "").unwrap();
+        let orig_libc = dir.join("orig-libc");
+        fs::write(
+            &orig_libc,
+            format!(
+                "{}\n{} {}\n",
+                glibc.display(),
+                dir.join("missing").display(),
+                libm.display()
+            ),
+        )
+        .unwrap();
+
+        let libc_libs = libc_dirs(&fs::read_to_string(&orig_libc).unwrap());
+        assert_eq!(libc_libs, [glibc.join("lib"), libm.join("lib")]);
+        assert_eq!(
+            find_libc(&libc_libs, Path::new("libc.so.6")),
+            Some(glibc.join("lib/libc.so.6"))
+        );
+        assert_eq!(
+            find_libc(&libc_libs, Path::new("libm.so.6")),
+            Some(libm.join("lib/libm.so.6"))
+        );
+        assert_eq!(find_libc(&libc_libs, Path::new("libpam.so.0")), None);
+    }
+
+    #[test]
+    fn test_no_libc_skip() {
+        let dir = TempDir::new("libc");
+        let libc_lib = dir.join("libc/lib");
+        fs::create_dir_all(&libc_lib).unwrap();
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, libc_lib.join("libc.so.6")).unwrap();
+
+        let resolve = |extra_args: &[&str], libs: &[PathBuf]| {
+            let mut library_cache = LibraryCache::new(vec!["so".into()]);
+            library_cache.populate_cache(libs, false).unwrap();
+            let mut args = vec![OsStr::new("--explain"), lib.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf_file(
+                &cli.patch,
+                lib,
+                &SharedHandle::ready(library_cache),
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                slice::from_ref(&libc_lib),
+            )
+            .unwrap()
+        };
+        let libc = |patch: &FilePatch| {
+            patch
+                .dependencies
+                .iter()
+                .find(|dep| dep.name == Path::new("libc.so.6"))
+                .map(|dep| dep.found.clone())
+        };
+
+        // Left to the dynamic linker by default
+        assert_eq!(libc(&resolve(&[], &[])), None);
+
+        // Has to be found in the library set
+        assert_eq!(libc(&resolve(&["--no-libc-skip"], &[])), Some(None));
+        let patch = resolve(&["--no-libc-skip"], slice::from_ref(&libc_lib));
+        assert_eq!(
+            libc(&patch),
+            Some(Some(libc_lib.join("libc.so.6").canonicalize().unwrap()))
+        );
+        assert!(patch
+            .rpath
+            .contains(&path_string(libc_lib.canonicalize().unwrap())));
+    }
+
+    #[test]
+    fn test_metadata_drift() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("drift");
+        let path = dir.join("file");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let before = fs::metadata(&path).unwrap();
+
+        fs::write(&path, "rewritten").unwrap();
+        assert!(metadata_drift(&before, &fs::metadata(&path).unwrap()).is_empty());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            metadata_drift(&before, &fs::metadata(&path).unwrap()),
+            ["mode"]
+        );
+
+        // Only root can hand files to others
+        if std::os::unix::fs::chown(&path, Some(before.uid() + 1), Some(before.gid() + 1)).is_ok() {
+            assert_eq!(
+                metadata_drift(&before, &fs::metadata(&path).unwrap()),
+                ["mode", "owner", "group"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_patch_keeps_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("keep-mode");
+        let out = dir.join("out");
+        let lib = dir.copy_asset("pam_systemd_home.so", "out/libfoo.so");
+        let libpam = dir.copy_asset("pam_systemd_home.so", "deps/libpam.so.0");
+        let patchelf = dir.join("patchelf");
+        // Only root can hand files to others, the owner is kept otherwise
+        let metadata = lib.metadata().unwrap();
+        let owner = (metadata.uid() + 1, metadata.gid() + 1);
+        let chowned = std::os::unix::fs::chown(&lib, Some(owner.0), Some(owner.1)).is_ok();
+        let owner = if chowned {
+            owner
+        } else {
+            (metadata.uid(), metadata.gid())
+        };
+        let patch = |script: &str| {
+            fs::set_permissions(&lib, fs::Permissions::from_mode(0o750)).unwrap();
+            fs::remove_file(out.join(".auto-patchelf.state")).ok();
+            fs::write(
+                &patchelf,
+                format!(
+                    "#!/bin/sh\n[ \"$1\" = --version ] && exec echo patchelf 0.18.0\n\
+                     for file; do :; done\n{script}\n"
+                ),
+            )
+            .unwrap();
+            fs::set_permissions(&patchelf, fs::Permissions::from_mode(0o755)).unwrap();
+            let cli = Cli::parse_from([
+                OsStr::new("--patchelf"),
+                patchelf.as_os_str(),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--libs"),
+                libpam.parent().unwrap().as_os_str(),
+                OsStr::new("--paths"),
+                out.as_os_str(),
+            ])
+            .unwrap();
+            output::capture(|| {
+                auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+            })
+        };
+
+        // Rewriting the content in place, like patchelf does
+        let output = patch(
+            "cat \"$file\" > \"$file.orig\"\ncat \"$file.orig\" > \"$file\"\nrm \"$file.orig\"",
+        );
+        assert!(output.contains("setting RPATH"), "{output}");
+        assert!(!output.contains("changed while patching"), "{output}");
+        let metadata = lib.metadata().unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o750);
+        assert_eq!((metadata.uid(), metadata.gid()), owner);
+
+        // Replacing the file with a new one loses the mode and the owner
+        let output = patch("cat \"$file\" > \"$file.tmp\"\nmv \"$file.tmp\" \"$file\"");
+        let drift = if chowned {
+            "mode, owner, group"
+        } else {
+            "mode"
+        };
+        assert!(
+            output.contains(&format!(
+                "{drift} of {} changed while patching",
+                lib.display()
+            )),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn test_patchelf_is_checked() {
+        let err = check_patchelf(Path::new("/nonexistent/patchelf")).unwrap_err();
+        assert!(err.to_string().contains("--patchelf"));
+
+        let err = check_patchelf(Path::new("false")).unwrap_err();
+        assert!(err.to_string().contains("is it really patchelf?"));
+
+        let dir = TempDir::new("nopatchelf");
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("/nonexistent/patchelf"),
+            OsStr::new("--paths"),
+            dir.as_os_str(),
+        ])
+        .unwrap();
+        let err = auto_patchelf(&cli, None, Path::new("/ld.so"), &[]).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/patchelf"));
+        assert!(!dir.join(".auto-patchelf.state").exists());
+    }
+
+    #[test]
+    fn test_old_patchelf() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new("oldpatchelf");
+        let patchelf = dir.join("patchelf");
+        fs::write(&patchelf, "#!/bin/sh\necho patchelf 0.10.0\n").unwrap();
+        fs::set_permissions(&patchelf, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            check_patchelf(&patchelf).unwrap(),
+            Some(PatchelfVersion::new(0, 10))
+        );
+
+        let run = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                patchelf.as_os_str(),
+                OsStr::new("--paths"),
+                dir.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/ld.so"), &[])
+        };
+        let err = run(&["--extra-args=--add-rpath"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--add-rpath requires patchelf 0.14 or newer, but the detected version is 0.10"
+        );
+        let err = run(&["--clear-symbol-version", "foo"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("--clear-symbol-version requires patchelf 0.12"));
+        assert!(run(&["--no-default-lib"]).is_ok());
+    }
+
+    #[test]
+    fn test_empty_paths() {
+        let libc_libs: &[PathBuf] = &[];
+        let interpreter_path = Path::new("/nonexistent/ld.so");
+
+        let cli = Cli::parse_from(["--paths"]).unwrap();
+        let err = auto_patchelf(&cli, None, interpreter_path, libc_libs).unwrap_err();
+        assert_eq!(err.to_string(), "No paths to patch, stopping.");
+
+        let cli = Cli::parse_from(["--paths", "--allow-empty"]).unwrap();
+        auto_patchelf(&cli, None, interpreter_path, libc_libs).unwrap();
+    }
+
+    #[test]
+    fn test_prior_patching() {
+        let content = read_file(asset("libprepatched.so")).unwrap();
+        let elf = ElfFile::new(&content).unwrap();
+        let rpath = elf.get_rpath();
+        assert_eq!(rpath.len(), 2);
+        let store_dir = Path::new("/nix/store");
+        assert!(rpath
+            .iter()
+            .all(|entry| is_store_path(Path::new(entry), store_dir)));
+
+        // Recreate the referenced store paths under a temporary store
+        let store = TempDir::new("store");
+        let relocated: Vec<String> = rpath
+            .iter()
+            .map(|entry| entry.replacen("/nix/store", &store.display().to_string(), 1))
+            .collect();
+        let relocated: Vec<&str> = relocated.iter().map(String::as_str).collect();
+        assert!(!is_prepatched(&relocated, &store));
+        for entry in &relocated {
+            fs::create_dir_all(entry).unwrap();
+        }
+        assert!(is_prepatched(&relocated, &store));
+
+        let lib = env::temp_dir().display().to_string();
+        assert!(!is_prepatched(&[relocated[0], &lib], &store));
+        assert!(!is_prepatched(&[], &store));
+        let invalid = store.join("not-a-hash/lib");
+        fs::create_dir_all(&invalid).unwrap();
+        assert!(!is_prepatched(&[invalid.to_str().unwrap()], &store));
+    }
+
+    #[test]
+    fn test_expect_arch() {
+        let dir = TempDir::new("expect-arch");
+        let lib = dir.join("libforeign.so");
+        fs::copy(asset("pam_systemd_home.so"), &lib).unwrap();
+
+        let run = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--paths"),
+                dir.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            fs::remove_file(dir.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+        };
+
+        run(&["--expect-arch", "x86_64"]).unwrap();
+        let err = run(&["--expect-arch", "aarch64"]).unwrap_err().to_string();
+        assert!(err.contains("unexpected architectures"), "{err}");
+        assert!(err.contains(&lib.display().to_string()), "{err}");
+        run(&["--expect-arch", "aarch64", "--warn-unexpected-arch"]).unwrap();
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let dir = TempDir::new("max-errors");
+        let asset = &asset("pam_systemd_home.so");
+        for name in ["a.so", "b.so", "c.so"] {
+            fs::copy(asset, dir.join(name)).unwrap();
+        }
+
+        let run = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--paths"),
+                dir.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            fs::remove_file(dir.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+                .unwrap_err()
+                .to_string()
+        };
+
+        let err = run(&[]);
+        assert!(err.starts_with("auto-patchelf failed to find"), "{err}");
+        let err = run(&["--max-errors", "1"]);
+        assert!(err.starts_with("Aborting after "), "{err}");
+        assert!(err.contains("--max-errors 1"), "{err}");
+        let err = run(&["--max-errors", "1000"]);
+        assert!(err.starts_with("auto-patchelf failed to find"), "{err}");
+    }
+
+    #[test]
+    fn test_fail_on_warning() {
+        let dir = TempDir::new("fail-on-warning");
+        dir.copy_asset("pam_systemd_home.so", "a.so");
+        let run = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--paths"),
+                dir.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            fs::remove_file(dir.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            let mut result = None;
+            output::capture(|| {
+                result = Some(auto_patchelf(
+                    &cli,
+                    None,
+                    Path::new("/nonexistent/ld.so"),
+                    &[],
+                ));
+            });
+            result.unwrap()
+        };
+
+        assert_eq!(run(&[]).unwrap(), 1);
+        let err = run(&["--fail-on-warning"]).unwrap_err().to_string();
+        assert!(
+            err.contains("warnings and --fail-on-warning is set"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_watch() {
+        let dir = TempDir::new("watch");
+        let out = dir.join("out");
+        let libs = dir.join("libs");
+        fs::create_dir_all(&out).unwrap();
+        fs::create_dir_all(&libs).unwrap();
+        let asset = &asset("pam_systemd_home.so");
+        let lib = out.join("a.so");
+        fs::copy(asset, &lib).unwrap();
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--libs"),
+            libs.as_os_str(),
+            OsStr::new("--paths"),
+            out.as_os_str(),
+        ])
+        .unwrap();
+        let mut watcher = Watcher::new(&cli).unwrap();
+        let mut step = || {
+            watcher
+                .step(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+                .unwrap()
+        };
+        let touch = |secs| {
+            File::options()
+                .write(true)
+                .open(&lib)
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        // Changes are only picked up once the file is no longer written
+        assert!(step().is_empty());
+        assert_eq!(step(), slice::from_ref(&lib));
+        assert!(step().is_empty());
+
+        touch(1_000);
+        assert!(step().is_empty());
+        touch(2_000);
+        assert!(step().is_empty());
+        assert_eq!(step(), slice::from_ref(&lib));
+        assert!(step().is_empty());
+    }
+
+    #[test]
+    fn test_watch_files_share_state() {
+        let dir = TempDir::new("watch-shared");
+        let a = dir.copy_asset("pam_systemd_home.so", "out/a.so");
+        let b = dir.copy_asset("pam_systemd_home.so", "out/b.so");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--paths"),
+            a.as_os_str(),
+            b.as_os_str(),
+        ])
+        .unwrap();
+        let mut watcher = Watcher::new(&cli).unwrap();
+        assert_eq!(watcher.states.len(), 1);
+        let mut step = || {
+            watcher
+                .step(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+                .unwrap()
+        };
+        assert!(step().is_empty());
+        assert_eq!(step(), [a.clone(), b.clone()]);
+
+        let state = DirState::deserialize(dir.join("out"), cli.patch.state_size_limit).unwrap();
+        for file in [&a, &b] {
+            let mtime = file.metadata().unwrap().mtime();
+            assert!(state.up_to_date(Path::new(file.file_name().unwrap()), mtime));
+        }
+    }
+
+    #[test]
+    fn test_watch_libs_recursive() {
+        let dir = TempDir::new("watch-libs");
+        let out = dir.join("out");
+        let lib = dir.copy_asset("pam_systemd_home.so", "libs/sub/libpam.so.0");
+        fs::create_dir_all(&out).unwrap();
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--libs"),
+            dir.join("libs").as_os_str(),
+            OsStr::new("--libs-recursive"),
+            OsStr::new("--paths"),
+            out.as_os_str(),
+        ])
+        .unwrap();
+        let mut watcher = Watcher::new(&cli).unwrap();
+        let mut step = || {
+            output::capture(|| {
+                watcher
+                    .step(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+                    .unwrap();
+            })
+        };
+        assert_eq!(step(), "");
+
+        // Rewriting a library in place only changes its own modification time
+        File::options()
+            .write(true)
+            .open(&lib)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(1_000))
+            .unwrap();
+        assert!(step().contains("libraries changed, rebuilding the library cache"));
+        assert_eq!(step(), "");
+
+        dir.copy_asset("pam_systemd_home.so", "libs/sub/libfoo.so");
+        assert!(step().contains("libraries changed, rebuilding the library cache"));
+    }
+
+    #[test]
+    fn test_watch_reports_missing() {
+        let dir = TempDir::new("watch-missing");
+        let out = dir.join("out");
+        let lib = dir.copy_asset("pam_systemd_home.so", "out/a.so");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("libpam.so.0"),
+            OsStr::new("--paths"),
+            out.as_os_str(),
+        ])
+        .unwrap();
+        let mut watcher = Watcher::new(&cli).unwrap();
+        let mut step = || {
+            output::capture(|| {
+                watcher
+                    .step(&cli, None, Path::new("/nonexistent/ld.so"), &[])
+                    .unwrap();
+            })
+        };
+
+        assert_eq!(step(), "");
+        let output = step();
+        assert!(output.contains("auto-patchelf: 9 dependencies could not be satisfied"));
+        assert!(output.contains(&format!(
+            "warn: auto-patchelf ignoring missing libpam.so.0 wanted by {}",
+            lib.display()
+        )));
+        assert!(output.contains(&format!(
+            "could not satisfy dependency libc.so.6 wanted by {}",
+            lib.display()
+        )));
+        assert!(output.contains("failed to find all the required dependencies"));
+    }
+
+    #[test]
+    fn test_pc_dir() {
+        let dir = TempDir::new("pc");
+        let out = dir.join("out");
+        let dev = dir.join("dev/lib/pkgconfig");
+        let lib = dir.join("lib");
+        for sub in [&out, &dev, &lib] {
+            fs::create_dir_all(sub).unwrap();
+        }
+        let asset = &asset("pam_systemd_home.so");
+        fs::copy(asset, out.join("a.so")).unwrap();
+        fs::copy(asset, lib.join("libpam.so.0")).unwrap();
+        fs::write(
+            dev.join("pam.pc"),
+            format!("prefix={}\nlibdir=${{prefix}}/lib\n", dir.display()),
+        )
+        .unwrap();
+        fs::write(dev.join("broken.pc"), "libdir=${missing}/lib\n").unwrap();
+        let used_libs = dir.join("used-libs");
+
+        let used_libpam = |extra_args: &[&OsStr]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--used-libs"),
+                used_libs.as_os_str(),
+                OsStr::new("--paths"),
+                out.as_os_str(),
+            ];
+            args.extend(extra_args);
+            fs::remove_file(out.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+            read_lines(&used_libs)
+                .unwrap()
+                .iter()
+                .any(|used| used.ends_with("/lib/libpam.so.0"))
+        };
+
+        assert!(!used_libpam(&[]));
+        assert!(used_libpam(&[OsStr::new("--pc-dir"), dev.as_os_str()]));
+    }
+
+    #[test]
+    fn test_xattr() {
+        let dir = TempDir::new("xattr-run");
+        if xattr::set(&dir, "user.auto-patchelf.probe", b"")
+            .is_err_and(|err| err.kind() == std::io::ErrorKind::Unsupported)
+        {
+            return;
+        }
+        let binary = dir.join("stub");
+        fs::copy(asset("stub"), &binary).unwrap();
+
+        let patched = |extra_args: &[&str]| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--paths"),
+                dir.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(OsStr::new));
+            fs::remove_file(dir.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap()
+        };
+
+        assert_eq!(patched(&["--xattr"]), 1);
+        assert_eq!(xattr::get(&binary, RPATH_XATTR).unwrap(), Some(Vec::new()));
+        // Without its state file, the recorded RPATH shows that it is patched
+        assert_eq!(patched(&["--xattr"]), 0);
+        assert_eq!(patched(&[]), 1);
+    }
+
+    #[test]
+    fn test_explain_cache() {
+        let dir = TempDir::new("explain-cache");
+        let binary = dir.join("stub");
+        fs::copy(asset("stub"), &binary).unwrap();
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--explain-cache"),
+            OsStr::new("--paths"),
+            dir.as_os_str(),
+        ])
+        .unwrap();
+        let explanation = || {
+            let output = output::capture(|| {
+                auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+            });
+            output
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{}: ", binary.display())))
+                .unwrap()
+                .to_string()
+        };
+
+        assert_eq!(explanation(), "processing (not in cache)");
+        assert_eq!(explanation(), "skipped (cached, mtime matches)");
+
+        let mtime = binary.metadata().unwrap().mtime();
+        File::options()
+            .write(true)
+            .open(&binary)
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH + Duration::from_secs(mtime as u64 + 10))
+            .unwrap();
+        assert_eq!(
+            explanation(),
+            format!("processing (mtime changed from {mtime} to {})", mtime + 10)
+        );
+    }
+
+    #[test]
+    fn test_sibling_outputs() {
+        let dir = TempDir::new("siblings");
+        let out = dir.join("out/lib");
+        let sibling = dir.join("lib/lib");
+        fs::create_dir_all(&out).unwrap();
+        fs::create_dir_all(&sibling).unwrap();
+        let asset = &asset("pam_systemd_home.so");
+        let lib = out.join("pam_systemd_home.so");
+        fs::copy(asset, &lib).unwrap();
+        fs::copy(asset, sibling.join("libpam.so.0")).unwrap();
+
+        let libpam = |extra_args: &[&str]| {
+            let mut args = vec![OsStr::new("--explain"), lib.as_os_str()];
+            args.extend(extra_args.iter().map(OsStr::new));
+            let cli = Cli::parse_from(args).unwrap();
+            let patch = auto_patchelf_file(
+                &cli.patch,
+                &lib,
+                &spawn_library_cache(&cli),
+                Path::new("/lib64/ld-linux-x86-64.so.2"),
+                None,
+                &[],
+            )
+            .unwrap();
+            let found = patch
+                .dependencies
+                .into_iter()
+                .find(|dep| dep.name == Path::new("libpam.so.0"))
+                .and_then(|dep| dep.found);
+            (found, patch.rpath)
+        };
+
+        assert_eq!(libpam(&[]).0, None);
+        let sibling_output = dir.join("lib");
+        let (found, rpath) = libpam(&["--sibling-outputs", sibling_output.to_str().unwrap()]);
+        assert_eq!(found, Some(sibling.join("libpam.so.0")));
+        assert!(rpath.contains(&path_string(&sibling)));
+    }
+
+    #[test]
+    fn test_seed_from_report() {
+        let dir = TempDir::new("seed");
+        let out = dir.join("out");
+        let lib = dir.copy_asset("pam_systemd_home.so", "out/pam_systemd_home.so");
+        let libpam = dir.copy_asset("pam_systemd_home.so", "deps/pam/lib/libpam.so.0");
+        let report = dir.join("resolved-dirs");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--libs"),
+            dir.join("deps").as_os_str(),
+            OsStr::new("--libs-recursive"),
+            OsStr::new("--resolved-dirs"),
+            report.as_os_str(),
+            OsStr::new("--paths"),
+            out.as_os_str(),
+        ])
+        .unwrap();
+        output::capture(|| {
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+        });
+        assert_eq!(
+            read_lines(&report).unwrap(),
+            [path_string(libpam.parent().unwrap())]
+        );
+
+        let cli = Cli::parse_from([
+            OsStr::new("--explain"),
+            lib.as_os_str(),
+            OsStr::new("--seed-from-report"),
+            report.as_os_str(),
+        ])
+        .unwrap();
+        let patch = auto_patchelf_file(
+            &cli.patch,
+            &lib,
+            &spawn_library_cache(&cli),
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+            None,
+            &[],
+        )
+        .unwrap();
+        let found = patch
+            .dependencies
+            .into_iter()
+            .find(|dep| dep.name == Path::new("libpam.so.0"))
+            .and_then(|dep| dep.found);
+        assert_eq!(found, Some(libpam));
+    }
+
+    #[test]
+    fn test_lib_runtime_map() {
+        let dir = TempDir::new("runtime-map");
+        let build_lib = dir.join("build/lib");
+        fs::create_dir_all(&build_lib).unwrap();
+        let lib = &asset("pam_systemd_home.so");
+        fs::copy(lib, build_lib.join("libpam.so.0")).unwrap();
+        let build_lib = build_lib.canonicalize().unwrap();
+
+        let cli = Cli::parse_from([
+            OsStr::new("--explain"),
+            lib.as_os_str(),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+        ])
+        .unwrap();
+        let mut library_cache = LibraryCache::new(vec!["so".into()]).with_runtime_map(vec![(
+            build_lib.parent().unwrap().to_path_buf(),
+            PathBuf::from("/opt/app"),
+        )]);
+        library_cache
+            .populate_cache(slice::from_ref(&build_lib), false)
+            .unwrap();
+        let patch = auto_patchelf_file(
+            &cli.patch,
+            lib,
+            &SharedHandle::ready(library_cache),
+            Path::new("/lib64/ld-linux-x86-64.so.2"),
+            None,
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(patch.rpath, ["/opt/app/lib"]);
+        let libpam = patch
+            .dependencies
+            .iter()
+            .find(|dep| dep.name == Path::new("libpam.so.0"))
+            .unwrap();
+        assert_eq!(libpam.found, Some(build_lib.join("libpam.so.0")));
+    }
+
+    #[test]
+    fn test_reindex_after_patch() {
+        let dir = TempDir::new("reindex");
+        let out = dir.join("out");
+        // liborigin.so is patched first and libtwohop.so needs it
+        let origin = dir.copy_asset("liborigin.so", "out/liborigin.so");
+        dir.copy_asset("libtwohop.so", "out/libtwohop.so");
+        let used_libs = dir.join("used-libs");
+
+        let used_origin = |reindex: bool| {
+            let mut args = vec![
+                OsStr::new("--patchelf"),
+                OsStr::new("true"),
+                OsStr::new("--ignore-missing"),
+                OsStr::new("*"),
+                OsStr::new("--ignore-existing"),
+                OsStr::new("--used-libs"),
+                used_libs.as_os_str(),
+                OsStr::new("--append-rpaths"),
+                out.as_os_str(),
+                OsStr::new("--paths"),
+                out.as_os_str(),
+            ];
+            if reindex {
+                args.push(OsStr::new("--reindex-after-patch"));
+            }
+            fs::remove_file(out.join(".auto-patchelf.state")).ok();
+            let cli = Cli::parse_from(args).unwrap();
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+            read_lines(&used_libs)
+                .unwrap()
+                .contains(&origin.display().to_string())
+        };
+
+        assert!(!used_origin(false));
+        assert!(used_origin(true));
+    }
+
+    #[test]
+    fn test_blob_dir() {
+        let dir = TempDir::new("blobs");
+        let blob = dir.copy_asset("pam_systemd_home.so", "blobs/0123abcd");
+        dir.copy_asset("pam_systemd_home.so", "libs/libpam.so.0");
+        let manifest = dir.join("manifest");
+        fs::write(&manifest, "lib/security/pam_systemd_home.so\t0123abcd\n").unwrap();
+        let needed = dir.join("needed");
+
+        let cli = Cli::parse_from([
+            OsStr::new("--patchelf"),
+            OsStr::new("true"),
+            OsStr::new("--ignore-missing"),
+            OsStr::new("*"),
+            OsStr::new("--libs"),
+            dir.join("libs").as_os_str(),
+            OsStr::new("--blob-dir"),
+            dir.join("blobs").as_os_str(),
+            OsStr::new("--path-manifest"),
+            manifest.as_os_str(),
+            OsStr::new("--print-needed"),
+            needed.as_os_str(),
+        ])
+        .unwrap();
+        let output = output::capture(|| {
+            auto_patchelf(&cli, None, Path::new("/nonexistent/ld.so"), &[]).unwrap();
+        });
+
+        assert!(output.contains("searching for dependencies of lib/security/pam_systemd_home.so"));
+        assert!(output.contains(&format!("setting RPATH to: {}", dir.join("libs").display())));
+        assert!(!output.contains(&blob.display().to_string()), "{output}");
+
+        let needed = fs::read_to_string(&needed).unwrap();
+        assert!(needed.starts_with("lib/security/pam_systemd_home.so:\n"));
+        assert!(needed.contains(&format!(
+            "\tlibpam.so.0 => {} (0x0000000000000000) [high]\n",
+            dir.join("libs/libpam.so.0").display()
+        )));
+    }
 }
@@ -0,0 +1,78 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, eyre, Result};
+
+/// Maps logical file paths to content-addressed blobs
+///
+/// Each non-empty line of the manifest has the form `<logical path>\t<hash>`,
+/// the blob itself is stored as `<blob dir>/<hash>`. Lines starting with `#`
+/// are ignored.
+pub(crate) struct PathManifest {
+    blob_dir: PathBuf,
+    entries: Vec<(PathBuf, String)>,
+}
+
+impl PathManifest {
+    pub(crate) fn load(manifest: impl AsRef<Path>, blob_dir: PathBuf) -> Result<Self> {
+        Self::parse(&fs::read_to_string(manifest)?, blob_dir)
+    }
+
+    pub(crate) fn parse(text: &str, blob_dir: PathBuf) -> Result<Self> {
+        let mut entries = Vec::new();
+
+        for (lineno, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (logical, hash) = line
+                .split_once('\t')
+                .ok_or_else(|| eyre!("line {}: expected <path>\\t<hash>", lineno + 1))?;
+            let hash = hash.trim();
+            if hash.is_empty() || hash.contains('/') {
+                bail!("line {}: invalid blob hash {hash:?}", lineno + 1);
+            }
+
+            entries.push((PathBuf::from(logical), hash.to_string()));
+        }
+
+        Ok(Self { blob_dir, entries })
+    }
+
+    /// Iterates over `(logical path, blob path)` pairs
+    pub(crate) fn blobs(&self) -> impl Iterator<Item = (&Path, PathBuf)> {
+        self.entries
+            .iter()
+            .map(|(logical, hash)| (logical.as_path(), self.blob_dir.join(hash)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let manifest = PathManifest::parse(
+            "# logical\thash\n/bin/foo\tabc123\n\n/lib/libfoo.so\tdef456\n",
+            PathBuf::from("/blobs"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            manifest.blobs().collect::<Vec<_>>(),
+            vec![
+                (Path::new("/bin/foo"), PathBuf::from("/blobs/abc123")),
+                (Path::new("/lib/libfoo.so"), PathBuf::from("/blobs/def456"))
+            ]
+        );
+        assert!(PathManifest::parse("/bin/foo\t../escape", PathBuf::from("/blobs")).is_err());
+    }
+}
@@ -3,24 +3,254 @@
 // SPDX-License-Identifier: EUPL-1.2
 
 use eyre::Result;
-use glob::Paths;
-use std::{fs::File, io::Read, path::Path};
+use glob::GlobResult;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Write},
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+use crate::output::Event;
 
 pub(crate) fn path_string(path: impl AsRef<Path>) -> String {
     path.as_ref().display().to_string()
 }
 
+/// Spells a path canonically without touching the filesystem
+///
+/// Repeated and trailing slashes are removed, e.g. `/a//b/` becomes `/a/b`.
+pub(crate) fn normalize_path(path: impl AsRef<Path>) -> String {
+    let path = path.as_ref().to_string_lossy();
+    let mut normalized = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && normalized.ends_with('/') {
+            continue;
+        }
+        normalized.push(c);
+    }
+    if normalized.len() > 1 && normalized.ends_with('/') {
+        normalized.pop();
+    }
+    normalized
+}
+
+/// Hashes bytes with 64 bit FNV-1a
+///
+/// Unlike `DefaultHasher`, the result is the same on all platforms and
+/// Rust versions.
+pub(crate) fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
 pub(crate) fn read_file(path: impl AsRef<Path>) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
     File::open(path)?.read_to_end(&mut buffer)?;
     Ok(buffer)
 }
 
-pub(crate) fn glob(path: &Path, pattern: &str, recursive: bool) -> Result<Paths> {
-    let pattern = if recursive {
-        format!("{}/**/{}", path.display(), pattern)
-    } else {
-        format!("{}/{}", path.display(), pattern)
+/// Finds the entries of `path` matching `pattern`, in subdirectories too if `recursive`
+///
+/// A recursive search goes at most `max_depth` directories deep, where
+/// the entries of `path` itself are at depth 0. It warns if there is more
+/// below that, as it may be a generated or malicious tree.
+pub(crate) fn glob(
+    path: &Path,
+    pattern: &str,
+    recursive: bool,
+    max_depth: Option<usize>,
+) -> Result<impl Iterator<Item = GlobResult>> {
+    let patterns = match (recursive, max_depth) {
+        (false, _) => vec![format!("{}/{}", path.display(), pattern)],
+        (true, None) => vec![format!("{}/**/{}", path.display(), pattern)],
+        (true, Some(max_depth)) => {
+            let deeper = format!("{}/{}*", path.display(), "*/".repeat(max_depth + 1));
+            if glob::glob(&deeper)?.next().is_some() {
+                Event::warning(
+                    "max-scan-depth",
+                    format!(
+                        "not searching {} deeper than {max_depth} directories (--max-scan-depth)",
+                        path.display()
+                    ),
+                )
+                .field("directory", path.display())
+                .emit();
+            }
+            (0..=max_depth)
+                .map(|depth| format!("{}/{}{}", path.display(), "*/".repeat(depth), pattern))
+                .collect()
+        }
     };
-    Ok(glob::glob(&pattern)?)
+    let paths = patterns
+        .iter()
+        .map(|pattern| glob::glob(pattern))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(paths.into_iter().flatten())
+}
+
+/// Writes paths separated by NUL bytes, suitable for `xargs -0`
+pub(crate) fn write_nul_delimited<'a>(
+    path: impl AsRef<Path>,
+    items: impl IntoIterator<Item = &'a Path>,
+) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for item in items {
+        out.write_all(item.as_os_str().as_bytes())?;
+        out.write_all(b"\0")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes paths one per line
+pub(crate) fn write_lines<'a>(
+    path: impl AsRef<Path>,
+    items: impl IntoIterator<Item = &'a Path>,
+) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for item in items {
+        out.write_all(item.as_os_str().as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads the non-empty lines of a file
+pub(crate) fn read_lines(path: impl AsRef<Path>) -> Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Scratch directory of a test, removed again when dropped
+#[cfg(test)]
+pub(crate) struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates an empty `auto-patchelf-<name>-<pid>` directory under the
+    /// system temporary directory, clearing leftovers of earlier runs
+    pub(crate) fn new(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("auto-patchelf-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    /// Copies the test asset `name` to `to` inside the directory
+    pub(crate) fn copy_asset(&self, name: &str, to: impl AsRef<Path>) -> std::path::PathBuf {
+        let to = self.0.join(to);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::copy(asset(name), &to).unwrap();
+        to
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Path of the test asset `name`
+#[cfg(test)]
+pub(crate) fn asset(name: &str) -> std::path::PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/assets")
+        .join(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_stable_hash() {
+        assert_eq!(stable_hash(b""), 0xcbf29ce484222325);
+        assert_eq!(stable_hash(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(stable_hash(b"foobar"), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn test_write_nul_delimited() {
+        let dir = TempDir::new("print0");
+        let out = dir.join("out");
+        let items = [Path::new("/a/b"), Path::new("/with\nnewline")];
+
+        write_nul_delimited(&out, items).unwrap();
+
+        assert_eq!(read_file(&out).unwrap(), b"/a/b\0/with\nnewline\0");
+    }
+
+    #[test]
+    fn test_glob_max_depth() {
+        let dir = TempDir::new("deep");
+        let mut deepest = dir.to_path_buf();
+        for _ in 0..1000 {
+            deepest.push("d");
+        }
+        fs::create_dir_all(&deepest).unwrap();
+        fs::write(deepest.join("libdeep.so"), "").unwrap();
+        fs::write(dir.join("d/d/libshallow.so"), "").unwrap();
+
+        let found = |max_depth| {
+            glob(&dir, "*.so", true, max_depth)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        let output = crate::output::capture(|| {
+            assert_eq!(found(Some(5)), [dir.join("d/d/libshallow.so")]);
+        });
+        assert!(output.starts_with("warn: not searching"));
+        assert_eq!(found(None).len(), 2);
+        assert_eq!(found(Some(1)), Vec::<PathBuf>::new());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/a//b/"), "/a/b");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("a/b"), "a/b");
+    }
+
+    #[test]
+    fn test_lines_round_trip() {
+        let dir = TempDir::new("lines");
+        let out = dir.join("out");
+        let items = [Path::new("/nix/store/a/lib"), Path::new("/nix/store/b/lib")];
+
+        write_lines(&out, items).unwrap();
+
+        assert_eq!(
+            read_lines(&out).unwrap(),
+            vec!["/nix/store/a/lib", "/nix/store/b/lib"]
+        );
+    }
 }
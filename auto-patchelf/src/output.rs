@@ -0,0 +1,704 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    cell::RefCell,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use miniserde::json::{self, Number, Object, Value};
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+static SUMMARY_ONLY: AtomicBool = AtomicBool::new(false);
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+static REPORT_SOCKET: Mutex<Option<UnixStream>> = Mutex::new(None);
+static NORMALIZED_STORE: OnceLock<String> = OnceLock::new();
+
+/// Stands in for the hashes of store paths with `--normalize-output-paths`
+const NORMALIZED_HASH: &str = "eeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee";
+
+thread_local! {
+    /// Output held back by `grouped` on this thread
+    static GROUP: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+/// How events are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Format {
+    #[default]
+    Human,
+    JsonLines,
+    /// Warnings and errors as GitHub Actions workflow commands
+    Github,
+}
+
+impl Format {
+    /// Gets the format used without `--format`, `github` when run by GitHub Actions
+    pub(crate) fn detect() -> Self {
+        if std::env::var_os("GITHUB_ACTIONS").is_some_and(|value| value == "true") {
+            Self::Github
+        } else {
+            Self::Human
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "jsonl" => Ok(Self::JsonLines),
+            "github" => Ok(Self::Github),
+            _ => Err(format!(
+                "unknown output format {s:?}, expected human, jsonl or github"
+            )),
+        }
+    }
+}
+
+/// Sets the output format for the rest of the process
+pub(crate) fn init(format: Format) {
+    FORMAT.set(format).ok();
+}
+
+/// Suppresses all events except the summary and errors
+pub(crate) fn set_summary_only() {
+    SUMMARY_ONLY.store(true, Ordering::Relaxed);
+}
+
+/// Replaces the hashes of paths under `store_dir` in all further output
+///
+/// Only what is written is affected, so that logs of builds differing in
+/// their store paths only can be compared.
+pub(crate) fn set_normalize_store_paths(store_dir: &Path) {
+    NORMALIZED_STORE.set(store_dir_prefix(store_dir)).ok();
+}
+
+/// Spells the store directory without a trailing slash
+fn store_dir_prefix(store_dir: &Path) -> String {
+    store_dir
+        .to_string_lossy()
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// Replaces the hash part of store paths like `/nix/store/<hash>-name`
+fn normalize_store_paths(text: &str, store_dir: &str) -> String {
+    const NIX_BASE32: &str = "0123456789abcdfghijklmnpqrsvwxyz";
+    let prefix = format!("{store_dir}/");
+
+    let mut normalized = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(&prefix) {
+        let (before, after) = rest.split_at(start + prefix.len());
+        normalized.push_str(before);
+        let hash = after.get(..NORMALIZED_HASH.len());
+        if hash.is_some_and(|hash| hash.chars().all(|c| NIX_BASE32.contains(c)))
+            && after[NORMALIZED_HASH.len()..].starts_with('-')
+        {
+            normalized.push_str(NORMALIZED_HASH);
+            rest = &after[NORMALIZED_HASH.len()..];
+        } else {
+            rest = after;
+        }
+    }
+    normalized.push_str(rest);
+    normalized
+}
+
+/// Escapes the message of a GitHub Actions workflow command
+fn escape_workflow_data(data: &str) -> String {
+    data.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Escapes a property value of a GitHub Actions workflow command
+fn escape_workflow_property(value: &str) -> String {
+    escape_workflow_data(value)
+        .replace(':', "%3A")
+        .replace(',', "%2C")
+}
+
+/// Mirrors all events to a file at full verbosity, with timestamps
+///
+/// An existing log file is kept as `<path>.1`. When writing the log fails,
+/// e.g. because the disk is full, logging stops with a warning on stdout.
+pub(crate) fn set_log_file(path: &Path) -> io::Result<()> {
+    *LOG_FILE.lock().unwrap() = Some(create_log_file(path)?);
+    Ok(())
+}
+
+/// Creates a log file, keeping an existing one as `<path>.1`
+fn create_log_file(path: &Path) -> io::Result<File> {
+    if path.is_file() {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        fs::rename(path, rotated)?;
+    }
+    File::create(path)
+}
+
+/// Streams all events as JSON lines to a supervising process listening on `path`
+///
+/// Like the log file, the socket gets the events at full verbosity. When
+/// writing to it fails, streaming stops with a warning on stdout.
+pub(crate) fn set_report_socket(path: &Path) -> io::Result<()> {
+    *REPORT_SOCKET.lock().unwrap() = Some(UnixStream::connect(path)?);
+    Ok(())
+}
+
+/// Writes a record to an optional sink, closing it if that fails
+fn write_sink(
+    sink: &Mutex<Option<impl Write>>,
+    record: impl FnOnce(&mut Vec<u8>) -> io::Result<()>,
+) -> Option<io::Error> {
+    let mut sink = sink.lock().unwrap();
+    let error = sink.as_mut().and_then(|out| {
+        let mut buf = Vec::new();
+        record(&mut buf).ok()?;
+        out.write_all(&buf).err()
+    });
+    if error.is_some() {
+        *sink = None;
+    }
+    error
+}
+
+/// Formats a point in time as an RFC 3339 UTC timestamp with milliseconds
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Holds back the events emitted by `f` and writes them to stdout at once
+///
+/// This keeps the output about a single file together, even if other files
+/// are processed concurrently. Nested groups are part of the outer group.
+pub(crate) fn grouped<T>(f: impl FnOnce() -> T) -> T {
+    grouped_with(f, |output| {
+        io::stdout().lock().write_all(output).ok();
+    })
+}
+
+fn grouped_with<T>(f: impl FnOnce() -> T, flush: impl FnOnce(&[u8])) -> T {
+    if GROUP.with_borrow(Option::is_some) {
+        return f();
+    }
+
+    GROUP.set(Some(Vec::new()));
+    let result = f();
+    if let Some(output) = GROUP.take() {
+        flush(&output);
+    }
+    result
+}
+
+/// Collects the human readable output of the events emitted by `f`
+#[cfg(test)]
+pub(crate) fn capture(f: impl FnOnce()) -> String {
+    let mut captured = String::new();
+    grouped_with(f, |output| {
+        captured = String::from_utf8_lossy(output).into_owned();
+    });
+    captured
+}
+
+fn format() -> Format {
+    FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Gets the number of warnings emitted so far
+pub(crate) fn warning_count() -> usize {
+    WARNINGS.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// A single reportable event
+///
+/// Human output only shows the message, JSON lines output carries the kind,
+/// level, message and all structured fields.
+pub(crate) struct Event {
+    kind: &'static str,
+    level: Level,
+    message: String,
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl Event {
+    pub(crate) fn new(kind: &'static str, level: Level, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            level,
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    pub(crate) fn info(kind: &'static str, message: impl Into<String>) -> Self {
+        Self::new(kind, Level::Info, message)
+    }
+
+    pub(crate) fn warning(kind: &'static str, message: impl Into<String>) -> Self {
+        Self::new(kind, Level::Warning, message)
+    }
+
+    pub(crate) fn error(kind: &'static str, message: impl Into<String>) -> Self {
+        Self::new(kind, Level::Error, message)
+    }
+
+    pub(crate) fn field(mut self, key: &'static str, value: impl Display) -> Self {
+        self.fields.push((key, Value::String(value.to_string())));
+        self
+    }
+
+    pub(crate) fn number(mut self, key: &'static str, value: usize) -> Self {
+        self.fields
+            .push((key, Value::Number(Number::U64(value as u64))));
+        self
+    }
+
+    pub(crate) fn list<T: Display>(
+        mut self,
+        key: &'static str,
+        values: impl IntoIterator<Item = T>,
+    ) -> Self {
+        let values = values
+            .into_iter()
+            .map(|value| Value::String(value.to_string()))
+            .collect();
+        self.fields.push((key, Value::Array(values)));
+        self
+    }
+
+    /// Normalizes the store paths in the message and the string fields
+    fn normalized(mut self, store_dir: &str) -> Self {
+        fn normalize_value(value: &mut Value, store_dir: &str) {
+            match value {
+                Value::String(s) => *s = normalize_store_paths(s, store_dir),
+                Value::Array(values) => {
+                    for value in values.iter_mut() {
+                        normalize_value(value, store_dir);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.message = normalize_store_paths(&self.message, store_dir);
+        for (_, value) in &mut self.fields {
+            normalize_value(value, store_dir);
+        }
+        self
+    }
+
+    fn is_shown(&self, summary_only: bool) -> bool {
+        !summary_only || self.kind == "summary" || self.level == Level::Error
+    }
+
+    fn write_log(&self, out: &mut impl Write, time: SystemTime) -> io::Result<()> {
+        write!(out, "{} ", timestamp(time))?;
+        self.write_to(out, Format::Human)
+    }
+
+    fn write_to(&self, out: &mut impl Write, format: Format) -> io::Result<()> {
+        match format {
+            Format::Human => match self.level {
+                Level::Info => writeln!(out, "{}", self.message),
+                Level::Warning => writeln!(out, "warn: {}", self.message),
+                Level::Error => writeln!(out, "error: {}", self.message),
+            },
+            Format::JsonLines => {
+                let mut object = Object::new();
+                object.insert("event".into(), Value::String(self.kind.into()));
+                object.insert("level".into(), Value::String(self.level.as_str().into()));
+                object.insert("message".into(), Value::String(self.message.clone()));
+                for (key, value) in &self.fields {
+                    object.insert((*key).into(), value.clone());
+                }
+                writeln!(out, "{}", json::to_string(&object))
+            }
+            Format::Github => {
+                let command = match self.level {
+                    Level::Info => return writeln!(out, "{}", self.message),
+                    Level::Warning => "warning",
+                    Level::Error => "error",
+                };
+                let file = self.fields.iter().find_map(|(key, value)| match value {
+                    Value::String(file) if *key == "file" => Some(file),
+                    _ => None,
+                });
+                match file {
+                    Some(file) => writeln!(
+                        out,
+                        "::{command} file={}::{}",
+                        escape_workflow_property(file),
+                        escape_workflow_data(&self.message)
+                    ),
+                    None => writeln!(out, "::{command}::{}", escape_workflow_data(&self.message)),
+                }
+            }
+        }
+    }
+
+    /// Writes the event to stdout in the configured format
+    ///
+    /// Suppressed warnings still count towards `--fail-on-warning`.
+    pub(crate) fn emit(mut self) {
+        if let Some(store_dir) = NORMALIZED_STORE.get() {
+            self = self.normalized(store_dir);
+        }
+        if self.level == Level::Warning {
+            WARNINGS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let log_error = write_sink(&LOG_FILE, |record| {
+            self.write_log(record, SystemTime::now())
+        });
+        if let Some(err) = log_error {
+            Event::warning(
+                "log-file",
+                format!("could not write the log file, stopped logging: {err}"),
+            )
+            .emit();
+        }
+        let socket_error = write_sink(&REPORT_SOCKET, |record| {
+            self.write_to(record, Format::JsonLines)
+        });
+        if let Some(err) = socket_error {
+            Event::warning(
+                "report-socket",
+                format!("could not write to the report socket, stopped streaming events: {err}"),
+            )
+            .emit();
+        }
+
+        if !self.is_shown(SUMMARY_ONLY.load(Ordering::Relaxed)) {
+            return;
+        }
+
+        let grouped = GROUP.with_borrow_mut(|group| {
+            group
+                .as_mut()
+                .map(|output| self.write_to(output, format()).ok())
+                .is_some()
+        });
+        if !grouped {
+            self.write_to(&mut io::stdout().lock(), format()).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::TempDir;
+
+    #[test]
+    fn test_json_lines() {
+        let mut out = Vec::new();
+        Event::info("found", " libfoo.so -> found: /lib")
+            .field("dependency", "libfoo.so")
+            .write_to(&mut out, Format::JsonLines)
+            .unwrap();
+        Event::warning("summary", "done")
+            .number("missing", 2)
+            .list("files", ["/bin/a", "/bin/b"])
+            .write_to(&mut out, Format::JsonLines)
+            .unwrap();
+
+        let lines: Vec<Object> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .map(|line| json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert!(matches!(&lines[0]["event"], Value::String(s) if s == "found"));
+        assert!(matches!(&lines[0]["dependency"], Value::String(s) if s == "libfoo.so"));
+        assert!(matches!(&lines[1]["level"], Value::String(s) if s == "warning"));
+        assert!(matches!(
+            &lines[1]["missing"],
+            Value::Number(Number::U64(2))
+        ));
+        assert!(matches!(&lines[1]["files"], Value::Array(files) if files.len() == 2));
+    }
+
+    #[test]
+    fn test_warnings_are_counted() {
+        let before = warning_count();
+        Event::info("info", "not a warning").emit();
+        Event::warning("warning", "a warning").emit();
+
+        assert!(warning_count() > before);
+    }
+
+    #[test]
+    fn test_report_socket() {
+        use std::io::{BufRead, BufReader};
+
+        let dir = TempDir::new("socket");
+        assert!(set_report_socket(&dir.join("socket")).is_err());
+
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        let sink = Mutex::new(Some(sender));
+        let event = Event::info("socket-test", "patched /bin/foo").field("file", "/bin/foo");
+        let error = write_sink(&sink, |record| event.write_to(record, Format::JsonLines));
+        assert!(error.is_none());
+
+        let mut receiver = BufReader::new(receiver);
+        let mut line = String::new();
+        receiver.read_line(&mut line).unwrap();
+        assert_eq!(
+            line.strip_suffix('\n').unwrap(),
+            r#"{"event":"socket-test","file":"/bin/foo","level":"info","message":"patched /bin/foo"}"#
+        );
+
+        // A supervisor that went away stops the streaming
+        drop(receiver);
+        let error = write_sink(&sink, |record| event.write_to(record, Format::JsonLines));
+        assert!(error.is_some());
+        assert!(sink.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_human() {
+        let mut out = Vec::new();
+        Event::warning("ignored", "ignoring libfoo.so")
+            .field("dependency", "libfoo.so")
+            .write_to(&mut out, Format::Human)
+            .unwrap();
+
+        assert_eq!(out, b"warn: ignoring libfoo.so\n");
+    }
+
+    #[test]
+    fn test_github() {
+        let mut out = Vec::new();
+        Event::error(
+            "missing",
+            "auto-patchelf could not satisfy dependency libfoo.so wanted by /out/bin/foo",
+        )
+        .field("file", "/out/bin/foo")
+        .field("dependency", "libfoo.so")
+        .write_to(&mut out, Format::Github)
+        .unwrap();
+        Event::warning("ignored", "ignoring 100% of:\n  /out/bin/a,b")
+            .field("file", "/out/bin/a,b")
+            .write_to(&mut out, Format::Github)
+            .unwrap();
+        Event::warning("interpreter", "not checking")
+            .write_to(&mut out, Format::Github)
+            .unwrap();
+        Event::info("found", " libbar.so -> found: /lib")
+            .write_to(&mut out, Format::Github)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "::error file=/out/bin/foo::auto-patchelf could not satisfy dependency libfoo.so wanted by /out/bin/foo\n\
+             ::warning file=/out/bin/a%2Cb::ignoring 100%25 of:%0A  /out/bin/a,b\n\
+             ::warning::not checking\n\
+             \x20libbar.so -> found: /lib\n"
+        );
+    }
+
+    #[test]
+    fn test_grouped_output_does_not_interleave() {
+        let output = std::sync::Mutex::new(Vec::new());
+
+        std::thread::scope(|scope| {
+            for file in 0..8 {
+                let output = &output;
+                scope.spawn(move || {
+                    grouped_with(
+                        || {
+                            for line in 0..50 {
+                                Event::info("line", format!("{file} {line}")).emit();
+                                std::thread::yield_now();
+                            }
+                        },
+                        |group| output.lock().unwrap().extend_from_slice(group),
+                    )
+                });
+            }
+        });
+
+        let output = String::from_utf8(output.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 8 * 50);
+        for group in lines.chunks(50) {
+            let file = group[0].split(' ').next().unwrap();
+            for (line, expected) in group.iter().zip(0..) {
+                assert_eq!(*line, format!("{file} {expected}"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let at = |secs: u64, millis: u64| {
+            UNIX_EPOCH + std::time::Duration::from_millis(secs * 1000 + millis)
+        };
+
+        assert_eq!(timestamp(at(0, 0)), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(at(951_782_400, 5)), "2000-02-29T00:00:00.005Z");
+        assert_eq!(
+            timestamp(at(1_700_000_000, 250)),
+            "2023-11-14T22:13:20.250Z"
+        );
+    }
+
+    #[test]
+    fn test_normalize_store_paths() {
+        let hash = "0123456789abcdfghijklmnpqrsvwxyz";
+        assert_eq!(
+            normalize_store_paths(
+                &format!("/nix/store/{hash}-foo/lib:/nix/store/short-bar:/nix/store/{hash}x"),
+                "/nix/store"
+            ),
+            format!("/nix/store/{NORMALIZED_HASH}-foo/lib:/nix/store/short-bar:/nix/store/{hash}x")
+        );
+
+        let store_dir = store_dir_prefix(Path::new("/nix/store/"));
+        assert_eq!(store_dir, "/nix/store");
+        let mut out = Vec::new();
+        Event::info(
+            "rpath",
+            format!("setting RPATH to: /nix/store/{hash}-zlib-1.3/lib"),
+        )
+        .normalized(&store_dir)
+        .write_to(&mut out, Format::Human)
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("setting RPATH to: /nix/store/{NORMALIZED_HASH}-zlib-1.3/lib\n")
+        );
+
+        let mut out = Vec::new();
+        Event::info("found", "")
+            .list("rpath", [format!("/nix/store/{hash}-zlib/lib")])
+            .normalized("/nix/store")
+            .write_to(&mut out, Format::JsonLines)
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains(&format!(
+            "\"rpath\":[\"/nix/store/{NORMALIZED_HASH}-zlib/lib\"]"
+        )));
+    }
+
+    #[test]
+    fn test_log_file() {
+        let dir = TempDir::new("log");
+        let log = dir.join("run.log");
+        fs::write(&log, "previous run\n").unwrap();
+
+        let sink = Mutex::new(Some(create_log_file(&log).unwrap()));
+        for event in [
+            Event::info("logged", "logged"),
+            Event::warning("logged", "logged"),
+        ] {
+            let error = write_sink(&sink, |record| event.write_log(record, SystemTime::now()));
+            assert!(error.is_none());
+        }
+        drop(sink);
+
+        assert_eq!(
+            fs::read_to_string(dir.join("run.log.1")).unwrap(),
+            "previous run\n"
+        );
+        let content = fs::read_to_string(&log).unwrap();
+        let records: Vec<&str> = content.lines().collect();
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            let (time, _) = record.split_once(' ').unwrap();
+            assert_eq!(time.len(), "1970-01-01T00:00:00.000Z".len());
+            assert!(time.ends_with('Z'));
+        }
+        assert!(records[1].contains(" warn: "));
+
+        // A full disk stops logging instead of failing the run
+        let full = File::options().write(true).open("/dev/full").unwrap();
+        let sink = Mutex::new(Some(full));
+        let error = write_sink(&sink, |record| {
+            Event::info("logged", "not written").write_log(record, SystemTime::now())
+        });
+        assert!(error.is_some());
+        assert!(sink.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_summary_only() {
+        let events = [
+            Event::info("searching", "searching for dependencies of /bin/foo"),
+            Event::info("not-found", " libfoo.so -> not found!"),
+            Event::warning("ignored", "auto-patchelf ignoring missing libfoo.so"),
+            Event::info(
+                "summary",
+                "auto-patchelf: 1 dependencies could not be satisfied",
+            ),
+            Event::error("patch-failed", "could not patch /bin/bar"),
+        ];
+
+        let mut out = Vec::new();
+        for event in events.iter().filter(|event| event.is_shown(true)) {
+            event.write_to(&mut out, Format::Human).unwrap();
+        }
+
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "auto-patchelf: 1 dependencies could not be satisfied\n\
+             error: could not patch /bin/bar\n"
+        );
+        assert!(events.iter().all(|event| event.is_shown(false)));
+    }
+}
@@ -0,0 +1,116 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::fmt;
+
+use eyre::{bail, Result};
+
+/// Version of patchelf as printed by `patchelf --version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct PatchelfVersion {
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
+}
+
+/// Options passed to patchelf that not every version understands, with the
+/// version they were added in
+const FEATURES: &[(&str, PatchelfVersion)] = &[
+    ("--no-default-lib", PatchelfVersion::new(0, 9)),
+    ("--force-rpath", PatchelfVersion::new(0, 9)),
+    ("--add-needed", PatchelfVersion::new(0, 9)),
+    ("--remove-needed", PatchelfVersion::new(0, 9)),
+    ("--replace-needed", PatchelfVersion::new(0, 9)),
+    ("--clear-symbol-version", PatchelfVersion::new(0, 12)),
+    ("--add-rpath", PatchelfVersion::new(0, 14)),
+];
+
+/// Since this version `--set-rpath` writes `DT_RUNPATH` instead of `DT_RPATH`
+pub(crate) const RUNPATH_DEFAULT: PatchelfVersion = PatchelfVersion::new(0, 9);
+
+impl PatchelfVersion {
+    pub(crate) const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+
+    /// Parses the output of `patchelf --version`, like `patchelf 0.18.0`
+    ///
+    /// Only the major and minor components are kept, patch releases do not
+    /// add options.
+    pub(crate) fn parse(output: &str) -> Option<Self> {
+        let version = output.split_whitespace().nth(1)?;
+        let mut components = version.split('.');
+        let major = components.next()?.parse().ok()?;
+        let minor = components
+            .next()
+            .map(|minor| {
+                let digits = minor.find(|c: char| !c.is_ascii_digit());
+                minor[..digits.unwrap_or(minor.len())].parse().ok()
+            })
+            .unwrap_or(Some(0))?;
+        Some(Self::new(major, minor))
+    }
+
+    /// Fails for the first of the `options` that is too new for this version
+    ///
+    /// Arguments that are not options, or options that this list does not
+    /// know about, are assumed to be supported.
+    pub(crate) fn check_options<'a>(
+        &self,
+        options: impl IntoIterator<Item = &'a str>,
+    ) -> Result<()> {
+        for option in options {
+            let Some((_, since)) = FEATURES.iter().find(|(name, _)| *name == option) else {
+                continue;
+            };
+            if self < since {
+                bail!(
+                    "{option} requires patchelf {since} or newer, but the detected version is {self}"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PatchelfVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            PatchelfVersion::parse("patchelf 0.18.0\n"),
+            Some(PatchelfVersion::new(0, 18))
+        );
+        assert_eq!(
+            PatchelfVersion::parse("patchelf 0.15.0pre"),
+            Some(PatchelfVersion::new(0, 15))
+        );
+        assert_eq!(
+            PatchelfVersion::parse("patchelf 1"),
+            Some(PatchelfVersion::new(1, 0))
+        );
+        assert_eq!(PatchelfVersion::parse(""), None);
+        assert_eq!(PatchelfVersion::parse("patchelf unknown"), None);
+    }
+
+    #[test]
+    fn test_check_options() {
+        let version = PatchelfVersion::new(0, 12);
+        assert!(version
+            .check_options(["--clear-symbol-version", "foo", "--frobnicate"])
+            .is_ok());
+        let err = version.check_options(["--add-rpath", "/lib"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--add-rpath requires patchelf 0.14 or newer, but the detected version is 0.12"
+        );
+    }
+}
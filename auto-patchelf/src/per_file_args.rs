@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{fs, path::Path};
+
+use eyre::{eyre, Result};
+use glob::Pattern;
+
+/// Extra patchelf arguments applied to files matching a glob pattern
+///
+/// Each non-empty line of the mapping file has the form `<glob>\t<args>`,
+/// where `<args>` is split on whitespace. Lines starting with `#` are
+/// ignored. The first matching pattern wins.
+#[derive(Default)]
+pub(crate) struct PerFileArgs {
+    rules: Vec<(Pattern, Vec<String>)>,
+}
+
+impl PerFileArgs {
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let mut rules = Vec::new();
+
+        for (lineno, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (pattern, args) = line
+                .split_once('\t')
+                .ok_or_else(|| eyre!("line {}: expected <glob>\\t<args>", lineno + 1))?;
+            let pattern = Pattern::new(pattern.trim())
+                .map_err(|err| eyre!("line {}: invalid glob: {err}", lineno + 1))?;
+            let args = args.split_whitespace().map(String::from).collect();
+
+            rules.push((pattern, args));
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Gets the arguments of the first pattern matching `path`
+    pub(crate) fn args_for(&self, path: &Path) -> &[String] {
+        self.rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches_path(path))
+            .map(|(_, args)| args.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Gets the arguments of every pattern
+    pub(crate) fn all_args(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .flat_map(|(_, args)| args.iter().map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_match_wins() {
+        let args = PerFileArgs::parse(
+            "# comment\n\
+             */bin/special\t--force-rpath --no-default-lib\n\
+             \n\
+             */bin/*\t--force-rpath\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            args.args_for(Path::new("/out/bin/special")),
+            ["--force-rpath", "--no-default-lib"]
+        );
+        assert_eq!(
+            args.args_for(Path::new("/out/bin/other")),
+            ["--force-rpath"]
+        );
+        assert!(args.args_for(Path::new("/out/lib/libfoo.so")).is_empty());
+    }
+
+    #[test]
+    fn test_missing_separator() {
+        assert!(PerFileArgs::parse("*/bin/* --force-rpath").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{bail, eyre, Result};
+
+use crate::{misc::glob, output::Event};
+
+/// Gets the existing `libdir` directories of the `.pc` files in `pc_dir`
+///
+/// Files that cannot be parsed or name a missing directory are ignored.
+pub(crate) fn libdirs(pc_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for pc_file in glob(pc_dir, "*.pc", false, None)? {
+        let pc_file = pc_file?;
+        let libdir = fs::read_to_string(&pc_file)
+            .map_err(Into::into)
+            .and_then(|text| parse_libdir(&text))
+            .and_then(|libdir| {
+                if libdir.is_dir() {
+                    Ok(libdir)
+                } else {
+                    Err(eyre!("{} does not exist", libdir.display()))
+                }
+            });
+        match libdir {
+            Ok(libdir) if !dirs.contains(&libdir) => dirs.push(libdir),
+            Ok(_) => {}
+            Err(err) => Event::info(
+                "pc",
+                format!("ignoring libdir of {}: {err}", pc_file.display()),
+            )
+            .field("file", pc_file.display())
+            .emit(),
+        }
+    }
+    Ok(dirs)
+}
+
+/// Gets the `libdir` variable of a pkg-config file
+///
+/// Variables are defined as `name=value` and may reference the ones defined
+/// before them as `${name}`. Keyword lines like `Libs:` are not needed for
+/// this and are skipped.
+pub(crate) fn parse_libdir(text: &str) -> Result<PathBuf> {
+    let mut variables = HashMap::new();
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let Some((name, value)) = line.split_once('=') else {
+            continue;
+        };
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        {
+            continue;
+        }
+        let value = expand(value.trim(), &variables)?;
+        variables.insert(name, value);
+    }
+
+    match variables.remove("libdir") {
+        Some(libdir) if !libdir.is_empty() => Ok(PathBuf::from(libdir)),
+        _ => bail!("no libdir variable"),
+    }
+}
+
+fn expand(value: &str, variables: &HashMap<&str, String>) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            bail!("unterminated variable reference in {value:?}");
+        };
+        let name = &rest[start + 2..start + end];
+        let Some(variable) = variables.get(name) else {
+            bail!("undefined variable {name:?}");
+        };
+        expanded.push_str(variable);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_libdir() {
+        let text = "\
+# generated
+prefix=/nix/store/abc-zlib
+exec_prefix=${prefix}
+libdir=${exec_prefix}/lib
+
+Name: zlib
+Libs: -L${libdir} -lz
+";
+        assert_eq!(
+            parse_libdir(text).unwrap(),
+            PathBuf::from("/nix/store/abc-zlib/lib")
+        );
+        assert!(parse_libdir("Name: zlib\nprefix=/usr\n").is_err());
+        assert!(parse_libdir("libdir=${prefix}/lib\n").is_err());
+        assert!(parse_libdir("prefix=/usr\nlibdir=${prefix/lib\n").is_err());
+    }
+}
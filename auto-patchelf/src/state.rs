@@ -10,9 +10,9 @@ use std::{
 };
 
 use bincode::Options;
-use eyre::{bail, Result};
+use eyre::{bail, eyre, Result};
 
-use crate::misc::path_string;
+use crate::{misc::path_string, output::Event};
 
 type MTime = i64;
 type Cache = HashMap<PathBuf, MTime>;
@@ -22,9 +22,19 @@ pub(crate) struct DirState {
     cache: Cache,
 }
 
+/// How an entry differs between two states
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum StateChange {
+    Added(MTime),
+    Removed(MTime),
+    Modified(MTime, MTime),
+}
+
 impl DirState {
     const VERSION: u32 = 1;
-    pub(crate) fn deserialize(path: impl AsRef<Path>) -> Result<Self> {
+    pub(crate) const DEFAULT_SIZE_LIMIT: u64 = 32 << 20;
+
+    pub(crate) fn deserialize(path: impl AsRef<Path>, size_limit: u64) -> Result<Self> {
         let mut file = File::options()
             .create(true)
             .truncate(false)
@@ -32,23 +42,60 @@ impl DirState {
             .read(true)
             .open(path.as_ref().join(".auto-patchelf.state"))?;
 
-        let cache = Self::deserialize_cache(&mut file)
+        let cache = Self::deserialize_cache(&mut file, size_limit)
             .inspect_err(|err| {
-                println!(
-                    "Unable to load cache file from {} {}",
-                    path_string(&path),
-                    err
-                );
+                Event::info(
+                    "state",
+                    format!(
+                        "Unable to load cache file from {} {}",
+                        path_string(&path),
+                        err
+                    ),
+                )
+                .emit();
             })
             .unwrap_or_default();
 
         Ok(Self { file, cache })
     }
 
-    fn deserialize_cache(file: &mut File) -> Result<Cache> {
+    /// Compares two state files without opening them for writing
+    ///
+    /// Changes are ordered by path.
+    pub(crate) fn diff(
+        old: impl AsRef<Path>,
+        new: impl AsRef<Path>,
+        size_limit: u64,
+    ) -> Result<Vec<(PathBuf, StateChange)>> {
+        let read = |path: &Path| {
+            Self::deserialize_cache(&mut File::open(path)?, size_limit)
+                .map_err(|err| eyre!("{}: {err}", path.display()))
+        };
+        let old = read(old.as_ref())?;
+        let mut new = read(new.as_ref())?;
+
+        let mut changes = Vec::new();
+        for (path, old_mtime) in old {
+            match new.remove(&path) {
+                None => changes.push((path, StateChange::Removed(old_mtime))),
+                Some(new_mtime) if new_mtime != old_mtime => {
+                    changes.push((path, StateChange::Modified(old_mtime, new_mtime)))
+                }
+                Some(_) => {}
+            }
+        }
+        changes.extend(
+            new.into_iter()
+                .map(|(path, mtime)| (path, StateChange::Added(mtime))),
+        );
+        changes.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(changes)
+    }
+
+    fn deserialize_cache(file: &mut File, size_limit: u64) -> Result<Cache> {
         let deserializer = bincode::options()
             .with_fixint_encoding()
-            .with_limit(32 << 20);
+            .with_limit(size_limit);
         let version_size = deserializer.serialized_size(&Self::VERSION).unwrap() as _;
         let mut version_buf = vec![0; version_size];
         file.read_exact(&mut version_buf)?;
@@ -57,10 +104,17 @@ impl DirState {
             bail!("Invalid version {}", version)
         }
 
-        Ok(deserializer.deserialize_from(file)?)
+        deserializer
+            .deserialize_from(file)
+            .map_err(|err| match *err {
+                bincode::ErrorKind::SizeLimit => eyre!(
+                    "state is larger than {size_limit} bytes, raise it with --state-size-limit"
+                ),
+                err => eyre!("state is corrupt: {err}"),
+            })
     }
 
-    pub(crate) fn serialize(mut self) -> Result<()> {
+    pub(crate) fn serialize(&mut self) -> Result<()> {
         self.file.rewind()?;
         self.file.set_len(0)?;
         bincode::serialize_into(&mut self.file, &Self::VERSION)?;
@@ -74,6 +128,11 @@ impl DirState {
             .is_some_and(|&entry| mtime == entry)
     }
 
+    /// Gets the modification time recorded for `path`
+    pub(crate) fn mtime(&self, path: impl AsRef<Path>) -> Option<MTime> {
+        self.cache.get(path.as_ref()).copied()
+    }
+
     pub(crate) fn update(&mut self, path: PathBuf, mtime: MTime) {
         self.cache
             .entry(path)
@@ -81,3 +140,73 @@ impl DirState {
             .or_insert(mtime);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_size_limit() {
+        let dir = TempDir::new("state");
+
+        let mut state = DirState::deserialize(&dir, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+        for i in 0..1000 {
+            state.update(PathBuf::from(format!("lib/libfoo.so.{i}")), i);
+        }
+        state.serialize().unwrap();
+
+        let state = DirState::deserialize(&dir, 1024).unwrap();
+        assert!(!state.up_to_date("lib/libfoo.so.1", 1));
+
+        let mut file = File::open(dir.join(".auto-patchelf.state")).unwrap();
+        let err = DirState::deserialize_cache(&mut file, 1024).unwrap_err();
+        assert!(err.to_string().contains("larger than 1024 bytes"));
+
+        let state = DirState::deserialize(&dir, 1 << 20).unwrap();
+        assert!(state.up_to_date("lib/libfoo.so.999", 999));
+    }
+
+    #[test]
+    fn test_diff() {
+        let dir = TempDir::new("state-diff");
+        let write_state = |name: &str, entries: &[(&str, MTime)]| {
+            let state_dir = dir.join(name);
+            fs::create_dir_all(&state_dir).unwrap();
+            let mut state =
+                DirState::deserialize(&state_dir, DirState::DEFAULT_SIZE_LIMIT).unwrap();
+            for (path, mtime) in entries {
+                state.update(PathBuf::from(path), *mtime);
+            }
+            state.serialize().unwrap();
+            state_dir.join(".auto-patchelf.state")
+        };
+        let old = write_state(
+            "old",
+            &[("bin/same", 1), ("bin/gone", 2), ("lib/touched", 3)],
+        );
+        let new = write_state(
+            "new",
+            &[("bin/same", 1), ("lib/touched", 4), ("bin/new", 5)],
+        );
+
+        assert_eq!(
+            DirState::diff(&old, &new, DirState::DEFAULT_SIZE_LIMIT).unwrap(),
+            [
+                (PathBuf::from("bin/gone"), StateChange::Removed(2)),
+                (PathBuf::from("bin/new"), StateChange::Added(5)),
+                (PathBuf::from("lib/touched"), StateChange::Modified(3, 4)),
+            ]
+        );
+        let modified_before = fs::metadata(&old).unwrap().modified().unwrap();
+        assert!(DirState::diff(&old, &old, DirState::DEFAULT_SIZE_LIMIT)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            fs::metadata(&old).unwrap().modified().unwrap(),
+            modified_before
+        );
+        assert!(DirState::diff(&old, dir.join("missing"), DirState::DEFAULT_SIZE_LIMIT).is_err());
+    }
+}
@@ -0,0 +1,124 @@
+// SPDX-FileCopyrightText: 2025 László Vaskó <vlaci@fastmail.com>
+//
+// SPDX-License-Identifier: EUPL-1.2
+
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+};
+
+/// The attribute does not exist
+const ENODATA: i32 = 61;
+/// The buffer is too small for the value
+const ERANGE: i32 = 34;
+
+// std links libc already, which provides these on Linux
+extern "C" {
+    fn getxattr(path: *const c_char, name: *const c_char, value: *mut c_void, size: usize)
+        -> isize;
+    fn setxattr(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: usize,
+        flags: c_int,
+    ) -> c_int;
+}
+
+fn c_string(bytes: &[u8]) -> io::Result<CString> {
+    CString::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// Reads the extended attribute `name` of `path`, following symlinks
+///
+/// Filesystems without extended attributes fail with
+/// [`io::ErrorKind::Unsupported`].
+pub(crate) fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    loop {
+        // SAFETY: both strings are NUL terminated, a NULL buffer of size 0
+        // only queries the size of the value
+        let size = unsafe { getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(ENODATA) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mut value = vec![0u8; size as usize];
+        // SAFETY: `value` is valid for writes of `value.len()` bytes
+        let read = unsafe {
+            getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            )
+        };
+        if read >= 0 {
+            value.truncate(read as usize);
+            return Ok(Some(value));
+        }
+        let err = io::Error::last_os_error();
+        // The value grew since its size was queried
+        if err.raw_os_error() != Some(ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+/// Sets the extended attribute `name` of `path` to `value`, following symlinks
+pub(crate) fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let path = c_string(path.as_os_str().as_bytes())?;
+    let name = c_string(name.as_bytes())?;
+    // SAFETY: both strings are NUL terminated and `value` is valid for
+    // reads of `value.len()` bytes
+    let status = unsafe {
+        setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if status < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::misc::TempDir;
+    use std::fs;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = TempDir::new("xattr");
+        let path = dir.join("file");
+        fs::write(&path, "").unwrap();
+
+        match set(&path, "user.auto-patchelf.test", b"/lib:/usr/lib") {
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {}
+            result => {
+                result.unwrap();
+                assert_eq!(
+                    get(&path, "user.auto-patchelf.test").unwrap(),
+                    Some(b"/lib:/usr/lib".to_vec())
+                );
+                set(&path, "user.auto-patchelf.test", b"").unwrap();
+                assert_eq!(
+                    get(&path, "user.auto-patchelf.test").unwrap(),
+                    Some(Vec::new())
+                );
+                assert_eq!(get(&path, "user.auto-patchelf.missing").unwrap(), None);
+            }
+        }
+    }
+}